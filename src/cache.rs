@@ -0,0 +1,414 @@
+//! Persistent metadata cache.
+//!
+//! Fetched metadata can be stored as a [`MetadataSnapshot`] under `/run`,
+//! and reused by later invocations (e.g. after switching root from the
+//! initrd, or by other units) instead of querying the provider again.
+//! Entries are keyed by provider and by an instance identifier, and expire
+//! after a TTL. Each piece of metadata is cached as it is first requested,
+//! so that a run only queries what its actions need. Metadata missing from
+//! the cache, data which is not part of snapshots (user data, vendor data)
+//! and boot check-in are served by the live provider, which is only created
+//! on demand.
+
+use crate::config::Config;
+use crate::metadata::{self, MetadataSnapshot};
+use crate::network;
+use crate::providers::MetadataProvider;
+use anyhow::{anyhow, Context, Result};
+use openssh_keys::PublicKey;
+use serde::{Deserialize, Serialize};
+use slog_scope::{debug, info, warn};
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default location of the metadata cache.
+pub(crate) const CACHE_PATH: &str = "/run/afterburn/metadata-cache.json";
+
+/// Default lifetime of cached metadata.
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Machine identifier exposed by the firmware, stable across boots.
+const PRODUCT_UUID_PATH: &str = "/sys/class/dmi/id/product_uuid";
+
+/// Random identifier of the current boot.
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// On-disk cache entry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheEntry {
+    provider: String,
+    instance_key: Option<String>,
+    timestamp_secs: u64,
+    metadata: MetadataSnapshot,
+    /// Names of the `metadata` fields which have been fetched.
+    #[serde(default)]
+    fetched: BTreeSet<String>,
+}
+
+/// Metadata cache location and policy.
+#[derive(Clone, Debug)]
+pub(crate) struct Cache {
+    path: PathBuf,
+    ttl: Duration,
+    instance_key: Option<String>,
+}
+
+impl Cache {
+    /// Build a cache at the given path, keyed on the current instance.
+    pub fn new(path: impl Into<PathBuf>, ttl: Duration) -> Self {
+        Self {
+            path: path.into(),
+            ttl,
+            instance_key: instance_key(),
+        }
+    }
+
+    /// Return metadata for the given provider, from the cache if a valid
+    /// entry exists, or from the provider otherwise.
+    ///
+    /// With `refresh`, the cache is always bypassed. Freshly fetched
+    /// metadata is stored unless `read_only` is set.
+    pub fn fetch(
+        &self,
+        provider: &str,
+        config: &Config,
+        refresh: bool,
+        read_only: bool,
+    ) -> Result<CachedProvider> {
+        let cached = if refresh { None } else { self.load(provider) };
+        let entry = cached.unwrap_or_else(|| CacheEntry {
+            provider: provider.to_string(),
+            instance_key: self.instance_key.clone(),
+            timestamp_secs: now_secs(),
+            metadata: MetadataSnapshot::default(),
+            fetched: BTreeSet::new(),
+        });
+        Ok(CachedProvider {
+            config: config.clone(),
+            cache: (!read_only).then(|| self.clone()),
+            entry: RefCell::new(entry),
+            live: OnceCell::new(),
+        })
+    }
+
+    /// Load a valid cache entry for the given provider, if any.
+    fn load(&self, provider: &str) -> Option<CacheEntry> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no metadata cache at {}", self.path.display());
+                return None;
+            }
+            Err(e) => {
+                warn!("failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        let entry: CacheEntry = match serde_json::from_slice(&content) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("ignoring invalid metadata cache: {}", e);
+                return None;
+            }
+        };
+
+        if entry.provider != provider {
+            debug!("ignoring metadata cache for provider '{}'", entry.provider);
+            return None;
+        }
+        if entry.instance_key != self.instance_key {
+            debug!("ignoring metadata cache for another instance");
+            return None;
+        }
+        let age = match now_secs().checked_sub(entry.timestamp_secs) {
+            Some(age) if age < self.ttl.as_secs() => age,
+            _ => {
+                debug!("ignoring expired metadata cache");
+                return None;
+            }
+        };
+        info!(
+            "using cached metadata from {} ({}s old)",
+            self.path.display(),
+            age
+        );
+        Some(entry)
+    }
+
+    /// Atomically store a cache entry.
+    fn store(&self, entry: &CacheEntry) -> Result<()> {
+        let content = serde_json::to_vec(entry).context("failed to serialize metadata cache")?;
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("could not get parent directory of {:?}", self.path))?;
+        fs::create_dir_all(dir).with_context(|| format!("failed to create directory {dir:?}"))?;
+        // temporary files are created with mode 0600
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(".metadata-cache-")
+            .tempfile_in(dir)
+            .context("failed to create temporary file")?;
+        temp_file
+            .write_all(&content)
+            .with_context(|| format!("failed to write to file {:?}", temp_file.path()))?;
+        temp_file
+            .persist(&self.path)
+            .map_err(|e| {
+                e.file.close().ok();
+                e.error
+            })
+            .with_context(|| format!("failed to persist file {:?}", self.path))?;
+        debug!("stored metadata cache at {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Return an identifier of the current instance.
+///
+/// The firmware product UUID is preferred; otherwise the boot ID is used,
+/// which still tells apart instances booted from a copied `/run`.
+fn instance_key() -> Option<String> {
+    [PRODUCT_UUID_PATH, BOOT_ID_PATH]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Metadata provider serving cached metadata, with a live provider for
+/// everything else.
+pub(crate) struct CachedProvider {
+    config: Config,
+    /// Cache to write fetched metadata to, unless read-only.
+    cache: Option<Cache>,
+    entry: RefCell<CacheEntry>,
+    live: OnceCell<Box<dyn MetadataProvider>>,
+}
+
+impl CachedProvider {
+    /// Return the live provider, creating it if needed.
+    fn live(&self) -> Result<&dyn MetadataProvider> {
+        if let Some(live) = self.live.get() {
+            return Ok(live.as_ref());
+        }
+        let provider = self.entry.borrow().provider.clone();
+        let live = metadata::fetch_metadata(&provider, &self.config)
+            .context("fetching metadata from provider")?;
+        Ok(self.live.get_or_init(|| live).as_ref())
+    }
+
+    /// Return a metadata field from the cache if it has been fetched, or
+    /// from the live provider otherwise, storing it in the cache.
+    ///
+    /// `get` queries a provider, which is either the live one or the cached
+    /// snapshot; `set` stores its result in the snapshot.
+    fn cached<T>(
+        &self,
+        field: &str,
+        get: impl Fn(&dyn MetadataProvider) -> Result<T>,
+        set: impl FnOnce(&mut MetadataSnapshot, &T),
+    ) -> Result<T> {
+        if self.entry.borrow().fetched.contains(field) {
+            return get(&self.entry.borrow().metadata);
+        }
+
+        let value = get(self.live()?)?;
+        let mut entry = self.entry.borrow_mut();
+        set(&mut entry.metadata, &value);
+        entry.fetched.insert(field.to_string());
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.store(&entry) {
+                warn!("failed to store metadata cache: {:#}", e);
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl MetadataProvider for CachedProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        self.cached(
+            "attributes",
+            |p| p.attributes(),
+            |s, v| s.attributes = v.clone().into_iter().collect(),
+        )
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        self.cached("hostname", |p| p.hostname(), |s, v| s.hostname = v.clone())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.cached(
+            "ssh_keys",
+            |p| p.ssh_keys(),
+            |s, v| s.ssh_keys = v.iter().map(ToString::to_string).collect(),
+        )
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.cached("networks", |p| p.networks(), |s, v| s.networks = v.clone())
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        self.cached(
+            "netplan_config",
+            |p| p.netplan_config(),
+            |s, v| s.netplan_config = v.clone(),
+        )
+    }
+
+    fn boot_checkin(&self) -> Result<()> {
+        self.live()?.boot_checkin()
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        self.cached(
+            "virtual_network_devices",
+            |p| p.virtual_network_devices(),
+            |s, v| s.virtual_network_devices = v.clone(),
+        )
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        self.cached(
+            "rd_network_kargs",
+            |p| p.rd_network_kargs(),
+            |s, v| s.rd_network_kargs = v.clone(),
+        )
+    }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        self.live()?.user_data()
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        self.live()?.vendor_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn snapshot() -> MetadataSnapshot {
+        MetadataSnapshot {
+            attributes: maplit::btreemap! {
+                "MOCK_INSTANCE_ID".to_string() => "i-0123".to_string(),
+            },
+            hostname: Some("mock-host".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn cache(dir: &Path, instance_key: &str) -> Cache {
+        Cache {
+            path: dir.join("afterburn/metadata-cache.json"),
+            ttl: DEFAULT_TTL,
+            instance_key: Some(instance_key.to_string()),
+        }
+    }
+
+    fn entry(cache: &Cache, fetched: &[&str]) -> CacheEntry {
+        CacheEntry {
+            provider: "aws".to_string(),
+            instance_key: cache.instance_key.clone(),
+            timestamp_secs: now_secs(),
+            metadata: snapshot(),
+            fetched: fetched.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), "instance-1");
+        assert_eq!(cache.load("aws"), None);
+
+        let entry = entry(&cache, &["attributes", "hostname"]);
+        cache.store(&entry).unwrap();
+        assert_eq!(cache.load("aws"), Some(entry));
+
+        // Other provider or instance.
+        assert_eq!(cache.load("gcp"), None);
+        let other = self::cache(dir.path(), "instance-2");
+        assert_eq!(other.load("aws"), None);
+
+        // Served from the cache, without creating a live provider.
+        let provider = cache
+            .fetch("aws", &Config::default(), false, false)
+            .unwrap();
+        assert_eq!(provider.hostname().unwrap().as_deref(), Some("mock-host"));
+        assert!(provider.live.get().is_none());
+    }
+
+    #[test]
+    fn test_cache_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), "instance-1");
+        cache.store(&entry(&cache, &["hostname"])).unwrap();
+
+        let live = MetadataSnapshot {
+            hostname: Some("live-host".to_string()),
+            ssh_keys: vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHwf2HMM5TRXvo2SQJjsNkiDD5KqiiNjrGVv3UUh9mPT core@host".to_string()],
+            ..Default::default()
+        };
+        let provider = cache
+            .fetch("aws", &Config::default(), false, false)
+            .unwrap();
+        provider.live.set(Box::new(live.clone())).ok().unwrap();
+
+        // Fetched fields are served from the cache, others from the live
+        // provider, and stored.
+        assert_eq!(provider.hostname().unwrap().as_deref(), Some("mock-host"));
+        assert_eq!(provider.ssh_keys().unwrap().len(), 1);
+        let stored = cache.load("aws").unwrap();
+        assert_eq!(
+            stored.fetched,
+            ["hostname", "ssh_keys"].map(String::from).into()
+        );
+        assert_eq!(stored.metadata.ssh_keys, live.ssh_keys);
+        assert_eq!(stored.metadata.hostname.as_deref(), Some("mock-host"));
+
+        // Refreshing bypasses the cache, and read-only mode doesn't store.
+        let provider = cache.fetch("aws", &Config::default(), true, true).unwrap();
+        provider.live.set(Box::new(live)).ok().unwrap();
+        assert_eq!(provider.hostname().unwrap().as_deref(), Some("live-host"));
+        assert_eq!(cache.load("aws").unwrap(), stored);
+    }
+
+    #[test]
+    fn test_cache_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(dir.path(), "instance-1");
+        cache.store(&entry(&cache, &["hostname"])).unwrap();
+
+        cache.ttl = Duration::from_secs(0);
+        assert_eq!(cache.load("aws"), None);
+
+        // Entries from the future are invalid too.
+        let entry = CacheEntry {
+            timestamp_secs: now_secs() + 3600,
+            ..entry(&cache, &["hostname"])
+        };
+        fs::write(&cache.path, serde_json::to_vec(&entry).unwrap()).unwrap();
+        cache.ttl = DEFAULT_TTL;
+        assert_eq!(cache.load("aws"), None);
+
+        // Garbage.
+        fs::write(&cache.path, "not json").unwrap();
+        assert_eq!(cache.load("aws"), None);
+    }
+}
@@ -0,0 +1,396 @@
+//! `daemon` CLI sub-command.
+
+use super::with_action;
+use crate::cache::{self, Cache};
+use crate::config::{self, Config, OutputsConfig};
+use crate::dbus::{self, Service};
+use crate::metadata::{self, MetadataSnapshot};
+use crate::overrides::MetadataOverrides;
+use crate::providers::MetadataProvider;
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Parser};
+use libsystemd::daemon::{self, NotifyState};
+use slog_scope::{debug, info, warn};
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Periodically refresh metadata and reapply changed outputs
+#[derive(Debug, Parser)]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"])))]
+pub struct CliDaemon {
+    /// The name of the cloud provider
+    #[arg(long, value_name = "name")]
+    provider: Option<String>,
+    /// Read the cloud provider from the kernel cmdline
+    #[arg(long)]
+    cmdline: bool,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
+    /// The file into which the metadata attributes are written
+    #[arg(long = "attributes", value_name = "path")]
+    attributes_file: Option<String>,
+    /// The file into which the hostname should be written
+    #[arg(long = "hostname", value_name = "path")]
+    hostname_file: Option<String>,
+    /// Update SSH keys for the given user
+    #[arg(long = "ssh-keys", value_name = "username")]
+    ssh_keys_user: Option<String>,
+    /// Publish metadata as the org.coreos.Afterburn1 D-Bus service
+    #[arg(long, value_name = "bus", value_enum, num_args = 0..=1, default_missing_value = "system")]
+    dbus: Option<dbus::Bus>,
+    /// Interval between metadata refreshes, in seconds
+    #[arg(long = "interval", value_name = "secs", default_value_t = 300)]
+    interval_secs: u64,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+    /// Overrides layered over provider metadata
+    #[arg(skip)]
+    overrides: MetadataOverrides,
+    /// Metadata cache, if enabled in configuration
+    #[arg(skip)]
+    cache: Option<Cache>,
+    /// Whether the cache has been refreshed since startup
+    #[arg(skip)]
+    cache_refreshed: Cell<bool>,
+    /// Metadata fetched by the last refresh, if any
+    #[arg(skip)]
+    previous: RefCell<Option<MetadataSnapshot>>,
+}
+
+impl CliDaemon {
+    /// Run the `daemon` sub-command.
+    pub(crate) fn run(mut self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider =
+            super::resolve_provider(self.provider.as_deref(), self.cmdline, self.detect, &config)?;
+        self.overrides = super::read_overrides(&config);
+        self.attributes_file = self
+            .attributes_file
+            .take()
+            .or(config.actions.attributes.clone());
+        self.hostname_file = self
+            .hostname_file
+            .take()
+            .or(config.actions.hostname.clone());
+        self.ssh_keys_user = self
+            .ssh_keys_user
+            .take()
+            .or(config.actions.ssh_keys.clone());
+        self.attributes_file = super::rooted(self.attributes_file.take())?;
+        self.hostname_file = super::rooted(self.hostname_file.take())?;
+        self.cache = config.cache.enabled.then(|| {
+            let ttl = config
+                .cache
+                .ttl_secs
+                .map_or(cache::DEFAULT_TTL, Duration::from_secs);
+            Cache::new(cache::CACHE_PATH, ttl)
+        });
+
+        if self.attributes_file.is_none()
+            && self.hostname_file.is_none()
+            && self.ssh_keys_user.is_none()
+            && self.dbus.is_none()
+        {
+            bail!("daemon: no action specified");
+        }
+        if self.interval_secs == 0 {
+            bail!("daemon: refresh interval must be positive");
+        }
+
+        super::with_provider(&provider, || self.run_loop(&provider, &config))
+    }
+
+    /// Refresh metadata forever, at the configured interval or when
+    /// requested over D-Bus.
+    fn run_loop(&self, provider: &str, config: &Config) -> Result<()> {
+        let interval = Duration::from_secs(self.interval_secs);
+        let watchdog = daemon::watchdog_enabled(false);
+        let (refresh_requests, requested) = mpsc::channel();
+        let service = match self.dbus {
+            Some(bus) => Some(Service::start(bus, provider, refresh_requests)?),
+            None => None,
+        };
+        let mut ready = false;
+        loop {
+            let result = self
+                .refresh(provider, config)
+                .and_then(|(updated, snapshot)| {
+                    if let Some(service) = &service {
+                        service
+                            .update(&snapshot)
+                            .context("updating D-Bus service")?;
+                    }
+                    Ok(updated)
+                });
+            let status = match result {
+                Ok(updated) if updated.is_empty() => "metadata unchanged".to_string(),
+                Ok(updated) => format!("updated {}", updated.join(", ")),
+                Err(e) => {
+                    warn!("failed to refresh metadata: {:#}", e);
+                    format!("failed to refresh metadata: {e}")
+                }
+            };
+
+            // Report readiness after the first attempt, even if unsuccessful,
+            // so that a metadata service outage doesn't block boot.
+            let mut state = vec![NotifyState::Status(status)];
+            if !ready {
+                state.push(NotifyState::Ready);
+                ready = true;
+            }
+            notify(&state);
+
+            wait_with_watchdog(interval, watchdog, Some(&requested));
+        }
+    }
+
+    /// Fetch metadata and reapply changed outputs.
+    ///
+    /// This returns the names of the outputs which have been updated, and
+    /// the fetched metadata.
+    fn refresh(
+        &self,
+        provider: &str,
+        config: &Config,
+    ) -> Result<(Vec<&'static str>, MetadataSnapshot)> {
+        // A valid cache entry is only reused at startup.
+        let metadata: Box<dyn MetadataProvider> = match &self.cache {
+            Some(cache) => {
+                let refresh = self.cache_refreshed.replace(true);
+                Box::new(cache.fetch(provider, config, refresh, false)?)
+            }
+            None => metadata::fetch_metadata(provider, config)
+                .context("fetching metadata from provider")?,
+        };
+        let metadata = self.overrides.clone().apply(metadata);
+
+        let snapshot = self.collect(provider, metadata.as_ref())?;
+        drop(metadata);
+
+        let updated = self.apply(&snapshot, &config.outputs)?;
+        Ok((updated, snapshot))
+    }
+
+    /// Query metadata, keeping the value from the last refresh for any
+    /// failed query.
+    ///
+    /// Until a refresh succeeds there is nothing to fall back to, so only
+    /// what is needed by configured outputs is fetched, and any failure
+    /// fails the refresh rather than clearing outputs.
+    fn collect(&self, provider: &str, metadata: &dyn MetadataProvider) -> Result<MetadataSnapshot> {
+        let snapshot = match self.previous.take() {
+            Some(previous) => MetadataSnapshot::collect_lenient(provider, metadata, &previous),
+            None => {
+                let dbus = self.dbus.is_some();
+                let mut snapshot = MetadataSnapshot::default();
+                if self.attributes_file.is_some() || dbus {
+                    snapshot.attributes = metadata.attributes()?.into_iter().collect();
+                }
+                if self.hostname_file.is_some() || dbus {
+                    snapshot.hostname = metadata.hostname()?;
+                }
+                if self.ssh_keys_user.is_some() || dbus {
+                    snapshot.ssh_keys =
+                        metadata.ssh_keys()?.iter().map(|k| k.to_string()).collect();
+                }
+                snapshot
+            }
+        };
+        self.previous.replace(Some(snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    /// Rewrite outputs whose content differs from the given snapshot.
+    fn apply(
+        &self,
+        snapshot: &MetadataSnapshot,
+        outputs: &OutputsConfig,
+    ) -> Result<Vec<&'static str>> {
+        let mut updated = vec![];
+
+        if let Some(path) = &self.attributes_file {
+            if snapshot.plan_attributes(path)?.diff()?.is_some() {
+                with_action("attributes", || {
+                    info!("metadata attributes changed, rewriting {}", path);
+                    snapshot.write_attributes(path.clone())
+                })
+                .context("writing metadata attributes")?;
+                updated.push("attributes");
+            }
+        }
+
+        if let Some(user) = &self.ssh_keys_user {
+            let fragment = outputs.ssh_keys_fragment();
+            if snapshot.plan_ssh_keys(user, fragment)?.diff()?.is_some() {
+                with_action("ssh-keys", || {
+                    info!("ssh keys changed, updating keys for user {}", user);
+                    snapshot.write_ssh_keys(user.clone(), fragment)
+                })
+                .context("writing ssh keys")?;
+                updated.push("ssh-keys");
+            }
+        }
+
+        if let Some(path) = &self.hostname_file {
+            let changed = match snapshot.plan_hostname(path)? {
+                Some(update) => update.diff()?.is_some(),
+                None => false,
+            };
+            if changed {
+                with_action("hostname", || snapshot.write_hostname(path.clone()))
+                    .context("writing hostname")?;
+                updated.push("hostname");
+            }
+        }
+
+        debug!("refresh completed, updated outputs: {:?}", updated);
+        Ok(updated)
+    }
+}
+
+/// Send a state notification to the service manager, if any.
+pub(super) fn notify(state: &[NotifyState]) {
+    if let Err(e) = daemon::notify(false, state) {
+        warn!("failed to notify service manager: {}", e);
+    }
+}
+
+/// Wait for the given duration or until a refresh is requested, pinging the
+/// service watchdog if enabled.
+pub(super) fn wait_with_watchdog(
+    duration: Duration,
+    watchdog: Option<Duration>,
+    requested: Option<&Receiver<()>>,
+) {
+    let deadline = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let mut step = deadline - now;
+        if let Some(timeout) = watchdog {
+            step = step.min(timeout / 2);
+        }
+        let woken = match requested.map(|requested| requested.recv_timeout(step)) {
+            Some(Ok(())) => true,
+            Some(Err(RecvTimeoutError::Timeout)) => false,
+            Some(Err(RecvTimeoutError::Disconnected)) | None => {
+                thread::sleep(step);
+                false
+            }
+        };
+        if watchdog.is_some() {
+            notify(&[NotifyState::Watchdog]);
+        }
+        if let (true, Some(requested)) = (woken, requested) {
+            // Coalesce pending requests into a single refresh.
+            while requested.try_recv().is_ok() {}
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use openssh_keys::PublicKey;
+    use std::collections::HashMap;
+    use std::fs;
+
+    struct FlakyMock(&'static str);
+
+    impl MetadataProvider for FlakyMock {
+        fn attributes(&self) -> Result<HashMap<String, String>> {
+            Ok(maplit::hashmap! {
+                "MOCK_ID".to_string() => self.0.to_string(),
+            })
+        }
+
+        fn hostname(&self) -> Result<Option<String>> {
+            Ok(Some(format!("host-{}", self.0)))
+        }
+
+        fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+            Err(anyhow!("transient failure"))
+        }
+    }
+
+    #[test]
+    fn test_collect_lenient() {
+        let cli = CliDaemon::try_parse_from(["daemon", "--provider", "mock", "--dbus"]).unwrap();
+
+        // Without previous metadata, failures fail the refresh.
+        cli.collect("mock", &FlakyMock("1")).unwrap_err();
+
+        let previous = MetadataSnapshot {
+            ssh_keys: vec!["ssh-ed25519 AAAA core@mock".to_string()],
+            ..Default::default()
+        };
+        cli.previous.replace(Some(previous.clone()));
+        let snapshot = cli.collect("mock", &FlakyMock("2")).unwrap();
+        assert_eq!(snapshot.hostname.as_deref(), Some("host-2"));
+        assert_eq!(snapshot.attributes["MOCK_ID"], "2");
+        assert_eq!(snapshot.ssh_keys, previous.ssh_keys);
+
+        let snapshot = cli.collect("mock", &FlakyMock("3")).unwrap();
+        assert_eq!(snapshot.hostname.as_deref(), Some("host-3"));
+        assert_eq!(snapshot.ssh_keys, previous.ssh_keys);
+    }
+
+    #[test]
+    fn test_apply_changed_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let attributes_path = dir.path().join("attributes");
+        let hostname_path = dir.path().join("hostname");
+        let cli = CliDaemon::try_parse_from([
+            "daemon",
+            "--provider",
+            "mock",
+            "--attributes",
+            attributes_path.to_str().unwrap(),
+            "--hostname",
+            hostname_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        let mut snapshot = MetadataSnapshot {
+            hostname: Some("foo".to_string()),
+            ..Default::default()
+        };
+        snapshot
+            .attributes
+            .insert("MOCK_ID".to_string(), "1".to_string());
+
+        assert_eq!(
+            cli.apply(&snapshot, &OutputsConfig::default()).unwrap(),
+            vec!["attributes", "hostname"]
+        );
+        assert!(cli
+            .apply(&snapshot, &OutputsConfig::default())
+            .unwrap()
+            .is_empty());
+
+        snapshot.hostname = Some("bar".to_string());
+        assert_eq!(
+            cli.apply(&snapshot, &OutputsConfig::default()).unwrap(),
+            vec!["hostname"]
+        );
+        assert_eq!(fs::read_to_string(&hostname_path).unwrap(), "bar\n");
+
+        // A missing hostname leaves the current one in place.
+        snapshot.hostname = None;
+        assert!(cli
+            .apply(&snapshot, &OutputsConfig::default())
+            .unwrap()
+            .is_empty());
+        assert_eq!(fs::read_to_string(&hostname_path).unwrap(), "bar\n");
+    }
+}
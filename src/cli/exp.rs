@@ -1,13 +1,27 @@
 //! `exp` CLI sub-command.
 
-use crate::{initrd, util};
-use anyhow::Result;
-use clap::{ArgGroup, Parser};
+use crate::config::{self, Config, OutputsConfig};
+use crate::errors::{self, Error, ErrorKind};
+use crate::generator::{self, Generator};
+use crate::metadata::{self, MetadataSnapshot};
+use crate::providers::{FileUpdate, MetadataProvider};
+use crate::retry::capture;
+use crate::{detect, initrd, util};
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgGroup, Parser, ValueEnum};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
 
 /// Experimental subcommands
 #[derive(Debug, Parser)]
 pub enum CliExp {
     RdNetworkKargs(CliRdNetworkKargs),
+    Dump(CliDump),
+    Detect(CliDetect),
+    Verify(CliVerify),
+    Capture(CliCapture),
+    Platforms(CliPlatforms),
+    SystemdGenerator(CliSystemdGenerator),
 }
 
 impl CliExp {
@@ -15,6 +29,12 @@ impl CliExp {
     pub(crate) fn run(&self) -> Result<()> {
         match self {
             CliExp::RdNetworkKargs(cmd) => cmd.run()?,
+            CliExp::Dump(cmd) => cmd.run()?,
+            CliExp::Detect(cmd) => cmd.run()?,
+            CliExp::Verify(cmd) => cmd.run()?,
+            CliExp::Capture(cmd) => cmd.run()?,
+            CliExp::Platforms(cmd) => cmd.run()?,
+            CliExp::SystemdGenerator(cmd) => cmd.run()?,
         };
         Ok(())
     }
@@ -38,7 +58,7 @@ pub struct CliRdNetworkKargs {
 impl CliRdNetworkKargs {
     /// Run the sub-command.
     pub(crate) fn run(&self) -> Result<()> {
-        let provider = super::get_provider(self.provider.as_deref())?;
+        let provider = super::get_provider(self.provider.as_deref(), false)?;
 
         if util::has_network_kargs(super::CMDLINE_PATH)? {
             slog_scope::warn!("kernel cmdline already specifies network arguments, skipping");
@@ -50,3 +70,478 @@ impl CliRdNetworkKargs {
         initrd::write_network_kargs(kargs)
     }
 }
+
+/// Output formats for structured documents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+/// Print all the metadata fetched from the provider
+#[derive(Debug, Parser)]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"]).required(true)))]
+pub struct CliDump {
+    /// Read the cloud provider from the kernel cmdline
+    #[arg(long)]
+    cmdline: bool,
+    /// The name of the cloud provider
+    #[arg(long, value_name = "name")]
+    provider: Option<String>,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// Only print the value at this dot-separated path (e.g. `attributes.AWS_REGION`)
+    #[arg(long, value_name = "path")]
+    key: Option<String>,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+}
+
+impl CliDump {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider = super::get_provider(self.provider.as_deref(), self.detect)?;
+        let metadata = metadata::fetch_metadata(&provider, &config)
+            .context("fetching metadata from provider")?;
+        let metadata = super::read_overrides(&config).apply(metadata);
+        let snapshot = MetadataSnapshot::collect(metadata.as_ref())?;
+
+        let doc = serde_json::to_value(&snapshot).context("serializing metadata")?;
+        let doc = match &self.key {
+            Some(path) => lookup_key(&doc, path)
+                .ok_or_else(|| anyhow!("key '{}' not found in metadata", path))?,
+            None => &doc,
+        };
+
+        // Plain strings are printed verbatim, to ease usage from shell scripts.
+        let output = match (doc, self.format) {
+            (Value::String(s), _) if self.key.is_some() => s.clone(),
+            (_, OutputFormat::Json) => serde_json::to_string_pretty(doc)?,
+            (_, OutputFormat::Yaml) => serde_yaml::to_string(doc)?,
+        };
+        println!("{}", output.trim_end());
+        Ok(())
+    }
+}
+
+/// Detect the cloud provider from DMI/SMBIOS data
+#[derive(Debug, Parser)]
+pub struct CliDetect {
+    /// Directory containing DMI identification entries
+    #[arg(long, value_name = "path", default_value = detect::DMI_ID_PATH)]
+    dmi_dir: PathBuf,
+}
+
+impl CliDetect {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        let dmi = detect::DmiInfo::read_from(&self.dmi_dir)?;
+        let candidates = detect::candidates(&dmi);
+        if candidates.is_empty() {
+            slog_scope::warn!("no known platform matches DMI data");
+        }
+        for candidate in candidates {
+            println!("{} {}", candidate.provider, candidate.confidence);
+        }
+        Ok(())
+    }
+}
+
+/// List supported platforms and their capabilities
+#[derive(Debug, Parser)]
+pub struct CliPlatforms {
+    /// Print platforms as a JSON document
+    #[arg(long)]
+    json: bool,
+}
+
+impl CliPlatforms {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        if self.json {
+            let output = serde_json::to_string_pretty(metadata::PLATFORMS)
+                .context("serializing platforms")?;
+            println!("{output}");
+            return Ok(());
+        }
+        for platform in metadata::PLATFORMS {
+            let capabilities: Vec<_> = platform
+                .capabilities
+                .iter()
+                .map(|c| c.description())
+                .collect();
+            println!("{}: {}", platform.name, capabilities.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Enable units for the detected platform, as a systemd generator
+#[derive(Debug, Parser)]
+pub struct CliSystemdGenerator {
+    /// Directory for generated units
+    #[arg(value_name = "normal-dir")]
+    output_dir: PathBuf,
+    /// Directory for generated units overriding all others (unused)
+    #[arg(value_name = "early-dir")]
+    early_dir: Option<PathBuf>,
+    /// Directory for generated units overridden by all others (unused)
+    #[arg(value_name = "late-dir")]
+    late_dir: Option<PathBuf>,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+}
+
+impl CliSystemdGenerator {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        // Generators must not fail the boot on unsupported platforms.
+        let provider = match self.provider(&config) {
+            Ok(provider) => provider,
+            Err(e) => {
+                slog_scope::info!("no platform detected, nothing to generate: {:#}", e);
+                return Ok(());
+            }
+        };
+        self.generate(&provider, &config, generator::in_initrd())
+    }
+
+    /// Generate units for the given platform.
+    fn generate(&self, provider: &str, config: &Config, in_initrd: bool) -> Result<()> {
+        let ssh_keys_user = config
+            .actions
+            .ssh_keys
+            .as_deref()
+            .unwrap_or(generator::DEFAULT_SSH_KEYS_USER);
+        // Platforms without metadata (e.g. `metal`) have nothing to enable.
+        let generator = match Generator::new(provider, in_initrd, ssh_keys_user) {
+            Ok(generator) => generator,
+            Err(e) if errors::kind_of(&e) == ErrorKind::UnsupportedPlatform => {
+                slog_scope::info!("unsupported platform, nothing to generate: {:#}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        generator.write(&self.output_dir, Path::new(generator::UNIT_DIR))
+    }
+
+    /// Return the platform from kernel cmdline, configuration or DMI data.
+    fn provider(&self, config: &Config) -> Result<String> {
+        if let Ok(provider) = util::get_platform(super::CMDLINE_PATH) {
+            return Ok(provider);
+        }
+        if let Some(provider) = &config.provider {
+            return Ok(provider.clone());
+        }
+        detect::detect_platform(Path::new(detect::DMI_ID_PATH))
+    }
+}
+
+/// Compare files on disk with provider metadata, without writing anything
+#[derive(Debug, Parser)]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"]).required(true)))]
+pub struct CliVerify {
+    /// Read the cloud provider from the kernel cmdline
+    #[arg(long)]
+    cmdline: bool,
+    /// The name of the cloud provider
+    #[arg(long, value_name = "name")]
+    provider: Option<String>,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
+    /// The metadata attributes file to verify
+    #[arg(long = "attributes", value_name = "path")]
+    attributes_file: Option<String>,
+    /// The hostname file to verify
+    #[arg(long = "hostname", value_name = "path")]
+    hostname_file: Option<String>,
+    /// The directory of network units to verify
+    #[arg(long = "network-units", value_name = "path")]
+    network_units_dir: Option<String>,
+    /// The directory of the netplan config to verify
+    #[arg(long = "netplan-config", value_name = "path")]
+    netplan_config_dir: Option<String>,
+    /// Verify SSH keys for the given user
+    #[arg(long = "ssh-keys", value_name = "username")]
+    ssh_keys_user: Option<String>,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+}
+
+impl CliVerify {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider = super::get_provider(self.provider.as_deref(), self.detect)?;
+        let metadata = metadata::fetch_metadata(&provider, &config)
+            .context("fetching metadata from provider")?;
+        let metadata = super::read_overrides(&config).apply(metadata);
+        let updates = self.plan(metadata.as_ref(), &config.outputs)?;
+        if updates.is_empty() {
+            slog_scope::warn!("verify: nothing to verify");
+        }
+
+        let mut drifted = 0;
+        for update in &updates {
+            if let Some(diff) = update.diff()? {
+                print!("{diff}");
+                drifted += 1;
+            }
+        }
+        if drifted > 0 {
+            return Err(anyhow!(Error::new(
+                ErrorKind::Drift,
+                format!(
+                    "{} of {} files differ from provider metadata",
+                    drifted,
+                    updates.len()
+                )
+            )));
+        }
+        slog_scope::info!("verified {} files, no drift found", updates.len());
+        Ok(())
+    }
+
+    /// Plan the expected state of all files to verify.
+    fn plan(
+        &self,
+        metadata: &dyn MetadataProvider,
+        outputs: &OutputsConfig,
+    ) -> Result<Vec<FileUpdate>> {
+        let mut updates = vec![];
+        if let Some(path) = super::rooted(self.attributes_file.clone())? {
+            updates.push(
+                metadata
+                    .plan_attributes(&path)
+                    .context("planning metadata attributes")?,
+            );
+        }
+        if let Some(user) = &self.ssh_keys_user {
+            updates.push(
+                metadata
+                    .plan_ssh_keys(user, outputs.ssh_keys_fragment())
+                    .context("planning ssh keys")?,
+            );
+        }
+        if let Some(path) = super::rooted(self.hostname_file.clone())? {
+            updates.extend(metadata.plan_hostname(&path).context("planning hostname")?);
+        }
+        if let Some(dir) = super::rooted(self.network_units_dir.clone())? {
+            updates.extend(
+                metadata
+                    .plan_network_units(&dir)
+                    .context("planning network units")?,
+            );
+        }
+        if let Some(dir) = super::rooted(self.netplan_config_dir.clone())? {
+            updates.extend(
+                metadata
+                    .plan_netplan_config(&dir, outputs.netplan_config_file())
+                    .context("planning netplan config")?,
+            );
+        }
+        Ok(updates)
+    }
+}
+
+/// Record all HTTP exchanges with the provider into a bundle
+#[derive(Debug, Parser)]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"]).required(true)))]
+pub struct CliCapture {
+    /// Read the cloud provider from the kernel cmdline
+    #[arg(long)]
+    cmdline: bool,
+    /// The name of the cloud provider
+    #[arg(long, value_name = "name")]
+    provider: Option<String>,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
+    /// The file into which the bundle is written
+    #[arg(long, value_name = "path")]
+    output: PathBuf,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+}
+
+impl CliCapture {
+    /// Run the sub-command.
+    pub(crate) fn run(&self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider = super::get_provider(self.provider.as_deref(), self.detect)?;
+        capture::start_capture();
+        let result = metadata::fetch_metadata(&provider, &config)
+            .context("fetching metadata from provider")
+            .and_then(|metadata| MetadataSnapshot::collect(metadata.as_ref()));
+        let bundle = capture::finish_capture();
+
+        // The bundle is written even on failure, to help reproducing it.
+        bundle
+            .write(&self.output)
+            .context("writing capture bundle")?;
+        slog_scope::info!(
+            "captured {} HTTP exchanges into {}",
+            bundle.exchanges.len(),
+            self.output.display()
+        );
+        result.map(|_| ())
+    }
+}
+
+/// Look up a value in a structured document by dot-separated path.
+///
+/// Path components index into objects by key and into arrays by position.
+fn lookup_key<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(doc, |node, component| match node {
+        Value::Object(map) => map.get(component),
+        Value::Array(list) => component.parse::<usize>().ok().and_then(|i| list.get(i)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overrides::MetadataOverrides;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    struct VerifyMock;
+
+    impl MetadataProvider for VerifyMock {
+        fn attributes(&self) -> Result<HashMap<String, String>> {
+            Ok(maplit::hashmap! {
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+            })
+        }
+
+        fn hostname(&self) -> Result<Option<String>> {
+            Ok(Some("mock-host".to_string()))
+        }
+
+        fn netplan_config(&self) -> Result<Option<String>> {
+            Ok(Some("network: {}\n".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_verify_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let attributes = dir.path().join("attributes");
+        let hostname = dir.path().join("hostname");
+        std::fs::write(&attributes, "AFTERBURN_MOCK_REGION=moon-1\n").unwrap();
+        std::fs::write(&hostname, "old-host\n").unwrap();
+
+        let argv = [
+            "verify",
+            "--provider=mock",
+            "--attributes",
+            attributes.to_str().unwrap(),
+            "--hostname",
+            hostname.to_str().unwrap(),
+        ];
+        let cli = CliVerify::try_parse_from(argv).unwrap();
+        let updates = cli.plan(&VerifyMock, &OutputsConfig::default()).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].diff().unwrap(), None);
+        let diff = updates[1].diff().unwrap().unwrap();
+        assert!(diff.contains("-old-host\n+mock-host\n"), "{diff}");
+    }
+
+    #[test]
+    fn test_verify_plan_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let hostname = dir.path().join("hostname");
+        std::fs::write(&hostname, "override-host\n").unwrap();
+
+        let argv = [
+            "verify",
+            "--provider=mock",
+            "--hostname",
+            hostname.to_str().unwrap(),
+        ];
+        let cli = CliVerify::try_parse_from(argv).unwrap();
+        let overrides = MetadataOverrides {
+            hostname: Some("override-host".to_string()),
+            ..Default::default()
+        };
+        let metadata = overrides.apply(Box::new(VerifyMock));
+        let updates = cli
+            .plan(metadata.as_ref(), &OutputsConfig::default())
+            .unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].diff().unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_plan_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let argv = [
+            "verify",
+            "--provider=mock",
+            "--netplan-config",
+            dir.path().to_str().unwrap(),
+        ];
+        let cli = CliVerify::try_parse_from(argv).unwrap();
+        let outputs = OutputsConfig {
+            netplan_config_file: Some("50-custom.yaml".to_string()),
+            ..Default::default()
+        };
+        let updates = cli.plan(&VerifyMock, &outputs).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].path, dir.path().join("50-custom.yaml"));
+    }
+
+    #[test]
+    fn test_generator_unsupported_platform() {
+        let dir = tempfile::tempdir().unwrap();
+        let cli = CliSystemdGenerator::try_parse_from([
+            "systemd-generator",
+            dir.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        for provider in ["metal", "qemu"] {
+            cli.generate(provider, &Config::default(), false).unwrap();
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_lookup_key() {
+        let doc = json!({
+            "attributes": { "AWS_REGION": "us-east-1" },
+            "hostname": null,
+            "ssh_keys": [ "ssh-rsa AAAA foo", "ssh-rsa BBBB bar" ],
+        });
+
+        assert_eq!(
+            lookup_key(&doc, "attributes.AWS_REGION"),
+            Some(&json!("us-east-1"))
+        );
+        assert_eq!(lookup_key(&doc, "hostname"), Some(&Value::Null));
+        assert_eq!(
+            lookup_key(&doc, "ssh_keys.1"),
+            Some(&json!("ssh-rsa BBBB bar"))
+        );
+        assert_eq!(lookup_key(&doc, "ssh_keys.2"), None);
+        assert_eq!(lookup_key(&doc, "ssh_keys.foo"), None);
+        assert_eq!(lookup_key(&doc, "attributes.AWS_ZONE"), None);
+        assert_eq!(lookup_key(&doc, "hostname.foo"), None);
+    }
+}
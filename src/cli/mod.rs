@@ -1,24 +1,73 @@
 //! Command-line arguments parsing.
 
-use anyhow::Result;
-use clap::Parser;
-use slog_scope::trace;
+use crate::config::Config;
+use crate::errors::{Error, ErrorKind};
+use crate::logging::{self, LogFormat};
+use crate::overrides::MetadataOverrides;
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
 
+mod daemon;
 mod exp;
 mod multi;
+mod serve;
+
+/// Program name when installed as a systemd generator.
+const GENERATOR_NAME: &str = "afterburn-generator";
 
 /// Path to kernel command-line (requires procfs mount).
 const CMDLINE_PATH: &str = "/proc/cmdline";
 
+/// Top-level command-line arguments.
 // NOTE(lucab): due to legacy translation there can't be global arguments
-//  here, i.e. a sub-command is always expected first.
+//  before the sub-command, i.e. a sub-command is always expected first.
+//  Global flags are accepted after it.
 #[derive(Debug, Parser)]
 #[clap(display_name = "Afterburn")]
 #[clap(version, propagate_version = true)]
+pub(crate) struct Cli {
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+    /// Root directory for all output paths and user lookups
+    #[arg(long, global = true, value_name = "path")]
+    pub root: Option<PathBuf>,
+    /// Serve HTTP requests from a capture bundle, without network access
+    #[arg(long, global = true, value_name = "path")]
+    pub replay: Option<PathBuf>,
+    #[command(subcommand)]
+    pub cmd: CliConfig,
+}
+
+/// Logging flags, common to all sub-commands.
+#[derive(Debug, Args)]
+pub(crate) struct LoggingArgs {
+    /// Log output format
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Increase logging verbosity (may be repeated)
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Decrease logging verbosity (may be repeated)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    quiet: u8,
+}
+
+impl LoggingArgs {
+    /// Return the log level selected by verbosity flags.
+    pub fn level(&self) -> slog::Level {
+        let verbosity = i16::from(self.verbose) - i16::from(self.quiet);
+        logging::level_for_verbosity(verbosity.clamp(i8::MIN.into(), i8::MAX.into()) as i8)
+    }
+}
+
+#[derive(Debug, Subcommand)]
 pub(crate) enum CliConfig {
     Multi(multi::CliMulti),
     #[clap(subcommand)]
     Exp(exp::CliExp),
+    Daemon(daemon::CliDaemon),
+    Serve(serve::CliServe),
 }
 
 impl CliConfig {
@@ -27,47 +76,116 @@ impl CliConfig {
         match self {
             CliConfig::Multi(cmd) => cmd.run(),
             CliConfig::Exp(cmd) => cmd.run(),
+            CliConfig::Daemon(cmd) => cmd.run(),
+            CliConfig::Serve(cmd) => cmd.run(),
         }
     }
 }
 
 /// Parse command-line arguments into CLI configuration.
-pub(crate) fn parse_args(argv: impl IntoIterator<Item = String>) -> Result<CliConfig> {
+///
+/// This runs before logging is set up, so the caller is in charge of
+/// tracing the result.
+pub(crate) fn parse_args(argv: impl IntoIterator<Item = String>) -> Result<Cli> {
     let args = translate_legacy_args(argv);
-    let cfg = match CliConfig::try_parse_from(args) {
+    let cfg = match Cli::try_parse_from(args) {
         Err(e) if e.kind() == clap::error::ErrorKind::DisplayHelp => e.exit(),
         Err(e) if e.kind() == clap::error::ErrorKind::DisplayVersion => e.exit(),
         v => v,
     }?;
-    trace!("cli configuration - {:?}", cfg);
     Ok(cfg)
 }
 
-/// Return specified provider or parse provider ID from kargs.
-fn get_provider(provider: Option<&str>) -> Result<String> {
+/// Return specified provider, detect it from DMI data, or parse provider ID from kargs.
+fn get_provider(provider: Option<&str>, detect: bool) -> Result<String> {
     match provider {
         Some(p) => Ok(p.to_string()),
+        None if detect => crate::detect::detect_platform(Path::new(crate::detect::DMI_ID_PATH)),
         None => crate::util::get_platform(CMDLINE_PATH),
     }
 }
 
+/// Return the provider from command-line flags, falling back to configuration.
+fn resolve_provider(
+    provider: Option<&str>,
+    cmdline: bool,
+    detect: bool,
+    config: &Config,
+) -> Result<String> {
+    if provider.is_none() && !cmdline && !detect {
+        return config.provider.clone().ok_or_else(|| {
+            anyhow!(Error::new(
+                ErrorKind::Usage,
+                "no provider specified, use --provider, --cmdline or --detect"
+            ))
+        });
+    }
+    get_provider(provider, detect)
+}
+
+/// Return metadata overrides from configuration and kernel cmdline.
+fn read_overrides(config: &Config) -> MetadataOverrides {
+    read_overrides_from(config, CMDLINE_PATH)
+}
+
+/// Return metadata overrides from configuration and the given kernel
+/// cmdline file.
+///
+/// An unreadable cmdline (e.g. in containers) provides no overrides.
+fn read_overrides_from(config: &Config, cmdline_path: &str) -> MetadataOverrides {
+    let cmdline = crate::util::Cmdline::read(cmdline_path).unwrap_or_else(|e| {
+        slog_scope::warn!("ignoring kernel argument overrides: {:#}", e);
+        crate::util::Cmdline::parse("")
+    });
+    config.overrides.clone().with_cmdline(&cmdline)
+}
+
+/// Return the given output path under the root directory, if any.
+fn rooted(path: Option<String>) -> Result<Option<String>> {
+    path.map(|p| Ok(crate::util::rooted(p)?.to_string_lossy().into_owned()))
+        .transpose()
+}
+
+/// Run `f` with a logging scope tagging records with the given provider.
+fn with_provider<T>(provider: &str, f: impl FnOnce() -> T) -> T {
+    let log = slog_scope::logger().new(slog::o!("provider" => provider.to_string()));
+    slog_scope::scope(&log, f)
+}
+
+/// Run `f` with a logging scope tagging records with the given action.
+fn with_action<T>(action: &'static str, f: impl FnOnce() -> T) -> T {
+    let log = slog_scope::logger().new(slog::o!("action" => action));
+    slog_scope::scope(&log, f)
+}
+
 /// Translate command-line arguments from legacy mode.
 ///
 /// In legacy mode there are no sub-commands, and single-dash (Golang-style)
 /// arguments are allowed too.
+///
+/// When invoked as `afterburn-generator` (i.e. by systemd, through a
+/// symlink), the `exp systemd-generator` sub-command is injected instead.
 fn translate_legacy_args(cli: impl IntoIterator<Item = String>) -> impl Iterator<Item = String> {
     // Process the first two arguments and check whether there is a sub-command (normal mode)
     // or not (legacy mode).
     let mut argv = cli.into_iter();
     let argv_0 = argv.next().unwrap_or_else(|| "afterburn".to_string());
     let argv_1 = argv.next();
-    let legacy_mode = match argv_1 {
-        Some(ref arg) => arg.starts_with('-'),
-        None => true,
-    };
+    let generator_mode = Path::new(&argv_0)
+        .file_name()
+        .is_some_and(|name| name == GENERATOR_NAME);
+    let legacy_mode = !generator_mode
+        && match argv_1 {
+            Some(ref arg) => arg.starts_with('-'),
+            None => true,
+        };
 
     // Inject back the first two arguments, plus the `multi` sub-command with a legacy marker.
     let mut new_argv = vec![argv_0];
+    if generator_mode {
+        new_argv.push("exp".to_string());
+        new_argv.push("systemd-generator".to_string());
+    }
     if let Some(arg) = argv_1 {
         new_argv.push(arg);
     }
@@ -95,7 +213,7 @@ mod tests {
     #[test]
     fn clap_tests() {
         use clap::CommandFactory;
-        CliConfig::command().debug_assert();
+        Cli::command().debug_assert();
     }
 
     #[test]
@@ -113,6 +231,24 @@ mod tests {
         assert_eq!(translated.len(), 4);
     }
 
+    #[test]
+    fn test_generator_args() {
+        let args: Vec<_> = [
+            "/usr/lib/systemd/system-generators/afterburn-generator",
+            "/run/systemd/generator",
+            "/run/systemd/generator.early",
+            "/run/systemd/generator.late",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        match parse_args(args).unwrap().cmd {
+            CliConfig::Exp(exp::CliExp::SystemdGenerator(_)) => {}
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+    }
+
     #[test]
     fn test_legacy_no_action() {
         let legacy: Vec<_> = ["afterburn", "--provider", "azure"]
@@ -120,7 +256,7 @@ mod tests {
             .map(ToString::to_string)
             .collect();
 
-        let cmd = parse_args(legacy).unwrap();
+        let cmd = parse_args(legacy).unwrap().cmd;
         match cmd {
             CliConfig::Multi(_) => {}
             x => panic!("unexpected cmd: {x:?}"),
@@ -133,6 +269,27 @@ mod tests {
         parse_args(args).unwrap_err();
     }
 
+    #[test]
+    fn test_multi_no_provider() {
+        // Without a provider on the command-line, one must be configured.
+        let args: Vec<_> = ["afterburn", "multi", "--attributes", "/dev/null"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        match parse_args(args).unwrap().cmd {
+            CliConfig::Multi(cmd) => {
+                cmd.resolve_provider(&crate::config::Config::default())
+                    .unwrap_err();
+                let config = crate::config::Config {
+                    provider: Some("aws".to_string()),
+                    ..Default::default()
+                };
+                assert_eq!(cmd.resolve_provider(&config).unwrap(), "aws");
+            }
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+    }
+
     #[test]
     fn test_basic_cli_args() {
         let args: Vec<_> = ["afterburn", "--provider", "azure", "--check-in"]
@@ -140,7 +297,7 @@ mod tests {
             .map(ToString::to_string)
             .collect();
 
-        let cmd = parse_args(args).unwrap();
+        let cmd = parse_args(args).unwrap().cmd;
         match cmd {
             CliConfig::Multi(_) => {}
             x => panic!("unexpected cmd: {x:?}"),
@@ -154,7 +311,7 @@ mod tests {
             .map(ToString::to_string)
             .collect();
 
-        let cmd = parse_args(args).unwrap();
+        let cmd = parse_args(args).unwrap().cmd;
         match cmd {
             CliConfig::Multi(_) => {}
             x => panic!("unexpected cmd: {x:?}"),
@@ -176,7 +333,7 @@ mod tests {
         .map(ToString::to_string)
         .collect();
 
-        let cmd = parse_args(args).unwrap();
+        let cmd = parse_args(args).unwrap().cmd;
         let subcmd = match cmd {
             CliConfig::Exp(v) => v,
             x => panic!("unexpected cmd: {x:?}"),
@@ -189,6 +346,193 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_detect_args() {
+        let args: Vec<_> = [
+            "afterburn",
+            "multi",
+            "--detect",
+            "--attributes",
+            "/dev/null",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        parse_args(args).unwrap();
+
+        // Conflicting provider sources.
+        let args: Vec<_> = ["afterburn", "multi", "--detect", "--provider", "aws"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        parse_args(args).unwrap_err();
+
+        let args: Vec<_> = ["afterburn", "exp", "detect", "--dmi-dir", "/tmp/dmi"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        match parse_args(args).unwrap().cmd {
+            CliConfig::Exp(exp::CliExp::Detect(_)) => {}
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+    }
+
+    #[test]
+    fn test_daemon_cmd() {
+        let args: Vec<_> = [
+            "afterburn",
+            "daemon",
+            "--provider",
+            "aws",
+            "--ssh-keys",
+            "core",
+            "--interval",
+            "60",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        match parse_args(args).unwrap().cmd {
+            CliConfig::Daemon(_) => {}
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+    }
+
+    #[test]
+    fn test_serve_cmd() {
+        let args: Vec<_> = [
+            "afterburn",
+            "serve",
+            "--cmdline",
+            "--socket",
+            "/run/afterburn/metadata.sock",
+            "--group",
+            "metadata",
+            "--attribute",
+            "AWS_REGION",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        match parse_args(args).unwrap().cmd {
+            CliConfig::Serve(_) => {}
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+    }
+
+    #[test]
+    fn test_exp_dump_cmd() {
+        let args: Vec<_> = [
+            "afterburn",
+            "exp",
+            "dump",
+            "--provider",
+            "aws",
+            "--format",
+            "yaml",
+            "--key",
+            "attributes.AWS_REGION",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        let cmd = parse_args(args).unwrap().cmd;
+        match cmd {
+            CliConfig::Exp(exp::CliExp::Dump(_)) => {}
+            x => panic!("unexpected cmd: {x:?}"),
+        };
+
+        // A provider source is required.
+        let args: Vec<_> = ["afterburn", "exp", "dump"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        parse_args(args).unwrap_err();
+    }
+
+    #[test]
+    fn test_logging_args() {
+        let args: Vec<_> = ["afterburn", "multi", "--provider", "aws"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let cli = parse_args(args).unwrap();
+        assert_eq!(cli.logging.log_format, LogFormat::Text);
+        assert_eq!(cli.logging.level(), slog::Level::Info);
+
+        let args: Vec<_> = [
+            "afterburn",
+            "exp",
+            "dump",
+            "--provider",
+            "aws",
+            "--log-format",
+            "journald",
+            "-vv",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        let cli = parse_args(args).unwrap();
+        assert_eq!(cli.logging.log_format, LogFormat::Journald);
+        assert_eq!(cli.logging.level(), slog::Level::Trace);
+
+        // Legacy mode.
+        let args: Vec<_> = ["afterburn", "--provider=aws", "--quiet"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            parse_args(args).unwrap().logging.level(),
+            slog::Level::Warning
+        );
+
+        let args: Vec<_> = ["afterburn", "multi", "-v", "-q"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        parse_args(args).unwrap_err();
+    }
+
+    #[test]
+    fn test_root_arg() {
+        // Legacy mode, as used by the initrd hostname unit.
+        let args: Vec<_> = [
+            "afterburn",
+            "--cmdline",
+            "--root=/sysroot",
+            "--hostname=/etc/hostname",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        let cli = parse_args(args).unwrap();
+        assert_eq!(cli.root, Some(PathBuf::from("/sysroot")));
+
+        let args: Vec<_> = ["afterburn", "daemon", "--provider", "aws", "--root", "/mnt"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let cli = parse_args(args).unwrap();
+        assert_eq!(cli.root, Some(PathBuf::from("/mnt")));
+    }
+
+    #[test]
+    fn test_overrides_without_cmdline() {
+        let config = Config {
+            overrides: MetadataOverrides {
+                hostname: Some("node-1".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let overrides = read_overrides_from(&config, "/nonexistent/cmdline");
+        assert_eq!(overrides, config.overrides);
+    }
+
     #[test]
     fn test_default_net_kargs() {
         // Missing flag.
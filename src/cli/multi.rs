@@ -1,12 +1,26 @@
 //! `multi` CLI sub-command.
 
-use crate::metadata;
-use anyhow::{Context, Result};
+use super::with_action;
+use crate::cache::{self, Cache};
+use crate::cloud_config::{CloudConfig, CloudConfigProvider};
+use crate::config::{self, Config, OutputsConfig};
+use crate::errors::{Error, ErrorKind};
+use crate::hooks::{self, Hooks};
+use crate::journal::{self, Event};
+use crate::metadata::{self, Capability};
+use crate::overrides::MetadataOverrides;
+use crate::providers::{FileUpdate, MetadataProvider};
+use crate::report::{self, ActionReport};
+use anyhow::{bail, Context, Result};
 use clap::{ArgGroup, Parser};
+use slog_scope::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 /// Perform multiple tasks in a single call
 #[derive(Debug, Parser)]
-#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider"]).required(true)))]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"])))]
 pub struct CliMulti {
     /// The name of the cloud provider
     #[arg(long, value_name = "name")]
@@ -14,6 +28,9 @@ pub struct CliMulti {
     /// Read the cloud provider from the kernel cmdline
     #[arg(long)]
     cmdline: bool,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
     /// The file into which the metadata attributes are written
     #[arg(long = "attributes", value_name = "path")]
     attributes_file: Option<String>,
@@ -32,15 +49,55 @@ pub struct CliMulti {
     /// Update SSH keys for the given user
     #[arg(long = "ssh-keys", value_name = "username")]
     ssh_keys_user: Option<String>,
+    /// The file into which the user data is written
+    #[arg(long = "user-data", value_name = "path")]
+    user_data_file: Option<String>,
+    /// Apply hostname, SSH keys and /run files from cloud-config user and vendor data
+    #[arg(long)]
+    cloud_config: bool,
+    /// Reuse metadata cached by previous invocations, and cache fetched metadata
+    #[arg(long)]
+    cache: bool,
+    /// Fetch metadata from the provider even if a valid cache entry exists
+    #[arg(long)]
+    refresh: bool,
+    /// Print the files which would be written, without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// In dry-run mode, print a unified diff against the current files
+    #[arg(long, requires = "dry_run")]
+    diff: bool,
+    /// Run all actions even if some fail, and report failures at the end
+    #[arg(long)]
+    keep_going: bool,
+    /// Write a JSON run report to the given path
+    #[arg(long, value_name = "path", num_args = 0..=1, default_missing_value = report::REPORT_PATH)]
+    report: Option<String>,
+    /// Write a run report in the Prometheus textfile-collector format
+    #[arg(long, value_name = "path")]
+    report_prometheus: Option<String>,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
     /// Whether this command was translated from legacy CLI args
-    #[arg(long, hide = true)]
+    ///
+    /// Legacy invocations don't read the provider from configuration.
+    #[arg(long, hide = true, requires = "provider-group")]
     legacy_cli: bool,
+    /// Overrides layered over provider metadata
+    #[arg(skip)]
+    overrides: MetadataOverrides,
 }
 
 impl CliMulti {
     /// Run the `multi` sub-command.
-    pub(crate) fn run(self) -> Result<()> {
-        let provider = super::get_provider(self.provider.as_deref())?;
+    pub(crate) fn run(mut self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider = self.resolve_provider(&config)?;
+        self.merge_config(config.actions.clone());
+        self.apply_root()?;
+        self.overrides = super::read_overrides(&config);
 
         if self.attributes_file.is_none()
             && self.network_units_dir.is_none()
@@ -48,46 +105,561 @@ impl CliMulti {
             && !self.check_in
             && self.ssh_keys_user.is_none()
             && self.hostname_file.is_none()
+            && self.user_data_file.is_none()
+            && !self.cloud_config
         {
-            slog_scope::warn!("multi: no action specified");
+            warn!("multi: no action specified");
+        }
+        // Unknown providers are reported when fetching metadata.
+        if let Ok(capabilities) = metadata::capabilities(&provider) {
+            for capability in self.unsupported_actions(&capabilities) {
+                warn!(
+                    "multi: {} requested, but not supported by provider '{}'",
+                    capability.description(),
+                    provider
+                );
+            }
+        }
+
+        let report_path = self.report.take().or(config.report.path.clone());
+        let prometheus_path = self
+            .report_prometheus
+            .take()
+            .or(config.report.prometheus_path.clone());
+        let reporting = !self.dry_run && (report_path.is_some() || prometheus_path.is_some());
+        if reporting {
+            report::start();
+        }
+
+        let cache = (self.cache || config.cache.enabled).then(|| {
+            let ttl = config
+                .cache
+                .ttl_secs
+                .map_or(cache::DEFAULT_TTL, Duration::from_secs);
+            Cache::new(cache::CACHE_PATH, ttl)
+        });
+
+        let mut actions = ActionRunner::new(self.keep_going);
+        let result = super::with_provider(&provider, || {
+            self.run_actions(&provider, &config, cache.as_ref(), &mut actions)?;
+            actions.finish()
+        });
+        if reporting {
+            write_report(
+                &provider,
+                &actions,
+                &result,
+                report_path.as_deref(),
+                prometheus_path.as_deref(),
+            );
+        }
+        result
+    }
+
+    /// Fetch metadata from the given provider, or from the cache if any,
+    /// and perform configured actions.
+    fn run_actions(
+        self,
+        provider: &str,
+        config: &Config,
+        cache: Option<&Cache>,
+        actions: &mut ActionRunner,
+    ) -> Result<()> {
+        // fetch the metadata from the cache or the configured provider
+        let mut metadata: Box<dyn MetadataProvider> = match cache {
+            Some(cache) => Box::new(cache.fetch(provider, config, self.refresh, self.dry_run)?),
+            None => metadata::fetch_metadata(provider, config)
+                .context("fetching metadata from provider")?,
+        };
+
+        // overlay cloud-config from user and vendor data, if requested
+        let mut cloud_config = None;
+        if self.cloud_config {
+            let overlay = CloudConfigProvider::try_new(metadata).context("reading cloud-config")?;
+            cloud_config = Some(overlay.config().clone());
+            metadata = Box::new(overlay);
         }
 
-        // fetch the metadata from the configured provider
-        let metadata =
-            metadata::fetch_metadata(&provider).context("fetching metadata from provider")?;
+        // apply operator overrides on top of everything else, sharing the
+        // result with hooks
+        let metadata: Rc<dyn MetadataProvider> = self.overrides.clone().apply(metadata).into();
+
+        if self.dry_run {
+            return self.run_dry(metadata.as_ref(), cloud_config.as_ref(), &config.outputs);
+        }
+
+        // prepare hooks, run after each action
+        let hooks_dir = Path::new(&self.config_dir).join(hooks::HOOKS_DIR_NAME);
+        actions.hooks = Hooks::load(&hooks_dir, Rc::clone(&metadata)).context("loading hooks")?;
 
         // write attributes if configured to do so
-        self.attributes_file
-            .map_or(Ok(()), |x| metadata.write_attributes(x))
-            .context("writing metadata attributes")?;
+        if let Some(path) = self.attributes_file {
+            actions.run("attributes", "writing metadata attributes", || {
+                metadata.write_attributes(path)
+            })?;
+        }
 
         // write ssh keys if configured to do so
-        self.ssh_keys_user
-            .map_or(Ok(()), |x| metadata.write_ssh_keys(x))
-            .context("writing ssh keys")?;
+        if let Some(user) = self.ssh_keys_user {
+            actions.run("ssh-keys", "writing ssh keys", || {
+                metadata.write_ssh_keys(user, config.outputs.ssh_keys_fragment())
+            })?;
+        }
 
         // write hostname if configured to do so
-        self.hostname_file
-            .map_or(Ok(()), |x| metadata.write_hostname(x))
-            .context("writing hostname")?;
+        if let Some(path) = self.hostname_file {
+            actions.run("hostname", "writing hostname", || {
+                metadata.write_hostname(path)
+            })?;
+        }
 
         // write network units if configured to do so
-        self.network_units_dir
-            .map_or(Ok(()), |x| metadata.write_network_units(x))
-            .context("writing network units")?;
+        if let Some(dir) = self.network_units_dir {
+            actions.run("network-units", "writing network units", || {
+                metadata.write_network_units(dir)
+            })?;
+        }
 
         // write netplan config if configured to do so
-        self.netplan_config_dir
-            .map_or(Ok(()), |x| metadata.write_netplan_config(x))
-            .context("writing netplan config")?;
+        if let Some(dir) = self.netplan_config_dir {
+            actions.run("netplan-config", "writing netplan config", || {
+                metadata.write_netplan_config(dir, config.outputs.netplan_config_file())
+            })?;
+        }
+
+        // write user data if configured to do so
+        if let Some(path) = self.user_data_file {
+            actions.run("user-data", "writing user data", || {
+                metadata.write_user_data(path)
+            })?;
+        }
+
+        // write cloud-config files if configured to do so
+        if let Some(cloud_config) = cloud_config {
+            actions.run("cloud-config", "writing cloud-config files", || {
+                cloud_config.write_files()
+            })?;
+        }
 
         // perform boot check-in.
         if self.check_in {
-            metadata
-                .boot_checkin()
-                .context("checking-in instance boot to cloud provider")?;
+            actions.run(
+                "check-in",
+                "checking-in instance boot to cloud provider",
+                || {
+                    let result = metadata.boot_checkin();
+                    journal_checkin(provider, &result);
+                    result.map(|()| vec![])
+                },
+            )?;
         }
 
         Ok(())
     }
 }
+
+impl CliMulti {
+    /// Return the provider from command-line flags, falling back to configuration.
+    pub(crate) fn resolve_provider(&self, config: &Config) -> Result<String> {
+        super::resolve_provider(self.provider.as_deref(), self.cmdline, self.detect, config)
+    }
+
+    /// Return the capabilities required by requested actions, which are
+    /// missing from the given ones.
+    ///
+    /// Hostname, SSH keys and attributes which may be supplied by overrides or
+    /// cloud-config are not considered missing.
+    fn unsupported_actions(&self, capabilities: &[Capability]) -> Vec<Capability> {
+        let user_data = capabilities.contains(&Capability::UserData)
+            || capabilities.contains(&Capability::VendorData);
+        let cloud_config = self.cloud_config && user_data;
+        let requested = [
+            (
+                Capability::Attributes,
+                self.attributes_file.is_some() && self.overrides.attributes.is_empty(),
+            ),
+            (Capability::BootCheckin, self.check_in),
+            (
+                Capability::Hostname,
+                self.hostname_file.is_some() && self.overrides.hostname.is_none() && !cloud_config,
+            ),
+            (Capability::NetworkUnits, self.network_units_dir.is_some()),
+            (Capability::NetplanConfig, self.netplan_config_dir.is_some()),
+            (
+                Capability::SshKeys,
+                self.ssh_keys_user.is_some() && self.overrides.ssh_keys.is_empty() && !cloud_config,
+            ),
+            (
+                Capability::UserData,
+                self.user_data_file.is_some() || (self.cloud_config && !user_data),
+            ),
+        ];
+        requested
+            .into_iter()
+            .filter(|(capability, requested)| *requested && !capabilities.contains(capability))
+            .map(|(capability, _)| capability)
+            .collect()
+    }
+
+    /// Move all output paths under the root directory, if any.
+    fn apply_root(&mut self) -> Result<()> {
+        self.attributes_file = super::rooted(self.attributes_file.take())?;
+        self.hostname_file = super::rooted(self.hostname_file.take())?;
+        self.network_units_dir = super::rooted(self.network_units_dir.take())?;
+        self.netplan_config_dir = super::rooted(self.netplan_config_dir.take())?;
+        self.user_data_file = super::rooted(self.user_data_file.take())?;
+        Ok(())
+    }
+
+    /// Print all planned file updates, without touching the filesystem.
+    fn run_dry(
+        &self,
+        metadata: &dyn MetadataProvider,
+        cloud_config: Option<&CloudConfig>,
+        outputs: &OutputsConfig,
+    ) -> Result<()> {
+        let mut updates = vec![];
+        if let Some(path) = &self.attributes_file {
+            updates.push(
+                metadata
+                    .plan_attributes(path)
+                    .context("planning metadata attributes")?,
+            );
+        }
+        if let Some(user) = &self.ssh_keys_user {
+            updates.push(
+                metadata
+                    .plan_ssh_keys(user, outputs.ssh_keys_fragment())
+                    .context("planning ssh keys")?,
+            );
+        }
+        if let Some(path) = &self.hostname_file {
+            updates.extend(metadata.plan_hostname(path).context("planning hostname")?);
+        }
+        if let Some(dir) = &self.network_units_dir {
+            updates.extend(
+                metadata
+                    .plan_network_units(dir)
+                    .context("planning network units")?,
+            );
+        }
+        if let Some(dir) = &self.netplan_config_dir {
+            updates.extend(
+                metadata
+                    .plan_netplan_config(dir, outputs.netplan_config_file())
+                    .context("planning netplan config")?,
+            );
+        }
+
+        for update in &updates {
+            print!("{}", render_update(update, self.diff)?);
+        }
+        // user data may be binary, only report its size
+        if let Some(path) = &self.user_data_file {
+            match metadata.user_data().context("fetching user data")? {
+                Some(user_data) => info!(
+                    "dry-run: would write {} bytes of user data to {}",
+                    user_data.len(),
+                    path
+                ),
+                None => info!("dry-run: no user data available"),
+            }
+        }
+        if let Some(cloud_config) = cloud_config {
+            for file in &cloud_config.write_files {
+                info!("dry-run: would write cloud-config file {}", file.path);
+            }
+        }
+        if self.check_in {
+            info!("dry-run: skipping boot check-in");
+        }
+        Ok(())
+    }
+
+    /// Fill in actions not specified on the command-line from configuration.
+    fn merge_config(&mut self, actions: config::ActionsConfig) {
+        self.attributes_file = self.attributes_file.take().or(actions.attributes);
+        self.check_in |= actions.check_in;
+        self.hostname_file = self.hostname_file.take().or(actions.hostname);
+        self.network_units_dir = self.network_units_dir.take().or(actions.network_units);
+        self.netplan_config_dir = self.netplan_config_dir.take().or(actions.netplan_config);
+        self.ssh_keys_user = self.ssh_keys_user.take().or(actions.ssh_keys);
+        self.user_data_file = self.user_data_file.take().or(actions.user_data);
+        self.cloud_config |= actions.cloud_config;
+    }
+}
+
+/// Runner for `multi` actions, either stopping at the first failure or
+/// collecting all outcomes.
+#[derive(Debug)]
+struct ActionRunner {
+    keep_going: bool,
+    outcomes: Vec<ActionReport>,
+    /// Hooks to run after each action, if any.
+    hooks: Option<Hooks>,
+}
+
+impl ActionRunner {
+    fn new(keep_going: bool) -> Self {
+        Self {
+            keep_going,
+            outcomes: vec![],
+            hooks: None,
+        }
+    }
+
+    /// Run an action, recording its outcome and running its hooks.
+    ///
+    /// The action returns the paths of the files it wrote. Failures are
+    /// returned immediately, unless running in keep-going mode.
+    fn run(
+        &mut self,
+        action: &'static str,
+        context: &'static str,
+        f: impl FnOnce() -> Result<Vec<PathBuf>>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let result = with_action(action, f).context(context);
+        self.outcomes.push(ActionReport {
+            name: action.to_string(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            duration_secs: start.elapsed().as_secs_f64(),
+        });
+        if let Some(hooks) = &self.hooks {
+            with_action(action, || hooks.run(action, &result));
+        }
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if self.keep_going => {
+                warn!("action {} failed: {:#}", action, e);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Report the outcome of all actions, failing if any action failed.
+    fn finish(&self) -> Result<()> {
+        let total = self.outcomes.len();
+        let summary: Vec<_> = self
+            .outcomes
+            .iter()
+            .filter_map(|o| o.error.as_ref().map(|e| format!("  {}: {e}", o.name)))
+            .collect();
+        if summary.is_empty() {
+            debug!("all {} actions succeeded", total);
+            return Ok(());
+        }
+        bail!(Error::new(
+            ErrorKind::PartialFailure,
+            format!(
+                "{} of {} actions failed:\n{}",
+                summary.len(),
+                total,
+                summary.join("\n")
+            )
+        ))
+    }
+}
+
+/// Write the run report to the configured destinations.
+fn write_report(
+    provider: &str,
+    actions: &ActionRunner,
+    result: &Result<()>,
+    json_path: Option<&str>,
+    prometheus_path: Option<&str>,
+) {
+    let mut report = report::finish(provider);
+    report.actions = actions.outcomes.clone();
+    report.success = result.is_ok();
+    report.error = result.as_ref().err().map(|e| format!("{e:#}"));
+
+    if let Some(path) = json_path {
+        if let Err(e) = report.write_json(Path::new(path)) {
+            warn!("failed to write run report: {:#}", e);
+        }
+    }
+    if let Some(path) = prometheus_path {
+        if let Err(e) = report.write_prometheus(Path::new(path)) {
+            warn!("failed to write Prometheus run report: {:#}", e);
+        }
+    }
+}
+
+/// Record the outcome of a boot check-in in the journal.
+fn journal_checkin(provider: &str, result: &Result<()>) {
+    match result {
+        Ok(()) => journal::send(
+            Event::BootCheckinSucceeded,
+            &format!("boot check-in to {provider} succeeded"),
+            &[("AFTERBURN_PROVIDER", provider)],
+        ),
+        Err(e) => journal::send(
+            Event::BootCheckinFailed,
+            &format!("boot check-in to {provider} failed: {e:#}"),
+            &[("AFTERBURN_PROVIDER", provider)],
+        ),
+    }
+}
+
+/// Render a planned file update, either in full or as a diff.
+fn render_update(update: &FileUpdate, diff: bool) -> Result<String> {
+    if diff {
+        return Ok(update.diff()?.unwrap_or_default());
+    }
+    let path = update.path.display();
+    let out = match &update.content {
+        Some(content) => format!("==> {path} <==\n{content}\n"),
+        None => format!("==> {path} (removed) <==\n\n"),
+    };
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> CliMulti {
+        let argv = ["multi"].iter().chain(args).map(ToString::to_string);
+        CliMulti::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn test_provider_from_config() {
+        let config = Config {
+            provider: Some("gcp".to_string()),
+            ..Default::default()
+        };
+
+        let cli = parse(&["--provider", "aws"]);
+        assert_eq!(cli.resolve_provider(&config).unwrap(), "aws");
+        let cli = parse(&[]);
+        assert_eq!(cli.resolve_provider(&config).unwrap(), "gcp");
+        cli.resolve_provider(&Config::default()).unwrap_err();
+    }
+
+    #[test]
+    fn test_action_runner() {
+        use anyhow::anyhow;
+
+        // Stop at the first failure.
+        let mut actions = ActionRunner::new(false);
+        actions.run("attributes", "writing", || Ok(vec![])).unwrap();
+        actions
+            .run("ssh-keys", "writing", || Err(anyhow!("unreachable")))
+            .unwrap_err();
+
+        // Run all actions, then report failures.
+        let mut actions = ActionRunner::new(true);
+        actions.run("attributes", "writing", || Ok(vec![])).unwrap();
+        actions
+            .run("ssh-keys", "writing ssh keys", || {
+                Err(anyhow!("unreachable"))
+            })
+            .unwrap();
+        actions.run("hostname", "writing", || Ok(vec![])).unwrap();
+        let err = actions.finish().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "1 of 3 actions failed:\n  ssh-keys: writing ssh keys: unreachable"
+        );
+
+        ActionRunner::new(true).finish().unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_actions() {
+        let vmware = metadata::capabilities("vmware").unwrap();
+        let cli = parse(&[
+            "--provider",
+            "vmware",
+            "--attributes",
+            "/run/metadata/afterburn",
+            "--netplan-config",
+            "/run/netplan",
+            "--ssh-keys",
+            "core",
+            "--check-in",
+        ]);
+        assert_eq!(
+            cli.unsupported_actions(&vmware),
+            vec![
+                Capability::Attributes,
+                Capability::BootCheckin,
+                Capability::SshKeys
+            ]
+        );
+
+        // SSH keys may come from cloud-config or overrides.
+        let mut cli = parse(&[
+            "--provider",
+            "vmware",
+            "--ssh-keys",
+            "core",
+            "--cloud-config",
+        ]);
+        assert!(cli.unsupported_actions(&vmware).is_empty());
+        let azure = metadata::capabilities("azure").unwrap();
+        assert_eq!(cli.unsupported_actions(&azure), vec![Capability::UserData]);
+        cli.cloud_config = false;
+        cli.overrides.hostname = Some("fixed".to_string());
+        cli.hostname_file = Some("/etc/hostname".to_string());
+        assert_eq!(cli.unsupported_actions(&[]), vec![Capability::SshKeys]);
+    }
+
+    #[test]
+    fn test_dry_run_args() {
+        let cli = parse(&["--provider", "aws", "--dry-run", "--diff"]);
+        assert!(cli.dry_run && cli.diff);
+
+        let argv = ["multi", "--provider", "aws", "--diff"];
+        CliMulti::try_parse_from(argv).unwrap_err();
+    }
+
+    #[test]
+    fn test_render_update() {
+        let update = FileUpdate {
+            path: "/etc/hostname".into(),
+            content: Some("foo\n".to_string()),
+        };
+        assert_eq!(
+            render_update(&update, false).unwrap(),
+            "==> /etc/hostname <==\nfoo\n\n"
+        );
+        let update = FileUpdate {
+            path: "/etc/hostname".into(),
+            content: None,
+        };
+        assert_eq!(
+            render_update(&update, false).unwrap(),
+            "==> /etc/hostname (removed) <==\n\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let actions = config::ActionsConfig {
+            attributes: Some("/run/metadata/afterburn".to_string()),
+            check_in: true,
+            hostname: Some("/etc/hostname".to_string()),
+            network_units: None,
+            netplan_config: None,
+            ssh_keys: Some("core".to_string()),
+            user_data: None,
+            cloud_config: false,
+        };
+
+        let mut cli = parse(&["--hostname", "/sysroot/etc/hostname"]);
+        cli.merge_config(actions);
+        assert_eq!(
+            cli.attributes_file.as_deref(),
+            Some("/run/metadata/afterburn")
+        );
+        assert!(cli.check_in);
+        assert_eq!(cli.hostname_file.as_deref(), Some("/sysroot/etc/hostname"));
+        assert_eq!(cli.network_units_dir, None);
+        assert_eq!(cli.ssh_keys_user.as_deref(), Some("core"));
+    }
+}
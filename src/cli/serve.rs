@@ -0,0 +1,180 @@
+//! `serve` CLI sub-command.
+
+use super::daemon::{notify, wait_with_watchdog};
+use crate::config::{self, Config};
+use crate::metadata::{self, MetadataSnapshot};
+use crate::overrides::MetadataOverrides;
+use crate::serve::{self, AttributeFilter, Server};
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Parser};
+use libsystemd::daemon::{self, NotifyState};
+use slog_scope::{info, warn};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Serve metadata over HTTP on a local unix socket
+#[derive(Debug, Parser)]
+#[command(group(ArgGroup::new("provider-group").args(["cmdline", "provider", "detect"])))]
+pub struct CliServe {
+    /// The name of the cloud provider
+    #[arg(long, value_name = "name")]
+    provider: Option<String>,
+    /// Read the cloud provider from the kernel cmdline
+    #[arg(long)]
+    cmdline: bool,
+    /// Detect the cloud provider from DMI/SMBIOS data
+    #[arg(long)]
+    detect: bool,
+    /// Path of the listening socket [default: /run/afterburn/metadata.sock]
+    #[arg(long, value_name = "path")]
+    socket: Option<String>,
+    /// Permissions of the listening socket, in octal [default: 0660]
+    #[arg(long, value_name = "mode", value_parser = parse_mode)]
+    mode: Option<u32>,
+    /// Group owning the listening socket
+    #[arg(long, value_name = "name")]
+    group: Option<String>,
+    /// Expose the given attribute, or attributes with a `PREFIX*` (may be repeated)
+    #[arg(long = "attribute", value_name = "name")]
+    attributes: Vec<String>,
+    /// Interval between metadata refreshes, in seconds
+    #[arg(long = "interval", value_name = "secs", default_value_t = 300)]
+    interval_secs: u64,
+    /// The directory containing configuration files
+    #[arg(long, value_name = "path", default_value = config::CONFIG_DIR)]
+    config_dir: String,
+}
+
+impl CliServe {
+    /// Run the `serve` sub-command.
+    pub(crate) fn run(self) -> Result<()> {
+        let config =
+            Config::read_from(Path::new(&self.config_dir)).context("reading configuration")?;
+        let provider =
+            super::resolve_provider(self.provider.as_deref(), self.cmdline, self.detect, &config)?;
+        let overrides = super::read_overrides(&config);
+        let socket = self
+            .socket
+            .or_else(|| config.serve.socket.clone())
+            .unwrap_or_else(|| serve::SOCKET_PATH.to_string());
+        let mode = self
+            .mode
+            .or(config.serve.mode)
+            .unwrap_or(serve::SOCKET_MODE);
+        let group = self.group.or_else(|| config.serve.group.clone());
+        let attributes = if self.attributes.is_empty() {
+            config.serve.attributes.clone()
+        } else {
+            self.attributes
+        };
+        if self.interval_secs == 0 {
+            bail!("serve: refresh interval must be positive");
+        }
+
+        let listener = serve::bind(Path::new(&socket), mode, group.as_deref())
+            .context("creating metadata socket")?;
+        info!("serving metadata on {}", socket);
+        let server = Arc::new(Server::new(AttributeFilter::new(attributes)));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.run(listener));
+        }
+
+        let interval = Duration::from_secs(self.interval_secs);
+        super::with_provider(&provider, || {
+            refresh_loop(&provider, &config, &overrides, &server, interval)
+        })
+    }
+}
+
+/// Refresh served metadata forever, at the given interval.
+fn refresh_loop(
+    provider: &str,
+    config: &Config,
+    overrides: &MetadataOverrides,
+    server: &Server,
+    interval: Duration,
+) -> Result<()> {
+    let watchdog = daemon::watchdog_enabled(false);
+    let mut ready = false;
+    loop {
+        let status = match refresh(provider, config, overrides, server) {
+            Ok(true) => "metadata updated".to_string(),
+            Ok(false) => "metadata unchanged".to_string(),
+            Err(e) => {
+                warn!("failed to refresh metadata: {:#}", e);
+                format!("failed to refresh metadata: {e}")
+            }
+        };
+
+        // Report readiness after the first attempt, even if unsuccessful,
+        // as the socket is already accepting connections.
+        let mut state = vec![NotifyState::Status(status)];
+        if !ready {
+            state.push(NotifyState::Ready);
+            ready = true;
+        }
+        notify(&state);
+
+        // Refreshes can't be requested over the socket.
+        wait_with_watchdog(interval, watchdog, None);
+    }
+}
+
+/// Fetch metadata and update the server, returning whether it changed.
+///
+/// Metadata which fails to be fetched keeps its previous value.
+fn refresh(
+    provider: &str,
+    config: &Config,
+    overrides: &MetadataOverrides,
+    server: &Server,
+) -> Result<bool> {
+    let metadata =
+        metadata::fetch_metadata(provider, config).context("fetching metadata from provider")?;
+    let metadata = overrides.clone().apply(metadata);
+    // Keep serving the previous value of any metadata which can't be
+    // fetched this time.
+    let previous = server.snapshot().unwrap_or_default();
+    let snapshot = MetadataSnapshot::collect_lenient(provider, metadata.as_ref(), &previous);
+    Ok(server.update(snapshot))
+}
+
+/// Parse octal file permissions.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid octal permissions '{value}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_mode("666").unwrap(), 0o666);
+        parse_mode("0680").unwrap_err();
+        parse_mode("1777").unwrap_err();
+        parse_mode("").unwrap_err();
+
+        let cli = CliServe::try_parse_from([
+            "serve",
+            "--cmdline",
+            "--mode",
+            "0600",
+            "--attribute",
+            "AWS_REGION",
+            "--attribute",
+            "AWS_AVAILABILITY_*",
+        ])
+        .unwrap();
+        assert_eq!(cli.mode, Some(0o600));
+        assert_eq!(cli.attributes.len(), 2);
+        CliServe::try_parse_from(["serve", "--cmdline", "--mode", "rw"]).unwrap_err();
+    }
+}
@@ -0,0 +1,459 @@
+//! Cloud-config interpreter for user data and vendor data.
+//!
+//! Only a safe subset of cloud-config is understood: `hostname`/`fqdn`,
+//! `ssh_authorized_keys` (top-level and per-user), and `write_files` with
+//! destinations under `/run`. Documents are either plain `#cloud-config`
+//! YAML or MIME multipart archives with `text/cloud-config` parts, possibly
+//! gzip-compressed; other formats (e.g. shell scripts) are ignored.
+
+use crate::journal::{self, Event};
+use crate::network;
+use crate::providers::MetadataProvider;
+use crate::util;
+use anyhow::{anyhow, bail, Context, Result};
+use openssh_keys::PublicKey;
+use serde::Deserialize;
+use slog_scope::{debug, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Header line of cloud-config documents.
+const CLOUD_CONFIG_HEADER: &str = "#cloud-config";
+
+/// Directory under which `write_files` entries are allowed.
+const WRITE_FILES_DIR: &str = "/run";
+
+/// Supported subset of a cloud-config document.
+///
+/// Unknown keys are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub(crate) struct CloudConfig {
+    pub hostname: Option<String>,
+    pub fqdn: Option<String>,
+    pub ssh_authorized_keys: Vec<String>,
+    pub users: Vec<UserEntry>,
+    pub write_files: Vec<WriteFile>,
+}
+
+/// Entry of the `users` list, either a plain name (e.g. `default`) or a
+/// user definition.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum UserEntry {
+    Name(String),
+    User {
+        name: Option<String>,
+        #[serde(default)]
+        ssh_authorized_keys: Vec<String>,
+    },
+}
+
+/// Entry of the `write_files` list.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub(crate) struct WriteFile {
+    pub path: String,
+    pub content: String,
+    pub encoding: Option<String>,
+    /// Octal file mode, e.g. `'0644'`.
+    pub permissions: Option<String>,
+    pub owner: Option<String>,
+}
+
+impl CloudConfig {
+    /// Parse user data or vendor data.
+    ///
+    /// This returns `None` if the data does not contain any cloud-config
+    /// document.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>> {
+        let data = util::maybe_gunzip(data.to_vec())?;
+        if data.starts_with(CLOUD_CONFIG_HEADER.as_bytes()) {
+            let text = std::str::from_utf8(&data).context("invalid UTF-8 in cloud-config")?;
+            return Self::from_yaml(text).map(Some);
+        }
+        if is_multipart(&data) {
+            return Self::from_multipart(&data);
+        }
+        debug!("user data is not a cloud-config document, ignoring");
+        Ok(None)
+    }
+
+    fn from_yaml(text: &str) -> Result<Self> {
+        // An empty document only holds the header comment.
+        let config: Option<Self> =
+            serde_yaml::from_str(text).context("failed to parse cloud-config")?;
+        Ok(config.unwrap_or_default())
+    }
+
+    fn from_multipart(data: &[u8]) -> Result<Option<Self>> {
+        let mail = mailparse::parse_mail(data).context("failed to parse MIME multipart")?;
+        let mut merged: Option<Self> = None;
+        for part in mail.subparts {
+            if part.ctype.mimetype != "text/cloud-config" {
+                debug!("ignoring unsupported {} part", part.ctype.mimetype);
+                continue;
+            }
+            let body = part
+                .get_body()
+                .context("failed to get cloud-config content")?;
+            let config = Self::from_yaml(&body)?;
+            merged = Some(match merged {
+                Some(previous) => previous.merge(config),
+                None => config,
+            });
+        }
+        Ok(merged)
+    }
+
+    /// Merge another document on top of this one.
+    ///
+    /// Scalar values are overridden, lists are concatenated.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.hostname = other.hostname.or(self.hostname);
+        self.fqdn = other.fqdn.or(self.fqdn);
+        self.ssh_authorized_keys.extend(other.ssh_authorized_keys);
+        self.users.extend(other.users);
+        self.write_files.extend(other.write_files);
+        self
+    }
+
+    /// Return the hostname, preferring `hostname` over `fqdn`.
+    pub fn hostname(&self) -> Option<String> {
+        self.hostname
+            .clone()
+            .or_else(|| self.fqdn.clone())
+            .filter(|h| !h.is_empty())
+    }
+
+    /// Return all SSH keys, both top-level and from user definitions.
+    pub fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let user_keys = self.users.iter().flat_map(|u| match u {
+            UserEntry::User {
+                ssh_authorized_keys,
+                ..
+            } => ssh_authorized_keys.as_slice(),
+            UserEntry::Name(_) => &[],
+        });
+        self.ssh_authorized_keys
+            .iter()
+            .chain(user_keys)
+            .map(|k| PublicKey::parse(k).context("failed to parse cloud-config SSH key"))
+            .collect()
+    }
+
+    /// Write all `write_files` entries, returning the paths of written files.
+    ///
+    /// Entries outside of `/run` are skipped.
+    pub fn write_files(&self) -> Result<Vec<PathBuf>> {
+        let mut written = vec![];
+        for file in &self.write_files {
+            let path = match allowed_path(&file.path) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("skipping cloud-config file: {:#}", e);
+                    continue;
+                }
+            };
+            if let Some(owner) = &file.owner {
+                if owner != "root" && owner != "root:root" {
+                    warn!(
+                        "ignoring owner {:?} of cloud-config file {}",
+                        owner, file.path
+                    );
+                }
+            }
+            let path = util::rooted(path)?;
+            write_file(&path, file)?;
+
+            let path_str = path.to_string_lossy();
+            journal::send(
+                Event::CloudConfigFileWritten,
+                &format!("wrote cloud-config file {path_str}"),
+                &[("AFTERBURN_PATH", &path_str)],
+            );
+            written.push(path);
+        }
+        Ok(written)
+    }
+}
+
+/// Whether the given data looks like a MIME multipart archive.
+fn is_multipart(data: &[u8]) -> bool {
+    match mailparse::parse_headers(data) {
+        Ok((headers, _)) => headers.iter().any(|h| {
+            h.get_key().eq_ignore_ascii_case("Content-Type")
+                && h.get_value().to_ascii_lowercase().starts_with("multipart/")
+        }),
+        Err(_) => false,
+    }
+}
+
+/// Check that a `write_files` destination is an absolute, normalized path
+/// under `/run`.
+fn allowed_path(path: &str) -> Result<&Path> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        bail!("path {:?} is not absolute", path);
+    }
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::RootDir | Component::Normal(_)))
+    {
+        bail!("path {:?} is not normalized", path);
+    }
+    if !path.starts_with(WRITE_FILES_DIR) || path == Path::new(WRITE_FILES_DIR) {
+        bail!("path {:?} is not under {}", path, WRITE_FILES_DIR);
+    }
+    Ok(path)
+}
+
+fn write_file(path: &Path, file: &WriteFile) -> Result<()> {
+    let encoding = match file.encoding.as_deref().unwrap_or_default() {
+        "text/plain" => "",
+        "gz+base64" | "gzip+b64" => "gzip+base64",
+        other => other,
+    };
+    let content = util::decode(encoding, file.content.as_bytes())
+        .with_context(|| format!("failed to decode cloud-config file {:?}", file.path))?;
+    let mode = match &file.permissions {
+        Some(p) => u32::from_str_radix(p.trim_start_matches("0o"), 8)
+            .map_err(|_| anyhow!("invalid permissions {:?} for {:?}", p, file.path))?,
+        None => 0o644,
+    };
+
+    let dir_path = path
+        .parent()
+        .ok_or_else(|| anyhow!("could not get parent directory of {:?}", path))?;
+    fs::create_dir_all(dir_path)
+        .with_context(|| format!("failed to create directory {dir_path:?}"))?;
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(".afterburn-")
+        .tempfile_in(dir_path)
+        .context("failed to create temporary file")?;
+    temp_file
+        .write_all(&content)
+        .with_context(|| format!("failed to write to file {:?}", temp_file.path()))?;
+    temp_file
+        .as_file()
+        .set_permissions(fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions of {:?}", temp_file.path()))?;
+    temp_file
+        .persist(path)
+        .map_err(|e| {
+            e.file.close().ok();
+            e.error
+        })
+        .with_context(|| format!("failed to persist file {path:?}"))?;
+    Ok(())
+}
+
+/// Metadata provider overlaying cloud-config hostname and SSH keys on top
+/// of another provider.
+///
+/// Vendor data is applied first, then user data on top of it.
+pub(crate) struct CloudConfigProvider {
+    inner: Box<dyn MetadataProvider>,
+    config: CloudConfig,
+}
+
+impl CloudConfigProvider {
+    /// Fetch and parse cloud-config from the vendor data and user data of
+    /// the given provider.
+    pub fn try_new(inner: Box<dyn MetadataProvider>) -> Result<Self> {
+        let mut config = CloudConfig::default();
+        let sources = [
+            ("vendor data", inner.vendor_data()?),
+            ("user data", inner.user_data()?),
+        ];
+        for (name, data) in sources {
+            if let Some(data) = data {
+                let parsed = CloudConfig::parse(&data)
+                    .with_context(|| format!("failed to parse cloud-config from {name}"))?;
+                if let Some(parsed) = parsed {
+                    config = config.merge(parsed);
+                }
+            }
+        }
+        Ok(Self { inner, config })
+    }
+
+    /// Return the merged cloud-config.
+    pub fn config(&self) -> &CloudConfig {
+        &self.config
+    }
+}
+
+impl MetadataProvider for CloudConfigProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        self.inner.attributes()
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        match self.config.hostname() {
+            Some(hostname) => Ok(Some(hostname)),
+            None => self.inner.hostname(),
+        }
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let mut keys = self.inner.ssh_keys()?;
+        for key in self.config.ssh_keys()? {
+            if !keys.iter().any(|k| k.to_string() == key.to_string()) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.inner.networks()
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        self.inner.netplan_config()
+    }
+
+    fn boot_checkin(&self) -> Result<()> {
+        self.inner.boot_checkin()
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        self.inner.virtual_network_devices()
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        self.inner.rd_network_kargs()
+    }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.user_data()
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.vendor_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9C/qb9iHvZ0VTMLsZaoVXA48akrfkJwpO5EnE3STbk core@mock";
+    const KEY_2: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBjYTHGYkNK7DZ4Gn0NGN1sjFUVapus4GXybEYg/ylcA some-key";
+
+    #[test]
+    fn test_parse_cloud_config() {
+        let data = format!(
+            "#cloud-config\nhostname: mock-host\nfqdn: mock-host.example.com\nssh_authorized_keys:\n  - {KEY_1}\nusers:\n  - default\n  - name: core\n    ssh_authorized_keys:\n      - {KEY_2}\n  - name: other\nruncmd:\n  - [ls, /]\n"
+        );
+        let config = CloudConfig::parse(data.as_bytes()).unwrap().unwrap();
+        assert_eq!(config.hostname().as_deref(), Some("mock-host"));
+        let keys = config.ssh_keys().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].to_string(), KEY_1);
+        assert_eq!(keys[1].to_string(), KEY_2);
+
+        // Header only.
+        let config = CloudConfig::parse(b"#cloud-config\n").unwrap().unwrap();
+        assert_eq!(config, CloudConfig::default());
+
+        // Not cloud-config.
+        assert_eq!(CloudConfig::parse(b"#!/bin/sh\necho hi\n").unwrap(), None);
+        CloudConfig::parse(b"#cloud-config\nusers: [\n").unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let vendordata = fs::read("./tests/fixtures/ibmcloud/vendor-data").unwrap();
+        let config = CloudConfig::parse(&vendordata).unwrap().unwrap();
+        assert_eq!(config.ssh_authorized_keys.len(), 2);
+        assert_eq!(config.users.len(), 2);
+        assert_eq!(config.write_files.len(), 1);
+        assert_eq!(config.hostname(), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let vendor = CloudConfig {
+            hostname: Some("vendor".into()),
+            fqdn: Some("vendor.example.com".into()),
+            ssh_authorized_keys: vec![KEY_1.into()],
+            ..Default::default()
+        };
+        let user = CloudConfig {
+            hostname: Some("user".into()),
+            ssh_authorized_keys: vec![KEY_2.into()],
+            ..Default::default()
+        };
+        let merged = vendor.merge(user);
+        assert_eq!(merged.hostname.as_deref(), Some("user"));
+        assert_eq!(merged.fqdn.as_deref(), Some("vendor.example.com"));
+        assert_eq!(merged.ssh_authorized_keys, vec![KEY_1, KEY_2]);
+    }
+
+    #[test]
+    fn test_allowed_path() {
+        allowed_path("/run/foo/bar").unwrap();
+        allowed_path("/run").unwrap_err();
+        allowed_path("/etc/passwd").unwrap_err();
+        allowed_path("/run/../etc/passwd").unwrap_err();
+        allowed_path("/runner/foo").unwrap_err();
+        allowed_path("run/foo").unwrap_err();
+    }
+
+    #[test]
+    fn test_write_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/app/config");
+        let file = WriteFile {
+            path: "/run/app/config".into(),
+            content: "aGVsbG8=".into(),
+            encoding: Some("b64".into()),
+            permissions: Some("0600".into()),
+            owner: None,
+        };
+        write_file(&path, &file).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    struct ProviderMock;
+
+    impl MetadataProvider for ProviderMock {
+        fn hostname(&self) -> Result<Option<String>> {
+            Ok(Some("provider-host".into()))
+        }
+
+        fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+            Ok(vec![PublicKey::parse(KEY_1)?])
+        }
+
+        fn user_data(&self) -> Result<Option<Vec<u8>>> {
+            let data = format!("#cloud-config\nssh_authorized_keys:\n  - {KEY_1}\n  - {KEY_2}\n");
+            Ok(Some(data.into_bytes()))
+        }
+
+        fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+            Ok(Some(b"#cloud-config\nhostname: vendor-host\n".to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_cloud_config_provider() {
+        let provider = CloudConfigProvider::try_new(Box::new(ProviderMock)).unwrap();
+        assert_eq!(provider.hostname().unwrap().as_deref(), Some("vendor-host"));
+        let keys: Vec<_> = provider
+            .ssh_keys()
+            .unwrap()
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        assert_eq!(keys, vec![KEY_1, KEY_2]);
+    }
+}
@@ -0,0 +1,443 @@
+//! Configuration file parsing.
+//!
+//! Configuration is read from `config.toml` in the configuration directory,
+//! followed by `*.toml` drop-ins from `config.d/` in lexical order. Later
+//! fragments override earlier ones, key by key. All files are optional;
+//! command-line flags take precedence over any configured value.
+
+use crate::overrides::MetadataOverrides;
+use crate::providers;
+use crate::retry;
+use anyhow::{Context, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use slog_scope::{debug, trace};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default configuration directory.
+pub(crate) const CONFIG_DIR: &str = "/etc/afterburn";
+
+/// Afterburn configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The name of the cloud provider.
+    pub provider: Option<String>,
+    /// Actions to perform, with their respective targets.
+    pub actions: ActionsConfig,
+    /// Retry and backoff settings for all providers.
+    pub retry: RetryConfig,
+    /// Provider-specific settings, by provider name.
+    pub providers: BTreeMap<String, ProviderConfig>,
+    /// Run report destinations.
+    pub report: ReportConfig,
+    /// Metadata cache settings.
+    pub cache: CacheConfig,
+    /// Overrides layered over provider metadata.
+    pub overrides: MetadataOverrides,
+    /// Local metadata service settings.
+    pub serve: ServeConfig,
+    /// Names of files written by actions.
+    pub outputs: OutputsConfig,
+}
+
+/// Actions to perform, mirroring the `multi` command-line flags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ActionsConfig {
+    /// The file into which the metadata attributes are written.
+    pub attributes: Option<String>,
+    /// Check-in this instance boot with the cloud provider.
+    pub check_in: bool,
+    /// The file into which the hostname should be written.
+    pub hostname: Option<String>,
+    /// The directory into which network units are written.
+    pub network_units: Option<String>,
+    /// The directory into which a netplan config is written.
+    pub netplan_config: Option<String>,
+    /// Update SSH keys for the given user.
+    pub ssh_keys: Option<String>,
+    /// The file into which the user data is written.
+    pub user_data: Option<String>,
+    /// Apply cloud-config from user and vendor data.
+    pub cloud_config: bool,
+}
+
+/// Run report destinations, mirroring the `multi` command-line flags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ReportConfig {
+    /// The file into which a JSON run report is written.
+    pub path: Option<String>,
+    /// The file into which a Prometheus run report is written.
+    pub prometheus_path: Option<String>,
+}
+
+/// Metadata cache settings, mirroring the `multi` command-line flags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CacheConfig {
+    /// Reuse metadata cached by previous invocations.
+    pub enabled: bool,
+    /// Lifetime of cached metadata, in seconds.
+    pub ttl_secs: Option<u64>,
+}
+
+/// Local metadata service settings, mirroring the `serve` command-line flags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServeConfig {
+    /// Path of the listening socket.
+    pub socket: Option<String>,
+    /// Permissions of the listening socket.
+    pub mode: Option<u32>,
+    /// Group owning the listening socket.
+    pub group: Option<String>,
+    /// Exposed attributes, by name or `*`-terminated prefix.
+    pub attributes: Vec<String>,
+}
+
+/// Names of files written into output directories.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OutputsConfig {
+    /// Name of the netplan config, within the netplan config directory.
+    #[serde(deserialize_with = "deserialize_file_name")]
+    pub netplan_config_file: Option<String>,
+    /// Name of the SSH keys fragment, within `~/.ssh/authorized_keys.d`.
+    #[serde(deserialize_with = "deserialize_file_name")]
+    pub ssh_keys_fragment: Option<String>,
+}
+
+impl OutputsConfig {
+    /// Return the configured netplan config file name, or the default one.
+    pub fn netplan_config_file(&self) -> &str {
+        self.netplan_config_file
+            .as_deref()
+            .unwrap_or(providers::NETPLAN_CONFIG_FILE)
+    }
+
+    /// Return the configured SSH keys fragment name, or the default one.
+    pub fn ssh_keys_fragment(&self) -> &str {
+        self.ssh_keys_fragment
+            .as_deref()
+            .unwrap_or(providers::SSH_KEYS_FRAGMENT)
+    }
+}
+
+/// Deserialize an optional plain file name, without any directory part.
+fn deserialize_file_name<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = Option::<String>::deserialize(deserializer)?;
+    if let Some(name) = &name {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(D::Error::custom(format!("invalid file name '{name}'")));
+        }
+    }
+    Ok(name)
+}
+
+/// Retry and backoff settings; unset values fall back to built-in defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetryConfig {
+    pub max_retries: Option<u8>,
+    pub initial_backoff_secs: Option<u64>,
+    pub max_backoff_secs: Option<u64>,
+}
+
+/// Provider-specific settings.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ProviderConfig {
+    /// Retry and backoff settings, overriding the global ones.
+    pub retry: RetryConfig,
+    /// Alternative metadata endpoints (scheme, host and port only), by
+    /// original host.
+    pub endpoints: BTreeMap<String, String>,
+    /// Path of the metadata document, for the `file` provider.
+    pub path: Option<String>,
+}
+
+impl RetryConfig {
+    /// Merge two settings, with values in `other` taking precedence.
+    fn merged(&self, other: &Self) -> Self {
+        Self {
+            max_retries: other.max_retries.or(self.max_retries),
+            initial_backoff_secs: other.initial_backoff_secs.or(self.initial_backoff_secs),
+            max_backoff_secs: other.max_backoff_secs.or(self.max_backoff_secs),
+        }
+    }
+
+    /// Build a retrying driver, if any setting is configured.
+    fn to_retry(&self) -> Option<retry::Retry> {
+        if *self == Self::default() {
+            return None;
+        }
+        let mut retry = retry::Retry::new();
+        if let Some(n) = self.max_retries {
+            retry = retry.max_retries(n);
+        }
+        if let Some(secs) = self.initial_backoff_secs {
+            retry = retry.initial_backoff(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.max_backoff_secs {
+            retry = retry.max_backoff(Duration::from_secs(secs));
+        }
+        Some(retry)
+    }
+}
+
+impl Config {
+    /// Read and merge all configuration fragments from the given directory.
+    pub fn read_from(config_dir: &Path) -> Result<Self> {
+        let mut merged = toml::Table::new();
+        for path in Self::fragments(config_dir)? {
+            let content = match std::fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {}", path.display()))
+                }
+            };
+            debug!("reading configuration fragment {}", path.display());
+            let fragment: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+            merge_tables(&mut merged, fragment);
+        }
+
+        let cfg: Self = toml::Value::Table(merged)
+            .try_into()
+            .context("invalid configuration")?;
+        trace!("configuration - {:?}", cfg);
+        Ok(cfg)
+    }
+
+    /// Return the paths of all configuration fragments, in merge order.
+    fn fragments(config_dir: &Path) -> Result<Vec<PathBuf>> {
+        let dropins_dir = config_dir.join("config.d");
+        let mut dropins = match std::fs::read_dir(&dropins_dir) {
+            Ok(entries) => entries
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<_>>>()
+                .with_context(|| format!("failed to list {}", dropins_dir.display()))?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to list {}", dropins_dir.display()))
+            }
+        };
+        dropins.retain(|p| p.extension().map(|ext| ext == "toml").unwrap_or(false));
+        dropins.sort();
+
+        let mut paths = vec![config_dir.join("config.toml")];
+        paths.extend(dropins);
+        Ok(paths)
+    }
+
+    /// Return retry settings for the given provider.
+    pub fn retry_for(&self, provider: &str) -> RetryConfig {
+        match self.providers.get(provider) {
+            Some(p) => self.retry.merged(&p.retry),
+            None => self.retry.clone(),
+        }
+    }
+
+    /// Return the configured document path for the given provider, if any.
+    pub fn path_for(&self, provider: &str) -> Option<&str> {
+        self.providers.get(provider)?.path.as_deref()
+    }
+
+    /// Return HTTP client settings for the given provider.
+    pub fn client_config(&self, provider: &str) -> retry::ClientConfig {
+        retry::ClientConfig {
+            retry: self.retry_for(provider).to_retry(),
+            endpoints: self
+                .providers
+                .get(provider)
+                .map(|p| p.endpoints.clone())
+                .unwrap_or_default(),
+            deadline: None,
+        }
+    }
+}
+
+/// Recursively merge `src` into `dst`, with values in `src` taking precedence.
+fn merge_tables(dst: &mut toml::Table, src: toml::Table) {
+    for (key, value) in src {
+        match (dst.get_mut(&key), value) {
+            (Some(toml::Value::Table(d)), toml::Value::Table(s)) => merge_tables(d, s),
+            (_, v) => {
+                dst.insert(key, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_missing_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Config::read_from(&dir.path().join("nonexistent")).unwrap();
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_dropins_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            r#"
+provider = "aws"
+
+[actions]
+attributes = "/run/metadata/afterburn"
+check_in = true
+
+[retry]
+max_retries = 3
+initial_backoff_secs = 2
+"#,
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("config.d")).unwrap();
+        fs::write(
+            dir.path().join("config.d/20-override.toml"),
+            r#"
+[actions]
+check_in = false
+ssh_keys = "core"
+
+[providers.aws]
+endpoints = { "169.254.169.254" = "http://127.0.0.1:8080" }
+retry = { max_retries = 5 }
+
+[providers.file]
+path = "/etc/afterburn/lab.json"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("config.d/10-provider.toml"),
+            "provider = \"gcp\"\n[actions]\nhostname = \"/etc/hostname\"\n",
+        )
+        .unwrap();
+        // Not a drop-in.
+        fs::write(dir.path().join("config.d/30-ignored.conf"), "invalid").unwrap();
+
+        let cfg = Config::read_from(dir.path()).unwrap();
+        assert_eq!(cfg.provider.as_deref(), Some("gcp"));
+        assert_eq!(
+            cfg.actions,
+            ActionsConfig {
+                attributes: Some("/run/metadata/afterburn".to_string()),
+                check_in: false,
+                hostname: Some("/etc/hostname".to_string()),
+                network_units: None,
+                netplan_config: None,
+                ssh_keys: Some("core".to_string()),
+                user_data: None,
+                cloud_config: false,
+            }
+        );
+        assert_eq!(
+            cfg.client_config("aws").endpoints,
+            maplit::btreemap! {
+                "169.254.169.254".to_string() => "http://127.0.0.1:8080".to_string(),
+            }
+        );
+        assert!(cfg.client_config("gcp").endpoints.is_empty());
+        assert_eq!(cfg.path_for("file"), Some("/etc/afterburn/lab.json"));
+        assert_eq!(cfg.path_for("aws"), None);
+        assert_eq!(
+            cfg.retry_for("aws"),
+            RetryConfig {
+                max_retries: Some(5),
+                initial_backoff_secs: Some(2),
+                max_backoff_secs: None,
+            }
+        );
+        assert_eq!(cfg.retry_for("gcp"), cfg.retry);
+    }
+
+    #[test]
+    fn test_outputs_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Config::read_from(dir.path()).unwrap();
+        assert_eq!(cfg.outputs.netplan_config_file(), "50-afterburn.yaml");
+        assert_eq!(cfg.outputs.ssh_keys_fragment(), "afterburn");
+
+        fs::write(
+            dir.path().join("config.toml"),
+            "[outputs]\nnetplan_config_file = \"90-metadata.yaml\"\nssh_keys_fragment = \"metadata\"\n",
+        )
+        .unwrap();
+        let cfg = Config::read_from(dir.path()).unwrap();
+        assert_eq!(cfg.outputs.netplan_config_file(), "90-metadata.yaml");
+        assert_eq!(cfg.outputs.ssh_keys_fragment(), "metadata");
+
+        for name in ["", "..", "../authorized_keys"] {
+            fs::write(
+                dir.path().join("config.toml"),
+                format!("[outputs]\nssh_keys_fragment = \"{name}\"\n"),
+            )
+            .unwrap();
+            Config::read_from(dir.path()).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("config.toml"), "[actions]\nfoo = 1\n").unwrap();
+        Config::read_from(dir.path()).unwrap_err();
+
+        fs::write(dir.path().join("config.toml"), "provider = [").unwrap();
+        Config::read_from(dir.path()).unwrap_err();
+    }
+
+    #[test]
+    fn test_serve_config() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            "[serve]\nmode = 0o666\nattributes = [\"AWS_REGION\", \"AWS_AVAILABILITY_*\"]\n",
+        )
+        .unwrap();
+        let cfg = Config::read_from(dir.path()).unwrap();
+        assert_eq!(
+            cfg.serve,
+            ServeConfig {
+                socket: None,
+                mode: Some(0o666),
+                group: None,
+                attributes: vec!["AWS_REGION".to_string(), "AWS_AVAILABILITY_*".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_config() {
+        assert_eq!(RetryConfig::default().to_retry(), None);
+        let cfg = RetryConfig {
+            max_retries: Some(2),
+            initial_backoff_secs: None,
+            max_backoff_secs: Some(30),
+        };
+        assert_eq!(
+            cfg.to_retry(),
+            Some(
+                retry::Retry::new()
+                    .max_retries(2)
+                    .max_backoff(Duration::from_secs(30))
+            )
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! D-Bus service exposing instance metadata.
+//!
+//! The `org.coreos.Afterburn1` service is published by the `daemon`
+//! sub-command, and serves the metadata fetched by its last refresh. Local
+//! agents can read it through properties, be notified of changes through the
+//! standard `PropertiesChanged` signal, and request an immediate refresh
+//! through the `Refresh()` method.
+
+use crate::metadata::MetadataSnapshot;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use slog_scope::{debug, info};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use zbus::{blocking, dbus_interface, zvariant};
+
+/// Well-known bus name of the service.
+pub(crate) const BUS_NAME: &str = "org.coreos.Afterburn1";
+
+/// Path of the metadata object.
+pub(crate) const OBJECT_PATH: &str = "/org/coreos/Afterburn1";
+
+/// Name of the metadata interface.
+pub(crate) const INTERFACE: &str = "org.coreos.Afterburn1";
+
+/// Message bus to publish the service on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Bus {
+    #[default]
+    System,
+    Session,
+}
+
+/// Metadata object.
+struct Metadata {
+    provider: String,
+    snapshot: MetadataSnapshot,
+    refresh_requests: Mutex<Sender<()>>,
+}
+
+#[dbus_interface(name = "org.coreos.Afterburn1")]
+impl Metadata {
+    /// Request an immediate metadata refresh.
+    ///
+    /// This returns once the request is queued; changes are signalled.
+    fn refresh(&self) -> zbus::fdo::Result<()> {
+        debug!("metadata refresh requested over D-Bus");
+        self.refresh_requests
+            .lock()
+            .map_err(|_| zbus::fdo::Error::Failed("refresh queue poisoned".to_string()))?
+            .send(())
+            .map_err(|_| zbus::fdo::Error::Failed("daemon is shutting down".to_string()))
+    }
+
+    /// Name of the provider.
+    #[dbus_interface(property)]
+    fn provider(&self) -> String {
+        self.provider.clone()
+    }
+
+    /// Metadata attributes.
+    #[dbus_interface(property)]
+    fn attributes(&self) -> HashMap<String, String> {
+        self.snapshot.attributes.clone().into_iter().collect()
+    }
+
+    /// Hostname, or an empty string if the provider has none.
+    #[dbus_interface(property)]
+    fn hostname(&self) -> String {
+        self.snapshot.hostname.clone().unwrap_or_default()
+    }
+
+    /// SSH public keys, in `authorized_keys` format.
+    #[dbus_interface(property)]
+    fn ssh_keys(&self) -> Vec<String> {
+        self.snapshot.ssh_keys.clone()
+    }
+}
+
+/// Published metadata service.
+pub(crate) struct Service {
+    connection: blocking::Connection,
+}
+
+impl Service {
+    /// Publish the service on the given bus.
+    ///
+    /// Refresh requests are sent to the given channel.
+    pub fn start(bus: Bus, provider: &str, refresh_requests: Sender<()>) -> Result<Self> {
+        let builder = match bus {
+            Bus::System => blocking::ConnectionBuilder::system(),
+            Bus::Session => blocking::ConnectionBuilder::session(),
+        }
+        .context("connecting to D-Bus")?;
+        let service = Self::serve(builder.name(BUS_NAME)?, provider, refresh_requests)?;
+        let bus_name = match bus {
+            Bus::System => "system",
+            Bus::Session => "session",
+        };
+        info!("published {} on the {} bus", BUS_NAME, bus_name);
+        Ok(service)
+    }
+
+    /// Serve the metadata object on the connection being built.
+    fn serve(
+        builder: blocking::ConnectionBuilder<'_>,
+        provider: &str,
+        refresh_requests: Sender<()>,
+    ) -> Result<Self> {
+        let metadata = Metadata {
+            provider: provider.to_string(),
+            snapshot: MetadataSnapshot::default(),
+            refresh_requests: Mutex::new(refresh_requests),
+        };
+        let connection = builder
+            .serve_at(OBJECT_PATH, metadata)?
+            .build()
+            .context("publishing metadata service")?;
+        Ok(Self { connection })
+    }
+
+    /// Update the served metadata, and signal changed properties.
+    pub fn update(&self, snapshot: &MetadataSnapshot) -> Result<()> {
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, Metadata>(OBJECT_PATH)?;
+        let mut changed: HashMap<&str, zvariant::Value<'_>> = HashMap::new();
+        {
+            let mut metadata = iface.get_mut();
+            if metadata.snapshot.attributes != snapshot.attributes {
+                metadata.snapshot.attributes = snapshot.attributes.clone();
+                changed.insert("Attributes", metadata.attributes().into());
+            }
+            if metadata.snapshot.hostname != snapshot.hostname {
+                metadata.snapshot.hostname = snapshot.hostname.clone();
+                changed.insert("Hostname", metadata.hostname().into());
+            }
+            if metadata.snapshot.ssh_keys != snapshot.ssh_keys {
+                metadata.snapshot.ssh_keys = snapshot.ssh_keys.clone();
+                changed.insert("SshKeys", metadata.ssh_keys().into());
+            }
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        debug!("D-Bus properties changed: {:?}", changed.keys());
+        self.connection
+            .emit_signal(
+                None::<zbus::names::BusName<'_>>,
+                OBJECT_PATH,
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &(INTERFACE, changed, Vec::<&str>::new()),
+            )
+            .context("signalling changed properties")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use zbus::dbus_proxy;
+
+    #[dbus_proxy(
+        interface = "org.coreos.Afterburn1",
+        default_path = "/org/coreos/Afterburn1"
+    )]
+    trait Afterburn1 {
+        fn refresh(&self) -> zbus::Result<()>;
+
+        #[dbus_proxy(property)]
+        fn provider(&self) -> zbus::Result<String>;
+
+        #[dbus_proxy(property)]
+        fn attributes(&self) -> zbus::Result<HashMap<String, String>>;
+
+        #[dbus_proxy(property)]
+        fn hostname(&self) -> zbus::Result<String>;
+
+        #[dbus_proxy(property)]
+        fn ssh_keys(&self) -> zbus::Result<Vec<String>>;
+    }
+
+    /// Serve the metadata object on a private peer-to-peer bus.
+    fn private_bus(
+        refresh_requests: Sender<()>,
+    ) -> (std::thread::JoinHandle<Service>, blocking::Connection) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let guid = zbus::Guid::generate();
+            let builder = blocking::ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p();
+            Service::serve(builder, "mock", refresh_requests).unwrap()
+        });
+        let client = blocking::ConnectionBuilder::unix_stream(client_stream)
+            .p2p()
+            .build()
+            .unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn test_service() {
+        let (tx, rx) = mpsc::channel();
+        let (server, client) = private_bus(tx);
+        let service = server.join().unwrap();
+        let proxy = Afterburn1ProxyBlocking::builder(&client)
+            .destination(BUS_NAME)
+            .unwrap()
+            .cache_properties(zbus::CacheProperties::No)
+            .build()
+            .unwrap();
+
+        assert_eq!(proxy.provider().unwrap(), "mock");
+        assert!(proxy.attributes().unwrap().is_empty());
+        assert_eq!(proxy.hostname().unwrap(), "");
+
+        let mut signals = blocking::MessageIterator::from(&client);
+        let snapshot = MetadataSnapshot {
+            attributes: maplit::btreemap! {
+                "MOCK_ID".to_string() => "1".to_string(),
+            },
+            hostname: Some("mock.example.com".to_string()),
+            ..Default::default()
+        };
+        service.update(&snapshot).unwrap();
+        assert_eq!(
+            proxy.attributes().unwrap(),
+            maplit::hashmap! {
+                "MOCK_ID".to_string() => "1".to_string(),
+            }
+        );
+        assert_eq!(proxy.hostname().unwrap(), "mock.example.com");
+        assert!(proxy.ssh_keys().unwrap().is_empty());
+
+        let signal = signals
+            .find(|msg| {
+                let header = msg.as_ref().unwrap().header().unwrap();
+                header.member().unwrap().map(|m| m.as_str()) == Some("PropertiesChanged")
+            })
+            .unwrap()
+            .unwrap();
+        let (iface, changed, _): (String, HashMap<String, zvariant::OwnedValue>, Vec<String>) =
+            signal.body().unwrap();
+        assert_eq!(iface, INTERFACE);
+        let mut names: Vec<_> = changed.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["Attributes", "Hostname"]);
+
+        proxy.refresh().unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+//! Platform detection from DMI/SMBIOS data.
+//!
+//! This is a fallback for systems which are not booted by Ignition, and thus
+//! do not carry a platform ID on the kernel command-line. Identification
+//! strings exposed by the firmware via sysfs are matched against a table of
+//! known cloud vendors, with each match carrying a confidence score.
+
+use crate::errors::{Error, ErrorKind};
+use anyhow::{bail, Context, Result};
+use slog_scope::{debug, trace};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Path to DMI identification entries (requires sysfs mount).
+pub(crate) const DMI_ID_PATH: &str = "/sys/class/dmi/id";
+
+/// Minimum confidence required to automatically select a platform.
+const MIN_CONFIDENCE: u8 = 50;
+
+/// DMI identification fields used for detection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DmiField {
+    SysVendor,
+    ProductName,
+    ChassisAssetTag,
+    BoardVendor,
+}
+
+impl DmiField {
+    /// Return the name of the sysfs entry for this field.
+    fn file_name(&self) -> &'static str {
+        match *self {
+            DmiField::SysVendor => "sys_vendor",
+            DmiField::ProductName => "product_name",
+            DmiField::ChassisAssetTag => "chassis_asset_tag",
+            DmiField::BoardVendor => "board_vendor",
+        }
+    }
+}
+
+/// A detection rule: all the given fields must contain the respective
+/// (lowercase) patterns for the rule to match.
+struct Rule {
+    provider: &'static str,
+    matches: &'static [(DmiField, &'static str)],
+    confidence: u8,
+}
+
+/// Known DMI identification strings, by provider.
+const RULES: &[Rule] = &[
+    Rule {
+        provider: "aliyun",
+        matches: &[(DmiField::ProductName, "alibaba cloud ecs")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "aliyun",
+        matches: &[(DmiField::SysVendor, "alibaba cloud")],
+        confidence: 90,
+    },
+    Rule {
+        provider: "aws",
+        matches: &[(DmiField::SysVendor, "amazon ec2")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "aws",
+        matches: &[(DmiField::BoardVendor, "amazon ec2")],
+        confidence: 90,
+    },
+    // Azure marks its VMs with a well-known chassis asset tag.
+    Rule {
+        provider: "azure",
+        matches: &[
+            (DmiField::SysVendor, "microsoft corporation"),
+            (
+                DmiField::ChassisAssetTag,
+                "7783-7084-3265-9085-8269-3286-77",
+            ),
+        ],
+        confidence: 100,
+    },
+    // Any other Hyper-V guest.
+    Rule {
+        provider: "azure",
+        matches: &[
+            (DmiField::SysVendor, "microsoft corporation"),
+            (DmiField::ProductName, "virtual machine"),
+        ],
+        confidence: 40,
+    },
+    Rule {
+        provider: "cloudstack-metadata",
+        matches: &[(DmiField::ProductName, "cloudstack")],
+        confidence: 60,
+    },
+    Rule {
+        provider: "digitalocean",
+        matches: &[(DmiField::SysVendor, "digitalocean")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "exoscale",
+        matches: &[(DmiField::SysVendor, "exoscale")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "exoscale",
+        matches: &[(DmiField::ProductName, "exoscale")],
+        confidence: 90,
+    },
+    Rule {
+        provider: "gcp",
+        matches: &[
+            (DmiField::SysVendor, "google"),
+            (DmiField::ProductName, "google compute engine"),
+        ],
+        confidence: 100,
+    },
+    Rule {
+        provider: "gcp",
+        matches: &[(DmiField::ProductName, "google compute engine")],
+        confidence: 90,
+    },
+    Rule {
+        provider: "hetzner",
+        matches: &[(DmiField::SysVendor, "hetzner")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "ibmcloud",
+        matches: &[(DmiField::ChassisAssetTag, "ibmcloud")],
+        confidence: 90,
+    },
+    Rule {
+        provider: "kubevirt",
+        matches: &[(DmiField::SysVendor, "kubevirt")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "openstack",
+        matches: &[(DmiField::ProductName, "openstack")],
+        confidence: 80,
+    },
+    Rule {
+        provider: "openstack",
+        matches: &[(DmiField::SysVendor, "openstack")],
+        confidence: 80,
+    },
+    Rule {
+        provider: "openstack",
+        matches: &[(DmiField::ChassisAssetTag, "openstack")],
+        confidence: 70,
+    },
+    Rule {
+        provider: "scaleway",
+        matches: &[(DmiField::SysVendor, "scaleway")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "vmware",
+        matches: &[(DmiField::SysVendor, "vmware")],
+        confidence: 100,
+    },
+    Rule {
+        provider: "vultr",
+        matches: &[(DmiField::SysVendor, "vultr")],
+        confidence: 100,
+    },
+];
+
+/// DMI identification strings of the current machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DmiInfo {
+    pub sys_vendor: Option<String>,
+    pub product_name: Option<String>,
+    pub chassis_asset_tag: Option<String>,
+    pub board_vendor: Option<String>,
+}
+
+impl DmiInfo {
+    /// Read DMI identification entries from the given sysfs directory.
+    ///
+    /// Missing entries are not an error, as firmwares are free to omit them.
+    pub fn read_from(dmi_dir: &Path) -> Result<Self> {
+        let read = |field: DmiField| -> Result<Option<String>> {
+            let path = dmi_dir.join(field.file_name());
+            match std::fs::read_to_string(&path) {
+                Ok(content) => {
+                    let value = content.trim();
+                    trace!("DMI {}: {:?}", field.file_name(), value);
+                    Ok(Some(value.to_string()).filter(|v| !v.is_empty()))
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+            }
+        };
+
+        Ok(Self {
+            sys_vendor: read(DmiField::SysVendor)?,
+            product_name: read(DmiField::ProductName)?,
+            chassis_asset_tag: read(DmiField::ChassisAssetTag)?,
+            board_vendor: read(DmiField::BoardVendor)?,
+        })
+    }
+
+    fn field(&self, field: DmiField) -> Option<&str> {
+        match field {
+            DmiField::SysVendor => self.sys_vendor.as_deref(),
+            DmiField::ProductName => self.product_name.as_deref(),
+            DmiField::ChassisAssetTag => self.chassis_asset_tag.as_deref(),
+            DmiField::BoardVendor => self.board_vendor.as_deref(),
+        }
+    }
+
+    /// Whether all the conditions of the given rule are satisfied.
+    fn matches(&self, rule: &Rule) -> bool {
+        rule.matches.iter().all(|(field, pattern)| {
+            self.field(*field)
+                .map(|v| v.to_lowercase().contains(pattern))
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// A candidate platform, with a confidence score (0-100).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Detection {
+    pub provider: &'static str,
+    pub confidence: u8,
+}
+
+/// Return all candidate platforms for the given DMI data.
+///
+/// Candidates are sorted by decreasing confidence, then by name.
+pub(crate) fn candidates(dmi: &DmiInfo) -> Vec<Detection> {
+    let mut scores: BTreeMap<&'static str, u8> = BTreeMap::new();
+    for rule in RULES.iter().filter(|r| dmi.matches(r)) {
+        let score = scores.entry(rule.provider).or_default();
+        *score = (*score).max(rule.confidence);
+    }
+
+    let mut out: Vec<_> = scores
+        .into_iter()
+        .map(|(provider, confidence)| Detection {
+            provider,
+            confidence,
+        })
+        .collect();
+    out.sort_by_key(|d| std::cmp::Reverse(d.confidence));
+    out
+}
+
+/// Detect the current platform from the DMI entries in the given directory.
+pub(crate) fn detect_platform(dmi_dir: &Path) -> Result<String> {
+    let dmi = DmiInfo::read_from(dmi_dir)?;
+    debug!("detecting platform from DMI data: {:?}", dmi);
+
+    match candidates(&dmi).first() {
+        Some(best) if best.confidence >= MIN_CONFIDENCE => {
+            debug!(
+                "detected platform '{}' (confidence {})",
+                best.provider, best.confidence
+            );
+            Ok(best.provider.to_string())
+        }
+        Some(best) => bail!(Error::new(
+            ErrorKind::UnsupportedPlatform,
+            format!(
+                "platform detection inconclusive, best candidate '{}' has confidence {} (minimum {})",
+                best.provider, best.confidence, MIN_CONFIDENCE
+            )
+        )),
+        None => bail!(Error::new(
+            ErrorKind::UnsupportedPlatform,
+            "failed to detect platform from DMI data"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn fake_sysfs(entries: &[(&str, &str)]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for (name, content) in entries {
+            fs::write(root.path().join(name), format!("{content}\n")).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_read_dmi() {
+        let root = fake_sysfs(&[
+            ("sys_vendor", "Google"),
+            ("product_name", "Google Compute Engine"),
+            ("chassis_asset_tag", ""),
+        ]);
+        let dmi = DmiInfo::read_from(root.path()).unwrap();
+        assert_eq!(
+            dmi,
+            DmiInfo {
+                sys_vendor: Some("Google".to_string()),
+                product_name: Some("Google Compute Engine".to_string()),
+                chassis_asset_tag: None,
+                board_vendor: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_detect_platform() {
+        let tests = vec![
+            (vec![("sys_vendor", "Amazon EC2")], "aws"),
+            (
+                vec![
+                    ("sys_vendor", "Microsoft Corporation"),
+                    ("product_name", "Virtual Machine"),
+                    ("chassis_asset_tag", "7783-7084-3265-9085-8269-3286-77"),
+                ],
+                "azure",
+            ),
+            (
+                vec![
+                    ("sys_vendor", "Google"),
+                    ("product_name", "Google Compute Engine"),
+                ],
+                "gcp",
+            ),
+            (
+                vec![("sys_vendor", "DigitalOcean"), ("product_name", "Droplet")],
+                "digitalocean",
+            ),
+            (
+                vec![
+                    ("sys_vendor", "OpenStack Foundation"),
+                    ("product_name", "OpenStack Nova"),
+                ],
+                "openstack",
+            ),
+            (vec![("sys_vendor", "VMware, Inc.")], "vmware"),
+        ];
+
+        for (entries, expected) in tests {
+            let root = fake_sysfs(&entries);
+            let provider = detect_platform(root.path()).unwrap();
+            assert_eq!(provider, expected, "failed testcase: {entries:?}");
+        }
+    }
+
+    #[test]
+    fn test_detect_inconclusive() {
+        // Generic Hyper-V guest, not necessarily on Azure.
+        let root = fake_sysfs(&[
+            ("sys_vendor", "Microsoft Corporation"),
+            ("product_name", "Virtual Machine"),
+        ]);
+        let dmi = DmiInfo::read_from(root.path()).unwrap();
+        assert_eq!(
+            candidates(&dmi),
+            vec![Detection {
+                provider: "azure",
+                confidence: 40
+            }]
+        );
+        detect_platform(root.path()).unwrap_err();
+
+        // Unknown hardware.
+        let root = fake_sysfs(&[("sys_vendor", "QEMU")]);
+        detect_platform(root.path()).unwrap_err();
+
+        // Empty sysfs.
+        let root = fake_sysfs(&[]);
+        detect_platform(root.path()).unwrap_err();
+    }
+
+    #[test]
+    fn test_candidates_order() {
+        let dmi = DmiInfo {
+            sys_vendor: Some("OpenStack Foundation".to_string()),
+            product_name: Some("CloudStack KVM Hypervisor".to_string()),
+            ..Default::default()
+        };
+        let providers: Vec<_> = candidates(&dmi).into_iter().map(|d| d.provider).collect();
+        assert_eq!(providers, vec!["openstack", "cloudstack-metadata"]);
+    }
+}
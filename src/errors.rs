@@ -0,0 +1,158 @@
+//! Error categories and exit codes.
+//!
+//! Errors are propagated as `anyhow::Error` throughout. At exit, the error
+//! chain is inspected to find the most specific category, either from an
+//! explicit [`Error`] or from well-known underlying error types, and the
+//! process exits with the matching code.
+
+use std::fmt;
+
+/// Error categories, each mapped to a distinct exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /// Any other failure.
+    Other,
+    /// Invalid command-line arguments.
+    Usage,
+    /// Unknown, undetectable or unsupported platform.
+    UnsupportedPlatform,
+    /// Metadata service unreachable or unavailable.
+    NetworkUnreachable,
+    /// Missing or malformed metadata.
+    BadMetadata,
+    /// Local filesystem or system failure.
+    LocalIo,
+    /// Some of the requested actions failed.
+    PartialFailure,
+    /// Files on disk differ from provider metadata.
+    Drift,
+}
+
+impl ErrorKind {
+    /// Return the process exit code for this category.
+    pub fn exit_code(&self) -> u8 {
+        match *self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::UnsupportedPlatform => 3,
+            ErrorKind::NetworkUnreachable => 4,
+            ErrorKind::BadMetadata => 5,
+            ErrorKind::LocalIo => 6,
+            ErrorKind::PartialFailure => 7,
+            ErrorKind::Drift => 8,
+        }
+    }
+}
+
+/// An error with an explicit category.
+#[derive(Debug)]
+pub(crate) struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Return the category of the given error.
+///
+/// An explicit [`Error`] anywhere in the chain, either as root cause or as
+/// context, takes precedence. Otherwise the error chain is walked from the
+/// outermost context to the root cause, and the first recognized error type
+/// determines the category.
+pub(crate) fn kind_of(err: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return e.kind;
+    }
+    err.chain()
+        .find_map(|e| {
+            if e.is::<clap::Error>() {
+                Some(ErrorKind::Usage)
+            } else if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+                match e.status() {
+                    Some(s) if s.is_client_error() => Some(ErrorKind::BadMetadata),
+                    _ if e.is_decode() => Some(ErrorKind::BadMetadata),
+                    _ => Some(ErrorKind::NetworkUnreachable),
+                }
+            } else if e.is::<serde_json::Error>()
+                || e.is::<serde_xml_rs::Error>()
+                || e.is::<serde_yaml::Error>()
+                || e.is::<openssh_keys::errors::OpenSSHKeyError>()
+                || e.is::<std::net::AddrParseError>()
+                || e.is::<ipnetwork::IpNetworkError>()
+            {
+                Some(ErrorKind::BadMetadata)
+            } else if e.is::<std::io::Error>() || e.is::<nix::Error>() {
+                Some(ErrorKind::LocalIo)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(ErrorKind::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn test_kind_of() {
+        let err = anyhow!("generic failure");
+        assert_eq!(kind_of(&err), ErrorKind::Other);
+
+        let err = anyhow!(Error::new(
+            ErrorKind::UnsupportedPlatform,
+            "unknown provider"
+        ))
+        .context("fetching metadata from provider");
+        assert_eq!(kind_of(&err), ErrorKind::UnsupportedPlatform);
+
+        let res: Result<serde_json::Value> =
+            serde_json::from_str("{").context("failed to deserialize data");
+        let err = res.context("fetching metadata").unwrap_err();
+        assert_eq!(kind_of(&err), ErrorKind::BadMetadata);
+
+        let res: Result<String> =
+            std::fs::read_to_string("/nonexistent/afterburn").context("failed to read file");
+        assert_eq!(kind_of(&res.unwrap_err()), ErrorKind::LocalIo);
+
+        // Explicit categories win.
+        let err = anyhow!(std::io::Error::from(std::io::ErrorKind::NotFound)).context(Error::new(
+            ErrorKind::PartialFailure,
+            "1 of 2 actions failed",
+        ));
+        assert_eq!(kind_of(&err), ErrorKind::PartialFailure);
+    }
+
+    #[test]
+    fn test_unique_exit_codes() {
+        let kinds = [
+            ErrorKind::Other,
+            ErrorKind::Usage,
+            ErrorKind::UnsupportedPlatform,
+            ErrorKind::NetworkUnreachable,
+            ErrorKind::BadMetadata,
+            ErrorKind::LocalIo,
+            ErrorKind::PartialFailure,
+            ErrorKind::Drift,
+        ];
+        let codes: std::collections::HashSet<_> = kinds.iter().map(|k| k.exit_code()).collect();
+        assert_eq!(codes.len(), kinds.len());
+        assert!(!codes.contains(&0));
+    }
+}
@@ -0,0 +1,269 @@
+//! systemd generator enabling Afterburn units for the current platform.
+//!
+//! Instead of relying on hand-maintained `ConditionKernelCommandLine=` lists
+//! in unit files, the generator looks up the capabilities of the detected
+//! platform and only pulls in the units for supported actions. Each unit
+//! pulled in gets a drop-in resetting its platform conditions and selecting
+//! the detected provider.
+//!
+//! See `systemd.generator(7)` for the generator interface.
+
+use crate::metadata::{self, Capability};
+use anyhow::{Context, Result};
+use slog_scope::info;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Directory containing units shipped by Afterburn.
+pub(crate) const UNIT_DIR: &str = "/usr/lib/systemd/system";
+
+/// File marking an initrd environment.
+pub(crate) const INITRD_RELEASE_PATH: &str = "/etc/initrd-release";
+
+/// Default user for SSH keys.
+pub(crate) const DEFAULT_SSH_KEYS_USER: &str = "core";
+
+/// Name of generated drop-ins.
+const DROPIN_NAME: &str = "10-afterburn-generator.conf";
+
+/// Platforms expecting a check-in on first boot only.
+const FIRSTBOOT_CHECKIN_PLATFORMS: &[&str] = &["packet"];
+
+/// Platforms not providing the hostname via DHCP, which thus needs to be
+/// fetched from metadata and statically applied on first boot.
+const STATIC_HOSTNAME_PLATFORMS: &[&str] = &[
+    "aliyun",
+    "azure",
+    "azurestack",
+    "digitalocean",
+    "exoscale",
+    "hetzner",
+    "ibmcloud",
+    "kubevirt",
+    "scaleway",
+    "vultr",
+];
+
+/// A unit pulled in by another one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Dependency {
+    /// Unit pulling in the dependency.
+    pub unit: String,
+    /// Dependency type, i.e. `wants` or `requires`.
+    pub kind: &'static str,
+    /// Unit pulled in; template instances are linked to their template.
+    pub dependency: String,
+}
+
+impl Dependency {
+    fn new(unit: &str, kind: &'static str, dependency: impl Into<String>) -> Self {
+        Self {
+            unit: unit.to_string(),
+            kind,
+            dependency: dependency.into(),
+        }
+    }
+
+    /// Return the unit file the dependency links to.
+    fn target(&self) -> String {
+        match self.dependency.split_once('@') {
+            Some((prefix, rest)) => match rest.rsplit_once('.') {
+                Some((_, suffix)) => format!("{prefix}@.{suffix}"),
+                None => self.dependency.clone(),
+            },
+            None => self.dependency.clone(),
+        }
+    }
+}
+
+/// Generator for a given platform.
+#[derive(Clone, Debug)]
+pub(crate) struct Generator {
+    provider: String,
+    capabilities: Vec<Capability>,
+    in_initrd: bool,
+    ssh_keys_user: String,
+}
+
+impl Generator {
+    /// Create a generator for the given provider, or provider chain.
+    pub fn new(provider: &str, in_initrd: bool, ssh_keys_user: &str) -> Result<Self> {
+        Ok(Self {
+            provider: provider.to_string(),
+            capabilities: metadata::capabilities(provider)?,
+            in_initrd,
+            ssh_keys_user: ssh_keys_user.to_string(),
+        })
+    }
+
+    /// Return all dependencies to generate.
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        let supports = |c| self.capabilities.contains(&c);
+        let mut deps = vec![];
+        if self.in_initrd {
+            if supports(Capability::Hostname)
+                && STATIC_HOSTNAME_PLATFORMS.contains(&self.provider.as_str())
+            {
+                deps.push(Dependency::new(
+                    "ignition-complete.target",
+                    "requires",
+                    "afterburn-hostname.service",
+                ));
+            }
+            return deps;
+        }
+        if supports(Capability::BootCheckin) {
+            let unit = if FIRSTBOOT_CHECKIN_PLATFORMS.contains(&self.provider.as_str()) {
+                "afterburn-firstboot-checkin.service"
+            } else {
+                "afterburn-checkin.service"
+            };
+            deps.push(Dependency::new("multi-user.target", "wants", unit));
+        }
+        if supports(Capability::SshKeys) {
+            deps.push(Dependency::new(
+                "multi-user.target",
+                "wants",
+                "afterburn-sshkeys.target",
+            ));
+            deps.push(Dependency::new(
+                "afterburn-sshkeys.target",
+                "requires",
+                format!("afterburn-sshkeys@{}.service", self.ssh_keys_user),
+            ));
+        }
+        deps
+    }
+
+    /// Return the drop-in for units pulled in.
+    pub fn dropin(&self) -> String {
+        format!(
+            "# Generated by afterburn\n[Unit]\nConditionKernelCommandLine=\n\n[Service]\nEnvironment=AFTERBURN_OPT_PROVIDER=--provider={}\n",
+            self.provider
+        )
+    }
+
+    /// Write dependency symlinks and drop-ins to the given directory.
+    pub fn write(&self, output_dir: &Path, unit_dir: &Path) -> Result<()> {
+        for dep in self.dependencies() {
+            let wants_dir = output_dir.join(format!("{}.{}", dep.unit, dep.kind));
+            fs::create_dir_all(&wants_dir)
+                .with_context(|| format!("creating {}", wants_dir.display()))?;
+            let link = wants_dir.join(&dep.dependency);
+            symlink(unit_dir.join(dep.target()), &link)
+                .with_context(|| format!("creating symlink {}", link.display()))?;
+
+            // Templates are configured once, for all instances.
+            let dropin_dir = output_dir.join(format!("{}.d", dep.target()));
+            let dropin = dropin_dir.join(DROPIN_NAME);
+            if dep.dependency.ends_with(".service") && !dropin.exists() {
+                fs::create_dir_all(&dropin_dir)
+                    .with_context(|| format!("creating {}", dropin_dir.display()))?;
+                fs::write(&dropin, self.dropin())
+                    .with_context(|| format!("writing {}", dropin.display()))?;
+            }
+            info!(
+                "enabling {} for provider '{}'",
+                dep.dependency, self.provider
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether we are running in an initrd.
+pub(crate) fn in_initrd() -> bool {
+    Path::new(INITRD_RELEASE_PATH).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(generator: &Generator) -> Vec<String> {
+        generator
+            .dependencies()
+            .iter()
+            .map(|d| format!("{}.{}/{}", d.unit, d.kind, d.dependency))
+            .collect()
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let azure = Generator::new("azure", false, "core").unwrap();
+        assert_eq!(
+            names(&azure),
+            vec![
+                "multi-user.target.wants/afterburn-checkin.service",
+                "multi-user.target.wants/afterburn-sshkeys.target",
+                "afterburn-sshkeys.target.requires/afterburn-sshkeys@core.service",
+            ]
+        );
+        let azure = Generator::new("azure", true, "core").unwrap();
+        assert_eq!(
+            names(&azure),
+            vec!["ignition-complete.target.requires/afterburn-hostname.service"]
+        );
+
+        let packet = Generator::new("packet", false, "admin").unwrap();
+        assert_eq!(
+            names(&packet),
+            vec![
+                "multi-user.target.wants/afterburn-firstboot-checkin.service",
+                "multi-user.target.wants/afterburn-sshkeys.target",
+                "afterburn-sshkeys.target.requires/afterburn-sshkeys@admin.service",
+            ]
+        );
+
+        // The hostname comes from DHCP on these platforms.
+        for provider in ["aws", "gcp"] {
+            let generator = Generator::new(provider, true, "core").unwrap();
+            assert!(generator.dependencies().is_empty(), "{provider}");
+        }
+
+        let vmware = Generator::new("vmware", false, "core").unwrap();
+        assert!(vmware.dependencies().is_empty());
+        let vmware = Generator::new("vmware", true, "core").unwrap();
+        assert!(vmware.dependencies().is_empty());
+
+        Generator::new("nope", false, "core").unwrap_err();
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let generator = Generator::new("scaleway", false, "core").unwrap();
+        generator.write(dir.path(), Path::new(UNIT_DIR)).unwrap();
+
+        let link = dir
+            .path()
+            .join("afterburn-sshkeys.target.requires/afterburn-sshkeys@core.service");
+        assert_eq!(
+            fs::read_link(link).unwrap(),
+            Path::new("/usr/lib/systemd/system/afterburn-sshkeys@.service")
+        );
+        let link = dir
+            .path()
+            .join("multi-user.target.wants/afterburn-checkin.service");
+        assert_eq!(
+            fs::read_link(link).unwrap(),
+            Path::new("/usr/lib/systemd/system/afterburn-checkin.service")
+        );
+
+        let dropin = fs::read_to_string(
+            dir.path()
+                .join("afterburn-sshkeys@.service.d")
+                .join(DROPIN_NAME),
+        )
+        .unwrap();
+        assert!(dropin.contains("\nConditionKernelCommandLine=\n"));
+        assert!(dropin.contains("AFTERBURN_OPT_PROVIDER=--provider=scaleway\n"));
+        assert!(dir
+            .path()
+            .join("afterburn-checkin.service.d")
+            .join(DROPIN_NAME)
+            .exists());
+        assert!(!dir.path().join("afterburn-sshkeys.target.d").exists());
+    }
+}
@@ -0,0 +1,235 @@
+//! Post-action hook scripts.
+//!
+//! After each action, all executables in `hooks.d/<action>/` under the
+//! configuration directory are run in lexical order, with metadata
+//! attributes and the action outcome in their environment. Hook failures
+//! are logged, but do not affect the outcome of the action.
+
+use crate::providers::MetadataProvider;
+use anyhow::{Context, Result};
+use slog_scope::{debug, info, warn};
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+/// Name of the hooks directory, relative to the configuration directory.
+pub(crate) const HOOKS_DIR_NAME: &str = "hooks.d";
+
+/// Hook scripts, with the metadata to pass to them.
+pub(crate) struct Hooks {
+    dir: PathBuf,
+    metadata: Rc<dyn MetadataProvider>,
+    /// Environment common to all hooks, built on first use.
+    env: OnceCell<BTreeMap<String, String>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("dir", &self.dir)
+            .field("env", &self.env)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Hooks {
+    /// Prepare hooks from the given directory.
+    ///
+    /// This returns `None` if the directory does not exist. Metadata
+    /// attributes, passed to all hooks, are only fetched when a hook is
+    /// about to run.
+    pub fn load(dir: &Path, metadata: Rc<dyn MetadataProvider>) -> Result<Option<Self>> {
+        if !dir
+            .try_exists()
+            .with_context(|| format!("failed to check {}", dir.display()))?
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            dir: dir.to_path_buf(),
+            metadata,
+            env: OnceCell::new(),
+        }))
+    }
+
+    /// Return the environment common to all hooks.
+    fn env(&self) -> &BTreeMap<String, String> {
+        self.env.get_or_init(|| {
+            let attributes = self.metadata.attributes().unwrap_or_else(|e| {
+                warn!("failed to fetch attributes for hooks: {:#}", e);
+                Default::default()
+            });
+            attributes
+                .into_iter()
+                .map(|(k, v)| (format!("AFTERBURN_{k}"), v))
+                .collect()
+        })
+    }
+
+    /// Run all hooks for the given action and outcome.
+    ///
+    /// On success, the outcome lists the paths of files written by the action.
+    pub fn run(&self, action: &str, outcome: &Result<Vec<PathBuf>>) {
+        let hooks = match executables(&self.dir.join(action)) {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                warn!("failed to list {} hooks: {:#}", action, e);
+                return;
+            }
+        };
+
+        for hook in hooks {
+            let mut cmd = Command::new(&hook);
+            cmd.envs(self.env())
+                .env("AFTERBURN_HOOK_ACTION", action)
+                .stdin(Stdio::null());
+            match outcome {
+                Ok(paths) => {
+                    let paths: Vec<_> = paths.iter().map(|p| p.to_string_lossy()).collect();
+                    cmd.env("AFTERBURN_HOOK_RESULT", "success")
+                        .env("AFTERBURN_HOOK_PATHS", paths.join("\n"));
+                }
+                Err(e) => {
+                    cmd.env("AFTERBURN_HOOK_RESULT", "failure")
+                        .env("AFTERBURN_HOOK_ERROR", format!("{e:#}"));
+                }
+            }
+
+            info!("running {} hook {}", action, hook.display());
+            match cmd.output() {
+                Ok(out) if out.status.success() => {
+                    debug!(
+                        "hook {} output: {}",
+                        hook.display(),
+                        String::from_utf8_lossy(&out.stdout).trim_end()
+                    );
+                }
+                Ok(out) => warn!(
+                    "hook {} failed ({}): {}",
+                    hook.display(),
+                    out.status,
+                    String::from_utf8_lossy(&out.stderr).trim_end()
+                ),
+                Err(e) => warn!("failed to run hook {}: {}", hook.display(), e),
+            }
+        }
+    }
+}
+
+/// Return all executable files in the given directory, in lexical order.
+///
+/// Hidden files are ignored, as well as a missing directory.
+fn executables(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("failed to list {}", dir.display())),
+    };
+
+    let mut hooks = vec![];
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("failed to list {}", dir.display()))?
+            .path();
+        let hidden = path
+            .file_name()
+            .map(|n| n.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+        if hidden {
+            continue;
+        }
+        // Follow symlinks.
+        let meta = std::fs::metadata(&path)
+            .with_context(|| format!("failed to stat {}", path.display()))?;
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        } else {
+            debug!("ignoring non-executable hook {}", path.display());
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[derive(Default)]
+    struct AttributesMock {
+        fetched: Cell<u32>,
+    }
+
+    impl MetadataProvider for AttributesMock {
+        fn attributes(&self) -> Result<HashMap<String, String>> {
+            self.fetched.set(self.fetched.get() + 1);
+            Ok(maplit::hashmap! {
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+            })
+        }
+    }
+
+    fn write_hook(path: &Path, mode: u32) {
+        fs::write(
+            path,
+            "#!/bin/sh\nenv | grep ^AFTERBURN_ | sort > \"$0.out\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_missing_hooks_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = Rc::new(AttributesMock::default());
+        let hooks = Hooks::load(&dir.path().join(HOOKS_DIR_NAME), metadata).unwrap();
+        assert!(hooks.is_none());
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let hooks_dir = dir.path().join(HOOKS_DIR_NAME);
+        fs::create_dir_all(hooks_dir.join("hostname")).unwrap();
+        fs::create_dir_all(hooks_dir.join("check-in")).unwrap();
+        write_hook(&hooks_dir.join("hostname/10-notify"), 0o755);
+        write_hook(&hooks_dir.join("hostname/20-disabled"), 0o644);
+        write_hook(&hooks_dir.join("check-in/10-notify"), 0o755);
+
+        let metadata = Rc::new(AttributesMock::default());
+        let hooks = Hooks::load(&hooks_dir, metadata.clone()).unwrap().unwrap();
+        assert_eq!(
+            executables(&hooks_dir.join("hostname")).unwrap(),
+            vec![hooks_dir.join("hostname/10-notify")]
+        );
+
+        // Attributes are only fetched once a hook runs.
+        hooks.run("ssh-keys", &Ok(vec![]));
+        assert_eq!(metadata.fetched.get(), 0);
+
+        hooks.run("hostname", &Ok(vec!["/etc/hostname".into()]));
+        let env = fs::read_to_string(hooks_dir.join("hostname/10-notify.out")).unwrap();
+        assert_eq!(
+            env,
+            "AFTERBURN_HOOK_ACTION=hostname\nAFTERBURN_HOOK_PATHS=/etc/hostname\nAFTERBURN_HOOK_RESULT=success\nAFTERBURN_MOCK_REGION=moon-1\n"
+        );
+        assert!(!hooks_dir.join("hostname/20-disabled.out").exists());
+
+        hooks.run("check-in", &Err(anyhow!("service unavailable")));
+        let env = fs::read_to_string(hooks_dir.join("check-in/10-notify.out")).unwrap();
+        assert_eq!(
+            env,
+            "AFTERBURN_HOOK_ACTION=check-in\nAFTERBURN_HOOK_ERROR=service unavailable\nAFTERBURN_HOOK_RESULT=failure\nAFTERBURN_MOCK_REGION=moon-1\n"
+        );
+        assert_eq!(metadata.fetched.get(), 1);
+    }
+}
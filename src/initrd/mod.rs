@@ -4,6 +4,7 @@
 //! services are configured, so it may not be able to use all usual metadata
 //! fetcher.
 
+use crate::journal::{self, Event};
 use crate::providers::vmware::VmwareProvider;
 use crate::providers::MetadataProvider;
 use anyhow::{Context, Result};
@@ -23,8 +24,9 @@ pub(crate) fn fetch_network_kargs(provider: &str) -> Result<Option<String>> {
 
 /// Write network kargs into a cmdline.d fragment.
 pub(crate) fn write_network_kargs(kargs: &str) -> Result<()> {
-    let mut fragment_file = File::create(KARGS_PATH)
-        .with_context(|| format!("failed to create file {KARGS_PATH:?}"))?;
+    let path = crate::util::rooted(KARGS_PATH)?;
+    let mut fragment_file =
+        File::create(&path).with_context(|| format!("failed to create file {path:?}"))?;
 
     fragment_file
         .write_all(kargs.as_bytes())
@@ -33,5 +35,16 @@ pub(crate) fn write_network_kargs(kargs: &str) -> Result<()> {
         .write_all(&[b'\n'])
         .context("failed to write trailing newline")?;
 
+    journal::send(
+        Event::NetworkKargsWritten,
+        &format!(
+            "wrote initrd network kernel arguments to {}",
+            path.display()
+        ),
+        &[
+            ("AFTERBURN_PATH", &path.to_string_lossy()),
+            ("AFTERBURN_KARGS", kargs),
+        ],
+    );
     Ok(())
 }
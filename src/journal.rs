@@ -0,0 +1,134 @@
+//! Structured journal events.
+//!
+//! Every action performed by Afterburn is recorded in the journal with a
+//! stable `MESSAGE_ID`, so that other tools (e.g. console login helpers or
+//! monitoring agents) can match on it. Message IDs must never change once
+//! released; new events need new IDs (see `journalctl --new-id128`).
+
+use libsystemd::logging::{self, Priority};
+use openssh_keys::PublicKey;
+use slog_scope::warn;
+
+/// Actions recorded in the journal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    SshKeysWritten,
+    SshKeysRemoved,
+    HostnameWritten,
+    AttributesWritten,
+    NetworkUnitWritten,
+    NetdevUnitWritten,
+    NetplanConfigWritten,
+    BootCheckinSucceeded,
+    BootCheckinFailed,
+    NetworkKargsWritten,
+    UserDataWritten,
+    CloudConfigFileWritten,
+}
+
+impl Event {
+    /// Return the stable message ID for this event.
+    pub fn message_id(&self) -> &'static str {
+        match *self {
+            Event::SshKeysWritten => "0f7d7a502f2d433caa1323440a6b4190",
+            Event::SshKeysRemoved => "f8b91c53f5544868a3a10d0dcf68e9ea",
+            Event::HostnameWritten => "95cb42ced6bc49ef97c424cd4334bf50",
+            Event::AttributesWritten => "dac1136ee7ed40c78a6a992242267c1c",
+            Event::NetworkUnitWritten => "e11589de83ac4d708b0fb6b8c059ef2a",
+            Event::NetdevUnitWritten => "8f1cd9ae8beb41c5ad1c0cc32790bb73",
+            Event::NetplanConfigWritten => "2380267dee7447538d30db093576e8b4",
+            Event::BootCheckinSucceeded => "da597fe0e8b740df90d10e58f32fe836",
+            Event::BootCheckinFailed => "2f07f6edbad24443834a60014d012356",
+            Event::NetworkKargsWritten => "279f8242b75941e288599f63afde160d",
+            Event::UserDataWritten => "2b987158e19d456eaffba6ea626aadfd",
+            Event::CloudConfigFileWritten => "0022dddbf150434abd07632a27597c04",
+        }
+    }
+
+    fn priority(&self) -> Priority {
+        match *self {
+            Event::BootCheckinFailed => Priority::Error,
+            _ => Priority::Info,
+        }
+    }
+}
+
+/// Send an event to the journal, with additional fields.
+///
+/// Field names may be repeated, for multi-valued fields. Failures are only
+/// logged, as the journal may not be available (e.g. in containers).
+pub(crate) fn send(event: Event, message: &str, fields: &[(&str, &str)]) {
+    let vars = fields
+        .iter()
+        .copied()
+        .chain(std::iter::once(("MESSAGE_ID", event.message_id())));
+    if let Err(e) = logging::journal_send(event.priority(), message, vars) {
+        warn!("failed to send information to journald: {}", e);
+    }
+}
+
+/// Return journal fields describing the given SSH keys.
+///
+/// Each key adds an `AFTERBURN_SSH_KEY_TYPE` and an
+/// `AFTERBURN_SSH_KEY_FINGERPRINT` value, in the same order.
+pub(crate) fn ssh_key_fields(keys: &[PublicKey]) -> Vec<(&'static str, String)> {
+    keys.iter()
+        .flat_map(|k| {
+            [
+                ("AFTERBURN_SSH_KEY_TYPE", k.keytype().to_string()),
+                (
+                    "AFTERBURN_SSH_KEY_FINGERPRINT",
+                    format!("SHA256:{}", k.fingerprint()),
+                ),
+            ]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_unique_message_ids() {
+        let events = [
+            Event::SshKeysWritten,
+            Event::SshKeysRemoved,
+            Event::HostnameWritten,
+            Event::AttributesWritten,
+            Event::NetworkUnitWritten,
+            Event::NetdevUnitWritten,
+            Event::NetplanConfigWritten,
+            Event::BootCheckinSucceeded,
+            Event::BootCheckinFailed,
+            Event::NetworkKargsWritten,
+            Event::UserDataWritten,
+            Event::CloudConfigFileWritten,
+        ];
+        let ids: HashSet<_> = events.iter().map(|e| e.message_id()).collect();
+        assert_eq!(ids.len(), events.len());
+        for id in ids {
+            assert_eq!(id.len(), 32);
+            assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+    }
+
+    #[test]
+    fn test_ssh_key_fields() {
+        let key = PublicKey::parse(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9C/qb9iHvZ0VTMLsZaoVXA48akrfkJwpO5EnE3STbk core@mock",
+        )
+        .unwrap();
+        assert_eq!(
+            ssh_key_fields(&[key]),
+            vec![
+                ("AFTERBURN_SSH_KEY_TYPE", "ssh-ed25519".to_string()),
+                (
+                    "AFTERBURN_SSH_KEY_FINGERPRINT",
+                    "SHA256:H0zyVsKoa/2anPtmKbpUxCR7RLG/cBMCRsZszdJaH8s".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Logging setup.
+//!
+//! Log records are emitted through the global `slog_scope` logger, and routed
+//! to one of several backends depending on the configured format. Key-value
+//! pairs attached to records (e.g. `provider`, `action`, `url`, `attempt`)
+//! are rendered by the structured backends as proper fields.
+
+use clap::ValueEnum;
+use libsystemd::logging::{self, Priority};
+use slog::{slog_o, Drain, Level, OwnedKVList, Record, KV};
+use std::fmt;
+
+/// Log output formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable lines on the terminal.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Native journald entries.
+    Journald,
+}
+
+/// Return the log level for the given verbosity adjustment.
+///
+/// The default level is `Info`; positive values increase verbosity, negative
+/// values decrease it.
+pub(crate) fn level_for_verbosity(verbosity: i8) -> Level {
+    match verbosity {
+        i8::MIN..=-3 => Level::Critical,
+        -2 => Level::Error,
+        -1 => Level::Warning,
+        0 => Level::Info,
+        1 => Level::Debug,
+        2..=i8::MAX => Level::Trace,
+    }
+}
+
+/// Setup the global logger.
+///
+/// Logging stays active for as long as the returned guard is in scope.
+pub(crate) fn setup(format: LogFormat, level: Level) -> slog_scope::GlobalLoggerGuard {
+    let drain = match format {
+        LogFormat::Text => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+        LogFormat::Json => {
+            let drain = slog_json::Json::new(std::io::stderr())
+                .add_default_keys()
+                .build()
+                .fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+        LogFormat::Journald => slog_async::Async::new(JournaldDrain).build().fuse(),
+    };
+    let drain = drain.filter_level(level).fuse();
+    let log = slog::Logger::root(drain, slog_o!());
+    slog_scope::set_global_logger(log)
+}
+
+/// Drain sending log records to journald, with native fields.
+struct JournaldDrain;
+
+impl Drain for JournaldDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let mut fields = JournalFields(vec![
+            ("CODE_FILE".to_string(), record.file().to_string()),
+            ("CODE_LINE".to_string(), record.line().to_string()),
+            ("CODE_MODULE".to_string(), record.module().to_string()),
+        ]);
+        // Serialization into a vector cannot fail.
+        values.serialize(record, &mut fields).ok();
+        record.kv().serialize(record, &mut fields).ok();
+
+        let message = record.msg().to_string();
+        if let Err(e) = logging::journal_send(
+            journal_priority(record.level()),
+            &message,
+            fields.0.into_iter(),
+        ) {
+            eprintln!("failed to send log entry to journald: {e}: {message}");
+        }
+        Ok(())
+    }
+}
+
+/// Map a log level to a journal priority.
+fn journal_priority(level: Level) -> Priority {
+    match level {
+        Level::Critical => Priority::Critical,
+        Level::Error => Priority::Error,
+        Level::Warning => Priority::Warning,
+        Level::Info => Priority::Info,
+        Level::Debug | Level::Trace => Priority::Debug,
+    }
+}
+
+/// Journal fields collected from log record key-value pairs.
+struct JournalFields(Vec<(String, String)>);
+
+impl slog::Serializer for JournalFields {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push((journal_field_name(key), val.to_string()));
+        Ok(())
+    }
+}
+
+/// Convert a record key to a journal field name, e.g. `url` to `AFTERBURN_URL`.
+///
+/// Journal field names may only contain uppercase letters, digits and underscores.
+fn journal_field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("AFTERBURN_{name}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_for_verbosity() {
+        assert_eq!(level_for_verbosity(0), Level::Info);
+        assert_eq!(level_for_verbosity(1), Level::Debug);
+        assert_eq!(level_for_verbosity(5), Level::Trace);
+        assert_eq!(level_for_verbosity(-1), Level::Warning);
+        assert_eq!(level_for_verbosity(-2), Level::Error);
+        assert_eq!(level_for_verbosity(-10), Level::Critical);
+    }
+
+    #[test]
+    fn test_journal_field_name() {
+        assert_eq!(journal_field_name("url"), "AFTERBURN_URL");
+        assert_eq!(journal_field_name("user-name"), "AFTERBURN_USER_NAME");
+    }
+}
@@ -12,34 +12,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cache;
 mod cli;
+mod cloud_config;
+mod config;
+mod dbus;
+mod detect;
+mod errors;
+mod generator;
+mod hooks;
 mod initrd;
+mod journal;
+mod logging;
 mod metadata;
 mod network;
+mod overrides;
 mod providers;
+mod report;
 mod retry;
+mod serve;
 mod util;
 
 use anyhow::{Context, Result};
-use slog::{slog_o, Drain};
-use slog_scope::debug;
+use slog_scope::{debug, trace};
 use std::env;
+use std::process::ExitCode;
 
-fn main() -> Result<()> {
-    // Setup logging.
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    let log = slog::Logger::root(drain, slog_o!());
-    let _guard = slog_scope::set_global_logger(log);
-    debug!("logging initialized");
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(errors::kind_of(&e).exit_code())
+        }
+    }
+}
 
+fn run() -> Result<()> {
     // Parse command-line arguments.
-    let cli_cmd = cli::parse_args(env::args())?;
-    debug!("command-line arguments parsed");
+    let cli = cli::parse_args(env::args())?;
+
+    // Setup logging.
+    let _guard = logging::setup(cli.logging.log_format, cli.logging.level());
+    debug!("logging initialized");
+    trace!("cli configuration - {:?}", cli);
+    util::set_root(cli.root.clone());
+    if let Some(path) = &cli.replay {
+        let bundle = retry::capture::Bundle::read(path).context("loading replay bundle")?;
+        retry::capture::start_replay(bundle);
+    }
 
     // Run core logic.
-    cli_cmd.run().context("failed to run")?;
+    cli.cmd.run().context("failed to run")?;
     debug!("all tasks completed");
 
     Ok(())
@@ -12,15 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use openssh_keys::PublicKey;
+use serde::{Deserialize, Serialize};
+use slog_scope::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
 
+use crate::config::Config;
+use crate::errors::{Error, ErrorKind};
+use crate::network;
 use crate::providers;
 use crate::providers::aliyun::AliyunProvider;
 use crate::providers::aws::AwsProvider;
+use crate::providers::chain::ProviderChain;
 use crate::providers::cloudstack::configdrive::ConfigDrive;
 use crate::providers::cloudstack::network::CloudstackNetwork;
 use crate::providers::digitalocean::DigitalOceanProvider;
 use crate::providers::exoscale::ExoscaleProvider;
+use crate::providers::file::FileProvider;
 use crate::providers::gcp::GcpProvider;
 use crate::providers::hetzner::HetznerProvider;
 use crate::providers::ibmcloud::IBMGen2Provider;
@@ -35,6 +45,7 @@ use crate::providers::powervs::PowerVSProvider;
 use crate::providers::scaleway::ScalewayProvider;
 use crate::providers::vmware::VmwareProvider;
 use crate::providers::vultr::VultrProvider;
+use crate::retry;
 
 macro_rules! box_result {
     ($exp:expr) => {
@@ -46,31 +57,477 @@ macro_rules! box_result {
 ///
 /// This is the generic, top-level function to fetch provider metadata.
 /// The configured provider is passed in and this function dispatches the call
-/// to the provider-specific fetch logic.
-pub fn fetch_metadata(provider: &str) -> Result<Box<dyn providers::MetadataProvider>> {
+/// to the provider-specific fetch logic. Provider chains (see
+/// [`ProviderChain`]) are dispatched to each of their members.
+///
+/// HTTP clients are set up with the settings of the given configuration.
+pub fn fetch_metadata(
+    provider: &str,
+    config: &Config,
+) -> Result<Box<dyn providers::MetadataProvider>> {
+    if let Some(chain) = ProviderChain::parse(provider)? {
+        return box_result!(chain.fetch(config)?);
+    }
+    fetch_provider(provider, config, None)
+}
+
+/// Fetch metadata for the given provider, which must not be a chain.
+///
+/// HTTP requests are no longer retried after the given deadline, if any.
+pub(crate) fn fetch_provider(
+    provider: &str,
+    config: &Config,
+    deadline: Option<Instant>,
+) -> Result<Box<dyn providers::MetadataProvider>> {
+    let client = retry::ClientConfig {
+        deadline,
+        ..config.client_config(provider)
+    };
     match provider {
-        "aliyun" => box_result!(AliyunProvider::try_new()?),
-        "aws" => box_result!(AwsProvider::try_new()?),
-        "azure" => box_result!(Azure::try_new()?),
-        "azurestack" => box_result!(AzureStack::try_new()?),
-        "cloudstack-metadata" => box_result!(CloudstackNetwork::try_new()?),
+        "aliyun" => box_result!(AliyunProvider::try_new(&client)?),
+        "aws" => box_result!(AwsProvider::try_new(&client)?),
+        "azure" => box_result!(Azure::try_new(&client)?),
+        "azurestack" => box_result!(AzureStack::try_new(&client)?),
+        "cloudstack-metadata" => box_result!(CloudstackNetwork::try_new(&client)?),
         "cloudstack-configdrive" => box_result!(ConfigDrive::try_new()?),
-        "digitalocean" => box_result!(DigitalOceanProvider::try_new()?),
-        "exoscale" => box_result!(ExoscaleProvider::try_new()?),
-        "gcp" => box_result!(GcpProvider::try_new()?),
-        "hetzner" => box_result!(HetznerProvider::try_new()?),
+        "digitalocean" => box_result!(DigitalOceanProvider::try_new(&client)?),
+        "exoscale" => box_result!(ExoscaleProvider::try_new(&client)?),
+        "file" => box_result!(FileProvider::try_new(config.path_for(provider))?),
+        "gcp" => box_result!(GcpProvider::try_new(&client)?),
+        "hetzner" => box_result!(HetznerProvider::try_new(&client)?),
         // IBM Cloud - VPC Generation 2.
         "ibmcloud" => box_result!(IBMGen2Provider::try_new()?),
         // IBM Cloud - Classic infrastructure.
         "ibmcloud-classic" => box_result!(IBMClassicProvider::try_new()?),
         "kubevirt" => box_result!(KubeVirtProvider::try_new()?),
-        "openstack" => openstack::try_config_drive_else_network(),
-        "openstack-metadata" => box_result!(OpenstackProviderNetwork::try_new()?),
-        "packet" => box_result!(PacketProvider::try_new()?),
+        "openstack" => openstack::try_config_drive_else_network(&client),
+        "openstack-metadata" => box_result!(OpenstackProviderNetwork::try_new(&client)?),
+        "packet" => box_result!(PacketProvider::try_new(&client)?),
         "powervs" => box_result!(PowerVSProvider::try_new()?),
-        "scaleway" => box_result!(ScalewayProvider::try_new()?),
+        "scaleway" => box_result!(ScalewayProvider::try_new(&client)?),
         "vmware" => box_result!(VmwareProvider::try_new()?),
-        "vultr" => box_result!(VultrProvider::try_new()?),
-        _ => bail!("unknown provider '{}'", provider),
+        "vultr" => box_result!(VultrProvider::try_new(&client)?),
+        _ => bail!(Error::new(
+            ErrorKind::UnsupportedPlatform,
+            format!("unknown provider '{provider}'")
+        )),
+    }
+}
+
+/// A kind of metadata, or action, a provider may support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Attributes,
+    BootCheckin,
+    Hostname,
+    NetworkUnits,
+    NetplanConfig,
+    NetworkKargs,
+    SshKeys,
+    UserData,
+    VendorData,
+}
+
+impl Capability {
+    /// Return a human-readable description of this capability.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Capability::Attributes => "Attributes",
+            Capability::BootCheckin => "Boot check-in",
+            Capability::Hostname => "Hostname",
+            Capability::NetworkUnits => "Network configuration",
+            Capability::NetplanConfig => "Netplan configuration",
+            Capability::NetworkKargs => "Custom network command-line arguments",
+            Capability::SshKeys => "SSH keys",
+            Capability::UserData => "User data",
+            Capability::VendorData => "Vendor data",
+        }
+    }
+}
+
+/// A supported platform, with its capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Platform {
+    pub name: &'static str,
+    pub capabilities: &'static [Capability],
+}
+
+/// All platforms dispatched by [`fetch_metadata`], sorted by name.
+pub const PLATFORMS: &[Platform] = {
+    use Capability::*;
+    &[
+        Platform {
+            name: "aliyun",
+            capabilities: &[Attributes, Hostname, SshKeys],
+        },
+        Platform {
+            name: "aws",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "azure",
+            capabilities: &[Attributes, BootCheckin, Hostname, SshKeys],
+        },
+        Platform {
+            name: "azurestack",
+            capabilities: &[BootCheckin, Hostname, SshKeys],
+        },
+        Platform {
+            name: "cloudstack-configdrive",
+            capabilities: &[Attributes, SshKeys, UserData],
+        },
+        Platform {
+            name: "cloudstack-metadata",
+            capabilities: &[Attributes, SshKeys, UserData],
+        },
+        Platform {
+            name: "digitalocean",
+            capabilities: &[Attributes, Hostname, NetworkUnits, SshKeys, UserData],
+        },
+        Platform {
+            name: "exoscale",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "file",
+            capabilities: &[
+                Attributes,
+                Hostname,
+                NetworkUnits,
+                NetplanConfig,
+                NetworkKargs,
+                SshKeys,
+                UserData,
+                VendorData,
+            ],
+        },
+        Platform {
+            name: "gcp",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "hetzner",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "ibmcloud",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData, VendorData],
+        },
+        Platform {
+            name: "ibmcloud-classic",
+            capabilities: &[Attributes, Hostname, NetworkUnits],
+        },
+        Platform {
+            name: "kubevirt",
+            capabilities: &[Attributes, Hostname, SshKeys],
+        },
+        Platform {
+            name: "openstack",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "openstack-metadata",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+        Platform {
+            name: "packet",
+            capabilities: &[Attributes, BootCheckin, Hostname, NetworkUnits, SshKeys],
+        },
+        Platform {
+            name: "powervs",
+            capabilities: &[Attributes, Hostname, SshKeys],
+        },
+        Platform {
+            name: "scaleway",
+            capabilities: &[Attributes, BootCheckin, Hostname, SshKeys],
+        },
+        Platform {
+            name: "vmware",
+            capabilities: &[NetplanConfig, NetworkKargs, UserData],
+        },
+        Platform {
+            name: "vultr",
+            capabilities: &[Attributes, Hostname, SshKeys, UserData],
+        },
+    ]
+};
+
+/// Return the capabilities of the given provider, or provider chain.
+///
+/// A chain supports everything supported by any of its members, except for
+/// boot check-in which is only performed with the first member.
+pub fn capabilities(provider: &str) -> Result<Vec<Capability>> {
+    let names = match ProviderChain::parse(provider)? {
+        Some(chain) => chain.member_names(),
+        None => vec![provider.to_string()],
+    };
+    let mut capabilities = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let platform = match PLATFORMS.iter().find(|p| p.name == name) {
+            Some(platform) => platform,
+            None => bail!(Error::new(
+                ErrorKind::UnsupportedPlatform,
+                format!("unknown provider '{name}'")
+            )),
+        };
+        capabilities.extend(
+            platform
+                .capabilities
+                .iter()
+                .filter(|&&c| i == 0 || c != Capability::BootCheckin),
+        );
+    }
+    capabilities.sort();
+    capabilities.dedup();
+    Ok(capabilities)
+}
+
+/// Normalized view of all the metadata exposed by a provider.
+///
+/// This collects the results of every `MetadataProvider` getter into a
+/// single owned document, which can be serialized for inspection.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetadataSnapshot {
+    pub attributes: BTreeMap<String, String>,
+    pub hostname: Option<String>,
+    pub ssh_keys: Vec<String>,
+    pub networks: Vec<network::Interface>,
+    pub virtual_network_devices: Vec<network::VirtualNetDev>,
+    pub netplan_config: Option<String>,
+    pub rd_network_kargs: Option<String>,
+}
+
+impl MetadataSnapshot {
+    /// Query all metadata from the given provider.
+    pub fn collect(provider: &dyn providers::MetadataProvider) -> Result<Self> {
+        let snapshot = Self {
+            attributes: provider
+                .attributes()
+                .context("fetching attributes")?
+                .into_iter()
+                .collect(),
+            hostname: provider.hostname().context("fetching hostname")?,
+            ssh_keys: provider
+                .ssh_keys()
+                .context("fetching ssh keys")?
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            networks: provider.networks().context("fetching networks")?,
+            virtual_network_devices: provider
+                .virtual_network_devices()
+                .context("fetching virtual network devices")?,
+            netplan_config: provider
+                .netplan_config()
+                .context("fetching netplan config")?,
+            rd_network_kargs: provider
+                .rd_network_kargs()
+                .context("fetching initrd network kargs")?,
+        };
+        Ok(snapshot)
+    }
+
+    /// Query all metadata from the given provider, keeping the values from
+    /// `fallback` for failed queries.
+    pub fn collect_lenient(
+        name: &str,
+        provider: &dyn providers::MetadataProvider,
+        fallback: &Self,
+    ) -> Self {
+        fn ok_or_warn<T: Clone>(name: &str, what: &str, res: Result<T>, fallback: &T) -> T {
+            res.unwrap_or_else(|e| {
+                warn!("failed to fetch {} from provider '{}': {:#}", what, name, e);
+                fallback.clone()
+            })
+        }
+
+        Self {
+            attributes: ok_or_warn(
+                name,
+                "attributes",
+                provider.attributes().map(|a| a.into_iter().collect()),
+                &fallback.attributes,
+            ),
+            hostname: ok_or_warn(name, "hostname", provider.hostname(), &fallback.hostname),
+            ssh_keys: ok_or_warn(
+                name,
+                "ssh keys",
+                provider
+                    .ssh_keys()
+                    .map(|keys| keys.iter().map(ToString::to_string).collect()),
+                &fallback.ssh_keys,
+            ),
+            networks: ok_or_warn(name, "networks", provider.networks(), &fallback.networks),
+            virtual_network_devices: ok_or_warn(
+                name,
+                "virtual network devices",
+                provider.virtual_network_devices(),
+                &fallback.virtual_network_devices,
+            ),
+            netplan_config: ok_or_warn(
+                name,
+                "netplan config",
+                provider.netplan_config(),
+                &fallback.netplan_config,
+            ),
+            rd_network_kargs: ok_or_warn(
+                name,
+                "initrd network kargs",
+                provider.rd_network_kargs(),
+                &fallback.rd_network_kargs,
+            ),
+        }
+    }
+}
+
+/// Serve metadata from a snapshot, e.g. to replay previously fetched data.
+impl providers::MetadataProvider for MetadataSnapshot {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.attributes.clone().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.hostname.clone())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.ssh_keys
+            .iter()
+            .map(|key| PublicKey::parse(key).with_context(|| format!("invalid ssh key {key:?}")))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        Ok(self.networks.clone())
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        Ok(self.virtual_network_devices.clone())
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        Ok(self.netplan_config.clone())
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        Ok(self.rd_network_kargs.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SnapshotMock;
+
+    impl providers::MetadataProvider for SnapshotMock {
+        fn attributes(&self) -> Result<HashMap<String, String>> {
+            Ok(maplit::hashmap! {
+                "MOCK_REGION".to_string() => "earth-1".to_string(),
+                "MOCK_INSTANCE_ID".to_string() => "i-0123".to_string(),
+            })
+        }
+
+        fn hostname(&self) -> Result<Option<String>> {
+            Ok(Some("mock.example.com".to_string()))
+        }
+
+        fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+            let key = PublicKey::parse(
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9C/qb9iHvZ0VTMLsZaoVXA48akrfkJwpO5EnE3STbk core@mock",
+            )?;
+            Ok(vec![key])
+        }
+    }
+
+    #[test]
+    fn test_platforms() {
+        let names: Vec<_> = PLATFORMS.iter().map(|p| p.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(names, sorted);
+        for platform in PLATFORMS {
+            let mut capabilities = platform.capabilities.to_vec();
+            capabilities.sort();
+            assert_eq!(capabilities, platform.capabilities, "{}", platform.name);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        use Capability::*;
+        assert_eq!(
+            capabilities("vmware").unwrap(),
+            vec![NetplanConfig, NetworkKargs, UserData]
+        );
+        assert_eq!(
+            capabilities("azurestack,vmware").unwrap(),
+            vec![
+                BootCheckin,
+                Hostname,
+                NetplanConfig,
+                NetworkKargs,
+                SshKeys,
+                UserData
+            ]
+        );
+        assert_eq!(
+            capabilities("vmware+packet").unwrap(),
+            vec![
+                Attributes,
+                Hostname,
+                NetworkUnits,
+                NetplanConfig,
+                NetworkKargs,
+                SshKeys,
+                UserData
+            ]
+        );
+        capabilities("nope").unwrap_err();
+        capabilities("aws,nope").unwrap_err();
+    }
+
+    #[test]
+    fn test_collect_snapshot() {
+        let snapshot = MetadataSnapshot::collect(&SnapshotMock).unwrap();
+        let attrs: Vec<_> = snapshot.attributes.keys().collect();
+        assert_eq!(attrs, vec!["MOCK_INSTANCE_ID", "MOCK_REGION"]);
+        assert_eq!(snapshot.hostname.as_deref(), Some("mock.example.com"));
+        assert_eq!(snapshot.ssh_keys.len(), 1);
+        assert!(snapshot.ssh_keys[0].ends_with("core@mock"));
+        assert!(snapshot.networks.is_empty());
+        assert!(snapshot.virtual_network_devices.is_empty());
+        assert_eq!(snapshot.netplan_config, None);
+        assert_eq!(snapshot.rd_network_kargs, None);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let snapshot = MetadataSnapshot::collect(&SnapshotMock).unwrap();
+        let replayed = MetadataSnapshot::collect(&snapshot).unwrap();
+        assert_eq!(replayed, snapshot);
+
+        let invalid = MetadataSnapshot {
+            ssh_keys: vec!["not-a-key".to_string()],
+            ..Default::default()
+        };
+        providers::MetadataProvider::ssh_keys(&invalid).unwrap_err();
+    }
+
+    #[test]
+    fn test_snapshot_lenient() {
+        let previous = MetadataSnapshot::collect(&SnapshotMock).unwrap();
+        let invalid = MetadataSnapshot {
+            hostname: Some("other.example.com".to_string()),
+            ssh_keys: vec!["not-a-key".to_string()],
+            ..Default::default()
+        };
+        let snapshot = MetadataSnapshot::collect_lenient("mock", &invalid, &previous);
+        assert_eq!(snapshot.hostname.as_deref(), Some("other.example.com"));
+        assert!(snapshot.attributes.is_empty());
+        assert_eq!(snapshot.ssh_keys, previous.ssh_keys);
     }
 }
@@ -19,6 +19,7 @@
 use anyhow::{anyhow, bail, Context, Result};
 use ipnetwork::IpNetwork;
 use pnet_base::MacAddr;
+use serde::{Deserialize, Serialize};
 use std::fmt::Write;
 use std::net::IpAddr;
 use std::string::String;
@@ -57,7 +58,7 @@ pub fn try_parse_cidr(address: IpAddr, netmask: IpAddr) -> Result<IpNetwork> {
     IpNetwork::new(address, prefix).context("failed to parse network")
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NetworkRoute {
     pub destination: IpNetwork,
     pub gateway: IpAddr,
@@ -67,7 +68,7 @@ pub struct NetworkRoute {
 ///
 /// Depending on platforms, an interface may be identified by
 /// name or by MAC address (at least one of those must be provided).
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Interface {
     /// Interface name.
     pub name: Option<String>,
@@ -76,28 +77,34 @@ pub struct Interface {
     /// Path as identifier
     pub path: Option<String>,
     /// Relative priority for interface configuration.
+    #[serde(default)]
     pub priority: u8,
+    #[serde(default)]
     pub nameservers: Vec<IpAddr>,
+    #[serde(default)]
     pub ip_addresses: Vec<IpNetwork>,
+    #[serde(default)]
     pub routes: Vec<NetworkRoute>,
     pub bond: Option<String>,
+    #[serde(default)]
     pub unmanaged: bool,
     /// Optional requirement setting instead of the default
     pub required_for_online: Option<String>,
 }
 
 /// A virtual network interface.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VirtualNetDev {
     pub name: String,
     pub kind: NetDevKind,
     pub mac_address: MacAddr,
     pub priority: Option<u32>,
+    #[serde(default)]
     pub sd_netdev_sections: Vec<SdSection>,
 }
 
 /// A free-form `systemd.netdev` section.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SdSection {
     pub name: String,
     pub attributes: Vec<(String, String)>,
@@ -105,7 +112,8 @@ pub struct SdSection {
 
 /// Supported virtual network device kinds.
 #[allow(dead_code)]
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NetDevKind {
     /// Parent aggregation for physically bonded devices.
     Bond,
@@ -0,0 +1,257 @@
+//! Operator overrides layered over provider metadata.
+//!
+//! Overrides are read from the `[overrides]` configuration table, and from
+//! kernel arguments which take precedence over it:
+//!  - `afterburn.hostname=<name>` replaces the hostname
+//!  - `afterburn.ssh_key=<key>` (repeatable) adds an SSH key
+//!  - `afterburn.attr.<NAME>=<value>` sets an attribute
+//!  - `afterburn.network.disable` drops all network configuration
+
+use crate::network;
+use crate::providers::MetadataProvider;
+use crate::util::Cmdline;
+use anyhow::{Context, Result};
+use openssh_keys::PublicKey;
+use serde::Deserialize;
+use slog_scope::info;
+use std::collections::{BTreeMap, HashMap};
+
+/// Kernel argument replacing the hostname.
+const CMDLINE_HOSTNAME_FLAG: &str = "afterburn.hostname";
+
+/// Kernel argument adding an SSH key.
+const CMDLINE_SSH_KEY_FLAG: &str = "afterburn.ssh_key";
+
+/// Prefix of kernel arguments setting attributes.
+const CMDLINE_ATTR_PREFIX: &str = "afterburn.attr.";
+
+/// Kernel argument dropping network configuration.
+const CMDLINE_NETWORK_DISABLE_FLAG: &str = "afterburn.network.disable";
+
+/// Metadata overrides.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MetadataOverrides {
+    /// Hostname, replacing the provider one.
+    pub hostname: Option<String>,
+    /// SSH keys, added to the provider ones.
+    pub ssh_keys: Vec<String>,
+    /// Attributes, replacing the provider ones with the same name.
+    pub attributes: BTreeMap<String, String>,
+    /// Drop all network configuration from the provider.
+    pub disable_network: bool,
+}
+
+impl MetadataOverrides {
+    /// Layer overrides from the given kernel cmdline on top of these ones.
+    pub fn with_cmdline(mut self, cmdline: &Cmdline) -> Self {
+        if let Some(hostname) = cmdline
+            .value(CMDLINE_HOSTNAME_FLAG)
+            .filter(|v| !v.is_empty())
+        {
+            self.hostname = Some(hostname.to_string());
+        }
+        self.ssh_keys.extend(
+            cmdline
+                .values(CMDLINE_SSH_KEY_FLAG)
+                .into_iter()
+                .filter(|v| !v.is_empty())
+                .map(String::from),
+        );
+        self.attributes.extend(
+            cmdline
+                .with_prefix(CMDLINE_ATTR_PREFIX)
+                .into_iter()
+                .filter_map(|(name, value)| match value {
+                    Some(value) if !name.is_empty() && !value.is_empty() => {
+                        Some((name.to_string(), value.to_string()))
+                    }
+                    _ => None,
+                }),
+        );
+        self.disable_network |= cmdline.flag(CMDLINE_NETWORK_DISABLE_FLAG);
+        self
+    }
+
+    /// Whether there is nothing to override.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Wrap a provider, if there is anything to override.
+    pub fn apply(self, inner: Box<dyn MetadataProvider>) -> Box<dyn MetadataProvider> {
+        if self.is_empty() {
+            return inner;
+        }
+        info!("applying metadata overrides");
+        Box::new(OverridesProvider {
+            inner,
+            overrides: self,
+        })
+    }
+}
+
+/// Metadata provider applying overrides on top of another provider.
+struct OverridesProvider {
+    inner: Box<dyn MetadataProvider>,
+    overrides: MetadataOverrides,
+}
+
+impl MetadataProvider for OverridesProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        let mut attributes = self.inner.attributes()?;
+        attributes.extend(self.overrides.attributes.clone());
+        Ok(attributes)
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        match &self.overrides.hostname {
+            Some(hostname) => Ok(Some(hostname.clone())),
+            None => self.inner.hostname(),
+        }
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let mut keys = self.inner.ssh_keys()?;
+        for key in &self.overrides.ssh_keys {
+            let key = PublicKey::parse(key)
+                .with_context(|| format!("invalid override ssh key {key:?}"))?;
+            if !keys.iter().any(|k| k.to_string() == key.to_string()) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        if self.overrides.disable_network {
+            return Ok(vec![]);
+        }
+        self.inner.networks()
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        if self.overrides.disable_network {
+            return Ok(None);
+        }
+        self.inner.netplan_config()
+    }
+
+    fn boot_checkin(&self) -> Result<()> {
+        self.inner.boot_checkin()
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        if self.overrides.disable_network {
+            return Ok(vec![]);
+        }
+        self.inner.virtual_network_devices()
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        if self.overrides.disable_network {
+            return Ok(None);
+        }
+        self.inner.rd_network_kargs()
+    }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.user_data()
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.vendor_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::MetadataSnapshot;
+
+    const KEY_1: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9C/qb9iHvZ0VTMLsZaoVXA48akrfkJwpO5EnE3STbk core@mock";
+    const KEY_2: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBjYTHGYkNK7DZ4Gn0NGN1sjFUVapus4GXybEYg/ylcA emergency";
+
+    fn provider() -> Box<dyn MetadataProvider> {
+        Box::new(MetadataSnapshot {
+            attributes: maplit::btreemap! {
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+                "MOCK_ZONE".to_string() => "moon-1a".to_string(),
+            },
+            hostname: Some("broken".to_string()),
+            ssh_keys: vec![KEY_1.to_string()],
+            rd_network_kargs: Some("ip=dhcp".to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_with_cmdline() {
+        let config = MetadataOverrides {
+            hostname: Some("from-config".to_string()),
+            ssh_keys: vec![KEY_1.to_string()],
+            ..Default::default()
+        };
+        let cmdline = Cmdline::parse(&format!(
+            "quiet afterburn.hostname=from-karg afterburn.ssh_key=\"{KEY_2}\" afterburn.attr.MOCK_ZONE=moon-1b afterburn.attr.EMPTY= afterburn.network.disable\n"
+        ));
+        assert_eq!(
+            config.with_cmdline(&cmdline),
+            MetadataOverrides {
+                hostname: Some("from-karg".to_string()),
+                ssh_keys: vec![KEY_1.to_string(), KEY_2.to_string()],
+                attributes: maplit::btreemap! {
+                    "MOCK_ZONE".to_string() => "moon-1b".to_string(),
+                },
+                disable_network: true,
+            }
+        );
+        assert!(MetadataOverrides::default()
+            .with_cmdline(&Cmdline::parse("quiet rd.neednet=1 afterburn.hostname="))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let overrides = MetadataOverrides {
+            hostname: Some("fixed".to_string()),
+            ssh_keys: vec![KEY_1.to_string(), KEY_2.to_string()],
+            attributes: maplit::btreemap! {
+                "MOCK_ZONE".to_string() => "moon-1b".to_string(),
+            },
+            disable_network: true,
+        };
+        let metadata = overrides.apply(provider());
+        assert_eq!(metadata.hostname().unwrap().as_deref(), Some("fixed"));
+        assert_eq!(
+            metadata.attributes().unwrap(),
+            maplit::hashmap! {
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+                "MOCK_ZONE".to_string() => "moon-1b".to_string(),
+            }
+        );
+        let keys: Vec<_> = metadata
+            .ssh_keys()
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(keys, vec![KEY_1.to_string(), KEY_2.to_string()]);
+        assert_eq!(metadata.rd_network_kargs().unwrap(), None);
+
+        let invalid = MetadataOverrides {
+            ssh_keys: vec!["not-a-key".to_string()],
+            ..Default::default()
+        };
+        invalid.apply(provider()).ssh_keys().unwrap_err();
+
+        // Nothing to override.
+        let metadata = MetadataOverrides::default().apply(provider());
+        assert_eq!(metadata.hostname().unwrap().as_deref(), Some("broken"));
+        assert_eq!(
+            metadata.rd_network_kargs().unwrap().as_deref(),
+            Some("ip=dhcp")
+        );
+    }
+}
@@ -23,8 +23,8 @@ pub struct AliyunProvider {
 }
 
 impl AliyunProvider {
-    pub fn try_new() -> Result<AliyunProvider> {
-        let client = retry::Client::try_new()?.return_on_404(true);
+    pub fn try_new(config: &retry::ClientConfig) -> Result<AliyunProvider> {
+        let client = retry::Client::try_from_config(config)?.return_on_404(true);
 
         Ok(AliyunProvider { client })
     }
@@ -179,3 +179,31 @@ fn test_aws_imds_version2() {
     server.reset();
     provider.attributes().unwrap_err();
 }
+
+#[test]
+fn test_aws_user_data() {
+    let ep = "/2021-01-03/user-data";
+    let mut server = mockito::Server::new();
+    let client = crate::retry::Client::try_new()
+        .context("failed to create http client")
+        .unwrap()
+        .max_retries(0)
+        .return_on_404(true)
+        .mock_base_url(server.url());
+    let provider = aws::AwsProvider { client };
+
+    server.mock("GET", ep).with_status(404).create();
+    assert_eq!(provider.user_data().unwrap(), None);
+
+    // gzip-compressed "hello", served as binary
+    let compressed = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00, 0x00,
+    ];
+    server
+        .mock("GET", ep)
+        .with_status(200)
+        .with_body(compressed)
+        .create();
+    assert_eq!(provider.user_data().unwrap(), Some(b"hello".to_vec()));
+}
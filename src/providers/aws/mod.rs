@@ -25,6 +25,7 @@ use slog_scope::warn;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
+use crate::util;
 
 #[cfg(test)]
 mod mock_tests;
@@ -41,8 +42,8 @@ pub struct AwsProvider {
 }
 
 impl AwsProvider {
-    pub fn try_new() -> Result<AwsProvider> {
-        let client = retry::Client::try_new()?.return_on_404(true);
+    pub fn try_new(config: &retry::ClientConfig) -> Result<AwsProvider> {
+        let client = retry::Client::try_from_config(config)?.return_on_404(true);
         AwsProvider::with_client(client)
     }
 
@@ -202,4 +203,12 @@ impl MetadataProvider for AwsProvider {
                 .collect::<Result<Vec<_>>>()
         })?
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .get(retry::Raw, AwsProvider::endpoint_for("user-data", false))
+            .send()?;
+        user_data.map(|d| util::maybe_gunzip(d.0)).transpose()
+    }
 }
@@ -0,0 +1,409 @@
+//! Provider chains.
+//!
+//! A chain queries several providers in order, each with an optional
+//! timeout, and either uses the first one that succeeds, or merges metadata
+//! from all of them. Chains are written as provider names separated by `,`
+//! (first that succeeds) or by `+` (merge), each optionally followed by a
+//! timeout in seconds, e.g. `openstack-metadata:30,file`.
+//!
+//! Metadata is fetched eagerly when building the chain. User data, vendor
+//! data and boot check-in are served by member providers on demand.
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssh_keys::PublicKey;
+use slog_scope::{info, warn};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::errors::{Error, ErrorKind};
+use crate::metadata::{self, MetadataSnapshot};
+use crate::network;
+use crate::providers::MetadataProvider;
+
+/// Maximum timeout of a chain member, in seconds.
+const MAX_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+
+/// How metadata from chain members is combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainMode {
+    /// Use the first provider from which all metadata can be fetched.
+    First,
+    /// Use metadata from all providers, with later ones filling the gaps
+    /// left by earlier ones.
+    Merge,
+}
+
+/// A member of a provider chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainMember {
+    pub name: String,
+    pub timeout: Option<Duration>,
+}
+
+/// An ordered list of providers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProviderChain {
+    pub mode: ChainMode,
+    pub members: Vec<ChainMember>,
+}
+
+impl ProviderChain {
+    /// Parse a provider chain.
+    ///
+    /// This returns `None` for plain provider names.
+    pub fn parse(spec: &str) -> Result<Option<Self>> {
+        let mode = match (spec.contains(','), spec.contains('+')) {
+            (false, false) if !spec.contains(':') => return Ok(None),
+            (false, false) | (true, false) => ChainMode::First,
+            (false, true) => ChainMode::Merge,
+            (true, true) => bail!(usage_error(format!(
+                "provider chain '{spec}' mixes ',' and '+' separators"
+            ))),
+        };
+        let members = spec
+            .split([',', '+'])
+            .map(|member| {
+                let (name, timeout) = match member.split_once(':') {
+                    Some((name, secs)) => {
+                        let secs: u64 = secs
+                            .parse()
+                            .ok()
+                            .filter(|secs| *secs <= MAX_TIMEOUT_SECS)
+                            .ok_or_else(|| {
+                                usage_error(format!(
+                                    "invalid timeout '{secs}' for provider '{name}'"
+                                ))
+                            })?;
+                        (name, Some(Duration::from_secs(secs)))
+                    }
+                    None => (member, None),
+                };
+                if name.is_empty() {
+                    bail!(usage_error(format!(
+                        "empty provider name in chain '{spec}'"
+                    )));
+                }
+                Ok(ChainMember {
+                    name: name.to_string(),
+                    timeout,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Self { mode, members }))
+    }
+
+    /// Return the names of all members.
+    pub fn member_names(&self) -> Vec<String> {
+        self.members.iter().map(|m| m.name.clone()).collect()
+    }
+
+    /// Fetch metadata from chain members, with their configured settings.
+    pub fn fetch(&self, config: &Config) -> Result<ChainProvider> {
+        self.fetch_with(|name, deadline| metadata::fetch_provider(name, config, deadline))
+    }
+
+    fn fetch_with(
+        &self,
+        fetch: impl Fn(&str, Option<Instant>) -> Result<Box<dyn MetadataProvider>>,
+    ) -> Result<ChainProvider> {
+        let mut providers = vec![];
+        let mut snapshot = MetadataSnapshot::default();
+        let mut last_error = None;
+        for member in &self.members {
+            match self.fetch_member(member, &fetch) {
+                Ok((provider, metadata)) => {
+                    info!("fetched metadata from provider '{}'", member.name);
+                    fill_gaps(&mut snapshot, metadata);
+                    providers.push(provider);
+                    if self.mode == ChainMode::First {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to fetch metadata from provider '{}': {:#}",
+                        member.name, e
+                    );
+                    last_error = Some(e.context(format!("provider '{}'", member.name)));
+                }
+            }
+        }
+
+        if providers.is_empty() {
+            let e = last_error.unwrap_or_else(|| anyhow!("empty provider chain"));
+            return Err(e.context("all providers in chain failed"));
+        }
+        Ok(ChainProvider {
+            snapshot,
+            providers,
+        })
+    }
+
+    /// Fetch metadata from a single member, within its timeout.
+    fn fetch_member(
+        &self,
+        member: &ChainMember,
+        fetch: &impl Fn(&str, Option<Instant>) -> Result<Box<dyn MetadataProvider>>,
+    ) -> Result<(Box<dyn MetadataProvider>, MetadataSnapshot)> {
+        let deadline = member
+            .timeout
+            .map(|timeout| {
+                Instant::now().checked_add(timeout).ok_or_else(|| {
+                    usage_error(format!(
+                        "timeout for provider '{}' is too large",
+                        member.name
+                    ))
+                })
+            })
+            .transpose()?;
+        let provider = fetch(&member.name, deadline)?;
+        let metadata = match self.mode {
+            ChainMode::First => MetadataSnapshot::collect(provider.as_ref())?,
+            ChainMode::Merge => MetadataSnapshot::collect_lenient(
+                &member.name,
+                provider.as_ref(),
+                &MetadataSnapshot::default(),
+            ),
+        };
+        Ok((provider, metadata))
+    }
+}
+
+fn usage_error(message: String) -> anyhow::Error {
+    anyhow!(Error::new(ErrorKind::Usage, message))
+}
+
+/// Fill values missing from `dst` with the ones from `src`.
+///
+/// Attributes are merged key by key; any other value is only taken from
+/// `src` if it is unset or empty in `dst`.
+fn fill_gaps(dst: &mut MetadataSnapshot, src: MetadataSnapshot) {
+    for (key, value) in src.attributes {
+        dst.attributes.entry(key).or_insert(value);
+    }
+    if dst.hostname.is_none() {
+        dst.hostname = src.hostname;
+    }
+    if dst.ssh_keys.is_empty() {
+        dst.ssh_keys = src.ssh_keys;
+    }
+    if dst.networks.is_empty() {
+        dst.networks = src.networks;
+    }
+    if dst.virtual_network_devices.is_empty() {
+        dst.virtual_network_devices = src.virtual_network_devices;
+    }
+    if dst.netplan_config.is_none() {
+        dst.netplan_config = src.netplan_config;
+    }
+    if dst.rd_network_kargs.is_none() {
+        dst.rd_network_kargs = src.rd_network_kargs;
+    }
+}
+
+/// Metadata provider serving metadata fetched from a provider chain.
+pub struct ChainProvider {
+    snapshot: MetadataSnapshot,
+    /// Members from which metadata was fetched, in chain order.
+    providers: Vec<Box<dyn MetadataProvider>>,
+}
+
+impl ChainProvider {
+    /// Return the first value returned by a member.
+    fn first_some(
+        &self,
+        f: impl Fn(&dyn MetadataProvider) -> Result<Option<Vec<u8>>>,
+    ) -> Result<Option<Vec<u8>>> {
+        for provider in &self.providers {
+            if let Some(data) = f(provider.as_ref())? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl MetadataProvider for ChainProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        self.snapshot.attributes()
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        self.snapshot.hostname()
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.snapshot.ssh_keys()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.snapshot.networks()
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        self.snapshot.virtual_network_devices()
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        self.snapshot.netplan_config()
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        self.snapshot.rd_network_kargs()
+    }
+
+    fn boot_checkin(&self) -> Result<()> {
+        // check in with the first member which provided metadata
+        self.providers
+            .first()
+            .context("no provider in chain")?
+            .boot_checkin()
+    }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        self.first_some(|p| p.user_data())
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        self.first_some(|p| p.vendor_data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemberMock {
+        hostname: Option<&'static str>,
+        region: Option<&'static str>,
+        failing_hostname: bool,
+    }
+
+    impl MetadataProvider for MemberMock {
+        fn attributes(&self) -> Result<HashMap<String, String>> {
+            let mut attrs = maplit::hashmap! {
+                "MOCK_SOURCE".to_string() => self.hostname.unwrap_or("none").to_string(),
+            };
+            if let Some(region) = self.region {
+                attrs.insert("MOCK_REGION".to_string(), region.to_string());
+            }
+            Ok(attrs)
+        }
+
+        fn hostname(&self) -> Result<Option<String>> {
+            if self.failing_hostname {
+                bail!("hostname unavailable");
+            }
+            Ok(self.hostname.map(String::from))
+        }
+    }
+
+    fn fetch_mock(name: &str, _: Option<Instant>) -> Result<Box<dyn MetadataProvider>> {
+        match name {
+            "drive" => Ok(Box::new(MemberMock {
+                hostname: None,
+                region: None,
+                failing_hostname: true,
+            })),
+            "service" => Ok(Box::new(MemberMock {
+                hostname: Some("from-service"),
+                region: Some("moon-1"),
+                failing_hostname: false,
+            })),
+            "missing" => bail!("no such source"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_chain() {
+        assert_eq!(ProviderChain::parse("aws").unwrap(), None);
+        assert_eq!(
+            ProviderChain::parse("openstack:30,file").unwrap(),
+            Some(ProviderChain {
+                mode: ChainMode::First,
+                members: vec![
+                    ChainMember {
+                        name: "openstack".to_string(),
+                        timeout: Some(Duration::from_secs(30)),
+                    },
+                    ChainMember {
+                        name: "file".to_string(),
+                        timeout: None,
+                    },
+                ],
+            })
+        );
+        let chain = ProviderChain::parse("openstack+file").unwrap().unwrap();
+        assert_eq!(chain.mode, ChainMode::Merge);
+        assert_eq!(chain.member_names(), vec!["openstack", "file"]);
+        let chain = ProviderChain::parse("aws:10").unwrap().unwrap();
+        assert_eq!(chain.members[0].timeout, Some(Duration::from_secs(10)));
+
+        let chain = ProviderChain::parse("aws:86400").unwrap().unwrap();
+        assert_eq!(chain.members[0].timeout, Some(Duration::from_secs(86400)));
+
+        for invalid in [
+            "aws,gcp+file",
+            "aws,",
+            "aws:soon",
+            "+file",
+            "aws:86401",
+            "aws:18446744073709551615",
+        ] {
+            let err = ProviderChain::parse(invalid).unwrap_err();
+            assert_eq!(crate::errors::kind_of(&err), ErrorKind::Usage, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_timeout_overflow() {
+        let chain = ProviderChain {
+            mode: ChainMode::First,
+            members: vec![ChainMember {
+                name: "service".to_string(),
+                timeout: Some(Duration::MAX),
+            }],
+        };
+        let err = chain.fetch_with(fetch_mock).err().unwrap();
+        assert_eq!(crate::errors::kind_of(&err), ErrorKind::Usage);
+    }
+
+    #[test]
+    fn test_first_mode() {
+        let chain = ProviderChain::parse("missing,drive,service")
+            .unwrap()
+            .unwrap();
+        let provider = chain.fetch_with(fetch_mock).unwrap();
+        // drive fails to provide a hostname, so it is skipped entirely
+        assert_eq!(provider.providers.len(), 1);
+        assert_eq!(
+            provider.hostname().unwrap().as_deref(),
+            Some("from-service")
+        );
+
+        let chain = ProviderChain::parse("missing,drive").unwrap().unwrap();
+        assert!(chain.fetch_with(fetch_mock).is_err());
+    }
+
+    #[test]
+    fn test_merge_mode() {
+        let chain = ProviderChain::parse("missing+drive+service")
+            .unwrap()
+            .unwrap();
+        let provider = chain.fetch_with(fetch_mock).unwrap();
+        assert_eq!(provider.providers.len(), 2);
+        assert_eq!(
+            provider.hostname().unwrap().as_deref(),
+            Some("from-service")
+        );
+        assert_eq!(
+            provider.attributes().unwrap(),
+            maplit::hashmap! {
+                "MOCK_SOURCE".to_string() => "none".to_string(),
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+            }
+        );
+    }
+}
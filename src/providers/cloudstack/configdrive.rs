@@ -126,6 +126,17 @@ impl MetadataProvider for ConfigDrive {
     fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         self.fetch_publickeys()
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let filename = self
+            .drive_path
+            .join("cloudstack")
+            .join("userdata")
+            .join("user_data.txt");
+        crate::util::read_optional(&filename)?
+            .map(crate::util::maybe_gunzip)
+            .transpose()
+    }
 }
 
 impl Drop for ConfigDrive {
@@ -8,7 +8,7 @@ use openssh_keys::PublicKey;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
-use crate::util::DhcpOption;
+use crate::util::{self, DhcpOption};
 
 #[derive(Clone, Debug)]
 pub struct CloudstackNetwork {
@@ -17,9 +17,9 @@ pub struct CloudstackNetwork {
 }
 
 impl CloudstackNetwork {
-    pub fn try_new() -> Result<CloudstackNetwork> {
+    pub fn try_new(config: &retry::ClientConfig) -> Result<CloudstackNetwork> {
         let server_base_url = CloudstackNetwork::get_server_base_url_from_dhcp()?;
-        let client = retry::Client::try_new()?.return_on_404(true);
+        let client = retry::Client::try_from_config(config)?.return_on_404(true);
 
         Ok(CloudstackNetwork {
             server_base_url,
@@ -94,4 +94,15 @@ impl MetadataProvider for CloudstackNetwork {
             Ok(vec![])
         }
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .get(
+                retry::Raw,
+                format!("{}/latest/user-data", self.server_base_url),
+            )
+            .send()?;
+        user_data.map(|d| util::maybe_gunzip(d.0)).transpose()
+    }
 }
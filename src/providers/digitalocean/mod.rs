@@ -64,11 +64,12 @@ pub struct DigitalOceanProvider {
     public_keys: Vec<String>,
     region: String,
     dns: Dns,
+    user_data: Option<String>,
 }
 
 impl DigitalOceanProvider {
-    pub fn try_new() -> Result<DigitalOceanProvider> {
-        let client = retry::Client::try_new()?;
+    pub fn try_new(config: &retry::ClientConfig) -> Result<DigitalOceanProvider> {
+        let client = retry::Client::try_from_config(config)?;
         let data: DigitalOceanProvider = client
             .get(
                 retry::Json,
@@ -287,4 +288,8 @@ impl MetadataProvider for DigitalOceanProvider {
     fn networks(&self) -> Result<Vec<network::Interface>> {
         self.parse_network()
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.user_data.clone().map(String::into_bytes))
+    }
 }
@@ -23,6 +23,7 @@ use openssh_keys::PublicKey;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
+use crate::util;
 
 #[cfg(test)]
 mod mock_tests;
@@ -33,8 +34,8 @@ pub struct ExoscaleProvider {
 }
 
 impl ExoscaleProvider {
-    pub fn try_new() -> Result<ExoscaleProvider> {
-        let client = retry::Client::try_new()?;
+    pub fn try_new(config: &retry::ClientConfig) -> Result<ExoscaleProvider> {
+        let client = retry::Client::try_from_config(config)?;
 
         Ok(ExoscaleProvider { client })
     }
@@ -97,4 +98,17 @@ impl MetadataProvider for ExoscaleProvider {
             .map(|s| PublicKey::read_keys(s.as_bytes()))
             .unwrap_or_else(|| Ok(vec![]))?)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .clone()
+            .return_on_404(true)
+            .get(
+                retry::Raw,
+                "http://169.254.169.254/1.0/user-data".to_string(),
+            )
+            .send()?;
+        user_data.map(|d| util::maybe_gunzip(d.0)).transpose()
+    }
 }
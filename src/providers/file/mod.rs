@@ -0,0 +1,207 @@
+//! Metadata provider serving a local metadata document.
+//!
+//! This provider does not query any cloud platform. Instead, it reads a
+//! hand-written (or previously captured) document describing the instance,
+//! which is useful on bare metal, in labs and for end-to-end testing.
+//!
+//! The document path is taken from the `afterburn.metadata-file=` kernel
+//! argument if present, then from configuration, and defaults to
+//! `/etc/afterburn/metadata.yaml`.
+//! Documents with a `.json` extension are parsed as JSON, all others as YAML.
+//! The format is the one of `afterburn exp dump`, with additional
+//! optional `user_data` and `vendor_data` strings.
+
+use anyhow::{Context, Result};
+use openssh_keys::PublicKey;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::metadata::MetadataSnapshot;
+use crate::network;
+use crate::providers::MetadataProvider;
+use crate::util;
+
+/// Kernel cmdline file.
+const CMDLINE_PATH: &str = "/proc/cmdline";
+
+/// Kernel argument overriding the document path.
+const CMDLINE_METADATA_FILE_FLAG: &str = "afterburn.metadata-file";
+
+/// Default document path.
+pub const DEFAULT_METADATA_FILE: &str = "/etc/afterburn/metadata.yaml";
+
+/// Metadata document.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct MetadataDocument {
+    #[serde(flatten)]
+    pub metadata: MetadataSnapshot,
+    /// Instance user data.
+    pub user_data: Option<String>,
+    /// Instance vendor data.
+    pub vendor_data: Option<String>,
+}
+
+/// File provider.
+#[derive(Clone, Debug)]
+pub struct FileProvider {
+    document: MetadataDocument,
+}
+
+impl FileProvider {
+    /// Read the metadata document from the path given on the kernel
+    /// cmdline, or else from the configured path, if any.
+    pub fn try_new(configured_path: Option<&str>) -> Result<Self> {
+        let cmdline = util::Cmdline::read(CMDLINE_PATH)?;
+        let path = cmdline
+            .value(CMDLINE_METADATA_FILE_FLAG)
+            .filter(|path| !path.is_empty())
+            .or(configured_path)
+            .unwrap_or(DEFAULT_METADATA_FILE);
+        Self::from_path(Path::new(path))
+    }
+
+    /// Read the metadata document at the given path.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read metadata file {}", path.display()))?;
+        let document = if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            serde_json::from_str(&content).context("failed to parse JSON metadata")
+        } else {
+            serde_yaml::from_str(&content).context("failed to parse YAML metadata")
+        }
+        .with_context(|| format!("invalid metadata file {}", path.display()))?;
+        Ok(Self { document })
+    }
+}
+
+impl MetadataProvider for FileProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        self.document.metadata.attributes()
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        self.document.metadata.hostname()
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.document.metadata.ssh_keys()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.document.metadata.networks()
+    }
+
+    fn virtual_network_devices(&self) -> Result<Vec<network::VirtualNetDev>> {
+        self.document.metadata.virtual_network_devices()
+    }
+
+    fn netplan_config(&self) -> Result<Option<String>> {
+        self.document.metadata.netplan_config()
+    }
+
+    fn rd_network_kargs(&self) -> Result<Option<String>> {
+        self.document.metadata.rd_network_kargs()
+    }
+
+    fn boot_checkin(&self) -> Result<()> {
+        // nothing to check in with
+        Ok(())
+    }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.document.user_data.clone().map(String::into_bytes))
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.document.vendor_data.clone().map(String::into_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    static DOCUMENT: &str = r#"
+attributes:
+  FILE_INSTANCE_ID: lab-01
+hostname: lab-01.example.com
+ssh_keys:
+  - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9C/qb9iHvZ0VTMLsZaoVXA48akrfkJwpO5EnE3STbk core@lab
+networks:
+  - name: eth0
+    mac_address: "52:54:00:12:34:56"
+    priority: 10
+    nameservers: [192.0.2.53]
+    ip_addresses: [192.0.2.10/24]
+    routes:
+      - destination: 0.0.0.0/0
+        gateway: 192.0.2.1
+  - name: eth1
+    bond: bond0
+virtual_network_devices:
+  - name: bond0
+    kind: bond
+    mac_address: "52:54:00:12:34:57"
+user_data: |
+  #cloud-config
+  hostname: from-user-data
+"#;
+
+    #[test]
+    fn test_yaml_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.yaml");
+        fs::write(&path, DOCUMENT).unwrap();
+
+        let provider = FileProvider::from_path(&path).unwrap();
+        assert_eq!(
+            provider.attributes().unwrap(),
+            maplit::hashmap! {
+                "FILE_INSTANCE_ID".to_string() => "lab-01".to_string(),
+            }
+        );
+        assert_eq!(
+            provider.hostname().unwrap().as_deref(),
+            Some("lab-01.example.com")
+        );
+        assert_eq!(provider.ssh_keys().unwrap().len(), 1);
+
+        let networks = provider.networks().unwrap();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(
+            networks[0].sd_network_unit_name().unwrap(),
+            "10-eth0.network"
+        );
+        assert!(networks[0].config().contains("Gateway=192.0.2.1"));
+        assert_eq!(networks[1].bond.as_deref(), Some("bond0"));
+        assert!(networks[1].ip_addresses.is_empty());
+
+        let netdevs = provider.virtual_network_devices().unwrap();
+        assert_eq!(netdevs.len(), 1);
+        assert_eq!(netdevs[0].kind, network::NetDevKind::Bond);
+
+        assert_eq!(provider.netplan_config().unwrap(), None);
+        let user_data = provider.user_data().unwrap().unwrap();
+        assert!(user_data.starts_with(b"#cloud-config\n"));
+        assert_eq!(provider.vendor_data().unwrap(), None);
+        provider.boot_checkin().unwrap();
+    }
+
+    #[test]
+    fn test_json_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.json");
+        fs::write(&path, r#"{"hostname": "lab-02"}"#).unwrap();
+
+        let provider = FileProvider::from_path(&path).unwrap();
+        assert_eq!(provider.hostname().unwrap().as_deref(), Some("lab-02"));
+        assert!(provider.attributes().unwrap().is_empty());
+        assert!(provider.ssh_keys().unwrap().is_empty());
+
+        FileProvider::from_path(&dir.path().join("missing.json")).unwrap_err();
+        fs::write(&path, "hostname: not-json").unwrap();
+        FileProvider::from_path(&path).unwrap_err();
+    }
+}
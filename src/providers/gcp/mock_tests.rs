@@ -8,7 +8,7 @@ fn basic_hostname() {
     let hostname = "test-hostname";
 
     let mut server = mockito::Server::new();
-    let mut provider = gcp::GcpProvider::try_new().unwrap();
+    let mut provider = gcp::GcpProvider::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     server.mock("GET", ep).with_status(503).create();
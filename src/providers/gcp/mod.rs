@@ -21,6 +21,7 @@ use std::collections::HashMap;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
+use crate::util;
 
 #[cfg(test)]
 mod mock_tests;
@@ -33,8 +34,8 @@ pub struct GcpProvider {
 }
 
 impl GcpProvider {
-    pub fn try_new() -> Result<GcpProvider> {
-        let client = retry::Client::try_new()?
+    pub fn try_new(config: &retry::ClientConfig) -> Result<GcpProvider> {
+        let client = retry::Client::try_from_config(config)?
             .header(
                 HeaderName::from_static(HDR_METADATA_FLAVOR),
                 HeaderValue::from_static("Google"),
@@ -162,4 +163,15 @@ impl MetadataProvider for GcpProvider {
 
         Ok(out)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .get(
+                retry::Raw,
+                GcpProvider::endpoint_for("instance/attributes/user-data"),
+            )
+            .send()?;
+        user_data.map(|d| util::maybe_gunzip(d.0)).transpose()
+    }
 }
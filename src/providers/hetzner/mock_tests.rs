@@ -7,7 +7,8 @@ use super::HetznerProvider;
 
 fn setup() -> (mockito::ServerGuard, HetznerProvider) {
     let server = mockito::Server::new();
-    let mut provider = HetznerProvider::try_new().expect("create provider under test");
+    let mut provider =
+        HetznerProvider::try_new(&Default::default()).expect("create provider under test");
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
     (server, provider)
 }
@@ -147,3 +148,34 @@ fn test_pubkeys() {
     let keys = provider.ssh_keys().unwrap();
     assert_eq!(keys.len(), 2);
 }
+
+#[test]
+fn test_user_data() {
+    let endpoint = "/hetzner/v1/userdata";
+    let user_data = "#cloud-config\nhostname: some-hostname\n";
+
+    let (mut server, provider) = setup();
+
+    // Fail on internal server errors
+    server.mock("GET", endpoint).with_status(503).create();
+    provider.user_data().unwrap_err();
+
+    // Return user data on success
+    server
+        .mock("GET", endpoint)
+        .with_status(200)
+        .with_body(user_data)
+        .create();
+    assert_eq!(
+        provider.user_data().unwrap(),
+        Some(user_data.as_bytes().to_vec())
+    );
+
+    // Return `None` if response is empty
+    server
+        .mock("GET", endpoint)
+        .with_status(200)
+        .with_body("")
+        .create();
+    assert_eq!(provider.user_data().unwrap(), None);
+}
@@ -22,6 +22,7 @@ use openssh_keys::PublicKey;
 use serde::Deserialize;
 
 use crate::retry;
+use crate::util;
 
 use super::MetadataProvider;
 
@@ -29,6 +30,7 @@ use super::MetadataProvider;
 mod mock_tests;
 
 const HETZNER_METADATA_BASE_URL: &str = "http://169.254.169.254/hetzner/v1/metadata";
+const HETZNER_USERDATA_URL: &str = "http://169.254.169.254/hetzner/v1/userdata";
 
 /// Metadata provider for Hetzner Cloud
 ///
@@ -39,8 +41,8 @@ pub struct HetznerProvider {
 }
 
 impl HetznerProvider {
-    pub fn try_new() -> Result<Self> {
-        let client = retry::Client::try_new()?;
+    pub fn try_new(config: &retry::ClientConfig) -> Result<Self> {
+        let client = retry::Client::try_from_config(config)?;
         Ok(Self { client })
     }
 
@@ -88,6 +90,20 @@ impl MetadataProvider for HetznerProvider {
 
         Ok(keys)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: retry::Bytes = self
+            .client
+            .get(retry::Raw, HETZNER_USERDATA_URL.to_string())
+            .send()?
+            .unwrap_or_default();
+
+        if user_data.0.is_empty() {
+            return Ok(None);
+        }
+
+        util::maybe_gunzip(user_data.0).map(Some)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,9 +21,7 @@ use std::str;
 use tempfile::TempDir;
 
 use crate::providers::MetadataProvider;
-
-use mailparse::*;
-use serde::Deserialize;
+use slog_scope::warn;
 
 const CONFIG_DRIVE_LABEL: &str = "cidata";
 
@@ -127,34 +125,34 @@ impl IBMGen2Provider {
     }
 
     /// Find the SSH keys in the vendordata file
+    ///
+    /// Only `ssh_authorized_keys` is extracted, from the first cloud-config
+    /// part which has it, so that other (possibly non-conforming) keys
+    /// can't prevent SSH access.
     fn fetch_ssh_keys(vendordata_vec: Vec<u8>) -> Result<Vec<String>> {
         // Parse MIME format from vendor-data file
         let vendor_data_mail =
-            parse_mail(&vendordata_vec).context("failed to parse MIME vendor-data")?;
-        let mut cloud_config = String::new();
+            mailparse::parse_mail(&vendordata_vec).context("failed to parse MIME vendor-data")?;
         for section in vendor_data_mail.subparts {
-            for header in &section.headers {
-                if let "text/cloud-config" = header.get_value().as_str() {
-                    if section
-                        .get_body()
-                        .unwrap_or_default()
-                        .contains("ssh_authorized_keys")
-                    {
-                        cloud_config = section
-                            .get_body()
-                            .context("failed to get cloud-config content")?;
-                        break;
-                    }
+            if section.ctype.mimetype != "text/cloud-config" {
+                continue;
+            }
+            let body = section
+                .get_body()
+                .context("failed to get cloud-config content")?;
+            let cloud_config: serde_yaml::Value = match serde_yaml::from_str(&body) {
+                Ok(cloud_config) => cloud_config,
+                Err(e) => {
+                    warn!("ignoring invalid cloud-config vendor-data part: {}", e);
+                    continue;
                 }
+            };
+            if let Some(keys) = cloud_config.get("ssh_authorized_keys") {
+                return serde_yaml::from_value(keys.clone())
+                    .context("failed to deserialize ssh_authorized_keys");
             }
         }
-        // Parse YAML to find SSH keys
-        if cloud_config.is_empty() {
-            return Ok(Vec::new());
-        }
-        let deserialized_cloud_config: VendorDataCloudConfig = serde_yaml::from_str(&cloud_config)
-            .context("failed to deserialize cloud-config content")?;
-        Ok(deserialized_cloud_config.ssh_authorized_keys)
+        Ok(Vec::new())
     }
 }
 
@@ -182,6 +180,16 @@ impl MetadataProvider for IBMGen2Provider {
 
         Ok(out)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let filename = self.metadata_dir().join("user-data");
+        crate::util::read_optional(&filename)
+    }
+
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        let filename = self.metadata_dir().join("vendor-data");
+        crate::util::read_optional(&filename)
+    }
 }
 
 impl Drop for IBMGen2Provider {
@@ -195,15 +203,6 @@ impl Drop for IBMGen2Provider {
     }
 }
 
-/// This struct represents the portion of the vendor-data file we will be deserializing.
-/// This data is in the "cloud-config" portion of the vendor-data file.
-/// The cloud-config can have fields not defined here, they will be ignored.
-/// The vendor-data file is in MIME format, the cloud-config data is in YAML format.
-#[derive(Debug, Deserialize, Clone)]
-struct VendorDataCloudConfig {
-    ssh_authorized_keys: Vec<String>,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -255,4 +254,35 @@ foo:      ba:r
             .iter()
             .any(|i| i == "ssh-rsa AAAAB4NzaC2yc3 <<snip>> 3TIX+eesnqasq9w== testuser2@test.com"));
     }
+
+    #[test]
+    fn test_fetch_ssh_keys_lenient() {
+        // Unrelated keys of unexpected types, and keys in later parts, are
+        // ignored.
+        let vendordata = r#"Content-Type: multipart/mixed; boundary="BOUNDARY"
+MIME-Version: 1.0
+
+--BOUNDARY
+Content-Type: text/cloud-config
+
+#cloud-config
+write_files:
+- path: /etc/motd
+  content: 42
+  permissions: 0644
+ssh_authorized_keys:
+- ssh-ed25519 AAAA first@test.com
+
+--BOUNDARY
+Content-Type: text/cloud-config
+
+#cloud-config
+ssh_authorized_keys:
+- ssh-ed25519 AAAA second@test.com
+
+--BOUNDARY--
+"#;
+        let ssh_keys = IBMGen2Provider::fetch_ssh_keys(vendordata.into()).unwrap();
+        assert_eq!(ssh_keys, vec!["ssh-ed25519 AAAA first@test.com"]);
+    }
 }
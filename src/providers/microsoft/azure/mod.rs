@@ -104,8 +104,8 @@ impl Azure {
     /// Try to build a new provider agent for Azure.
     ///
     /// This internally tries to reach the WireServer and verify compatibility.
-    pub fn try_new() -> Result<Self> {
-        Self::with_client(None)
+    pub fn try_new(config: &retry::ClientConfig) -> Result<Self> {
+        Self::with_client(Some(retry::Client::try_from_config(config)?))
     }
 
     /// Try to build a new provider agent for Azure, with a given client.
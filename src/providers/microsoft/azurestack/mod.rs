@@ -105,8 +105,8 @@ impl AzureStack {
     /// Try to build a new provider agent for AzureStack.
     ///
     /// This internally tries to reach the WireServer and verify compatibility.
-    pub fn try_new() -> Result<Self> {
-        Self::with_client(None)
+    pub fn try_new(config: &retry::ClientConfig) -> Result<Self> {
+        Self::with_client(Some(retry::Client::try_from_config(config)?))
     }
 
     /// Try to build a new provider agent for AzureStack, with a given client.
@@ -25,9 +25,11 @@
 
 pub mod aliyun;
 pub mod aws;
+pub mod chain;
 pub mod cloudstack;
 pub mod digitalocean;
 pub mod exoscale;
+pub mod file;
 pub mod gcp;
 pub mod hetzner;
 pub mod ibmcloud;
@@ -41,21 +43,72 @@ pub mod scaleway;
 pub mod vmware;
 pub mod vultr;
 
+use crate::journal::{self, Event};
 use crate::network;
 use anyhow::{anyhow, Context, Result};
-use libsystemd::logging;
 use nix::unistd;
 use openssh_keys::PublicKey;
 use slog_scope::warn;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use uzers::{self, User};
 
-/// Message ID markers for authorized-keys entries in journal.
-const AFTERBURN_SSH_AUTHORIZED_KEYS_ADDED_MESSAGEID: &str = "0f7d7a502f2d433caa1323440a6b4190";
-const AFTERBURN_SSH_AUTHORIZED_KEYS_REMOVED_MESSAGEID: &str = "f8b91c53f5544868a3a10d0dcf68e9ea";
+/// Default name of the netplan config, within the netplan config directory.
+pub(crate) const NETPLAN_CONFIG_FILE: &str = "50-afterburn.yaml";
+
+/// Default name of the SSH authorized keys fragment.
+pub(crate) const SSH_KEYS_FRAGMENT: &str = "afterburn";
+
+/// A change to a file on disk, planned by an action.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileUpdate {
+    /// Path of the target file.
+    pub path: PathBuf,
+    /// New file content, or `None` if the file is to be removed.
+    pub content: Option<String>,
+}
+
+impl FileUpdate {
+    fn write(path: impl Into<PathBuf>, content: String) -> Self {
+        Self {
+            path: path.into(),
+            content: Some(content),
+        }
+    }
+
+    fn remove(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            content: None,
+        }
+    }
+
+    /// Return a unified diff from the current file content to this update,
+    /// or `None` if the file is already up to date.
+    pub fn diff(&self) -> Result<Option<String>> {
+        let current = match fs::read_to_string(&self.path) {
+            Ok(c) => Some(c),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read file {:?}", self.path))
+            }
+        };
+        if current == self.content {
+            return Ok(None);
+        }
+
+        let old = current.unwrap_or_default();
+        let new = self.content.clone().unwrap_or_default();
+        let path = self.path.display().to_string();
+        let diff = similar::TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(&path, &path)
+            .to_string();
+        Ok(Some(diff))
+    }
+}
 
 fn create_file(filename: &str) -> Result<File> {
     let file_path = Path::new(&filename);
@@ -71,37 +124,53 @@ fn create_file(filename: &str) -> Result<File> {
 /// Add a message to the journal logging SSH key additions; this
 /// will be used by at least Fedora CoreOS to display in the console
 /// if no ssh keys are present.
-fn write_ssh_key_journal_entry(log: logging::Priority, name: &str, path: &str, added: bool) {
+fn write_ssh_key_journal_entry(name: &str, path: &str, keys: &[PublicKey], added: bool) {
     let message = format!(
         "{} ssh authorized keys file for user: {}",
         if added { "wrote" } else { "removed" },
         name
     );
-    let map = maplit::hashmap! {
-        "AFTERBURN_USER_NAME" => name.as_ref(),
-        "AFTERBURN_PATH" => path.as_ref(),
-        "MESSAGE_ID" => match added {
-            true => AFTERBURN_SSH_AUTHORIZED_KEYS_ADDED_MESSAGEID,
-            false => AFTERBURN_SSH_AUTHORIZED_KEYS_REMOVED_MESSAGEID,
-        },
+    let event = match added {
+        true => Event::SshKeysWritten,
+        false => Event::SshKeysRemoved,
     };
-    if let Err(e) = logging::journal_send(log, &message, map.iter()) {
-        warn!("failed to send information to journald: {}", e);
-    }
+    let key_fields = journal::ssh_key_fields(keys);
+    let mut fields = vec![("AFTERBURN_USER_NAME", name), ("AFTERBURN_PATH", path)];
+    fields.extend(key_fields.iter().map(|(k, v)| (*k, v.as_str())));
+    journal::send(event, &message, &fields);
 }
 
-fn write_ssh_keys(user: User, ssh_keys: Vec<PublicKey>) -> Result<()> {
-    use std::io::ErrorKind::NotFound;
+/// Return the path of the authorized keys fragment with the given name, for
+/// the given user.
+fn ssh_keys_fragment_path(user: &User, fragment: &str) -> PathBuf {
     use uzers::os::unix::UserExt;
 
-    // switch users
-    let _guard = uzers::switch::switch_user_group(user.uid(), user.primary_group_id())
-        .context("failed to switch user/group")?;
+    user.home_dir()
+        .join(".ssh")
+        .join("authorized_keys.d")
+        .join(fragment)
+}
+
+fn write_ssh_keys(user: User, fragment: &str, ssh_keys: Vec<PublicKey>) -> Result<()> {
+    use std::io::ErrorKind::NotFound;
+
+    // switch users, unless populating a root directory as an unprivileged
+    // user (e.g. an image tree in CI)
+    let _guard = if crate::util::root().is_some() && !unistd::geteuid().is_root() {
+        None
+    } else {
+        let guard = uzers::switch::switch_user_group(user.uid(), user.primary_group_id())
+            .context("failed to switch user/group")?;
+        Some(guard)
+    };
 
     // get paths
-    let dir_path = user.home_dir().join(".ssh").join("authorized_keys.d");
-    let file_name = "afterburn";
-    let file_path = &dir_path.join(file_name);
+    let file_path = &ssh_keys_fragment_path(&user, fragment);
+    let dir_path = file_path
+        .parent()
+        .ok_or_else(|| anyhow!("could not get parent directory of {:?}", file_path))?
+        .to_path_buf();
+    let file_name = fragment;
 
     // stringify for logging
     let username = user.name().to_string_lossy();
@@ -119,7 +188,7 @@ fn write_ssh_keys(user: User, ssh_keys: Vec<PublicKey>) -> Result<()> {
             .context("failed to create temporary file")?;
 
         // write out keys
-        for key in ssh_keys {
+        for key in &ssh_keys {
             writeln!(temp_file, "{key}").with_context(|| {
                 format!("failed to write to file {:?}", temp_file.path().display())
             })?;
@@ -142,7 +211,7 @@ fn write_ssh_keys(user: User, ssh_keys: Vec<PublicKey>) -> Result<()> {
             .with_context(|| format!("failed to persist file {:?}", file_path.display()))?;
 
         // emit journal entry
-        write_ssh_key_journal_entry(logging::Priority::Info, &username, &file_path_str, true);
+        write_ssh_key_journal_entry(&username, &file_path_str, &ssh_keys, true);
     } else {
         // delete the file
         let deleted = match fs::remove_file(file_path) {
@@ -154,7 +223,7 @@ fn write_ssh_keys(user: User, ssh_keys: Vec<PublicKey>) -> Result<()> {
 
         // emit journal entry
         if deleted {
-            write_ssh_key_journal_entry(logging::Priority::Info, &username, &file_path_str, false);
+            write_ssh_key_journal_entry(&username, &file_path_str, &[], false);
         }
     }
 
@@ -224,27 +293,51 @@ pub trait MetadataProvider {
         Ok(None)
     }
 
-    fn write_attributes(&self, attributes_file_path: String) -> Result<()> {
-        let mut attributes_file = create_file(&attributes_file_path)?;
-        for (k, v) in self.attributes()? {
-            writeln!(&mut attributes_file, "AFTERBURN_{k}={v}").with_context(|| {
-                format!("failed to write attributes to file {attributes_file:?}")
-            })?;
-        }
-        Ok(())
+    /// Return the instance user data, if any.
+    ///
+    /// User data is returned as raw bytes, after undoing any transport
+    /// encoding or compression applied by the platform.
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        warn!("user-data requested, but not supported on this platform");
+        Ok(None)
     }
 
-    fn write_ssh_keys(&self, ssh_keys_user: String) -> Result<()> {
-        let ssh_keys = self.ssh_keys()?;
-        let user = uzers::get_user_by_name(&ssh_keys_user)
-            .ok_or_else(|| anyhow!("could not find user with username {:?}", ssh_keys_user))?;
+    /// Return the instance vendor data, if any.
+    ///
+    /// Like user data, vendor data is returned as raw bytes.
+    fn vendor_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
 
-        write_ssh_keys(user, ssh_keys)?;
+    /// Plan the attributes file update.
+    fn plan_attributes(&self, attributes_file_path: &str) -> Result<FileUpdate> {
+        let attributes: BTreeMap<_, _> = self.attributes()?.into_iter().collect();
+        let content = attributes
+            .into_iter()
+            .map(|(k, v)| format!("AFTERBURN_{k}={v}\n"))
+            .collect();
+        Ok(FileUpdate::write(attributes_file_path, content))
+    }
 
-        Ok(())
+    /// Plan the update of the named SSH authorized keys fragment for the
+    /// given user.
+    ///
+    /// The fragment is removed if there are no keys.
+    fn plan_ssh_keys(&self, ssh_keys_user: &str, fragment: &str) -> Result<FileUpdate> {
+        let ssh_keys = self.ssh_keys()?;
+        let user = crate::util::get_user_by_name(ssh_keys_user)?;
+        let path = ssh_keys_fragment_path(&user, fragment);
+        let update = if ssh_keys.is_empty() {
+            FileUpdate::remove(path)
+        } else {
+            let content = ssh_keys.iter().map(|k| format!("{k}\n")).collect();
+            FileUpdate::write(path, content)
+        };
+        Ok(update)
     }
 
-    fn write_hostname(&self, hostname_file_path: String) -> Result<()> {
+    /// Plan the hostname file update, if a hostname is available.
+    fn plan_hostname(&self, hostname_file_path: &str) -> Result<Option<FileUpdate>> {
         if let Some(mut hostname) = self.hostname()? {
             if let Some(maxlen) = max_hostname_len()? {
                 if hostname.len() > maxlen {
@@ -263,57 +356,223 @@ pub trait MetadataProvider {
                     }
                 }
             }
+            return Ok(Some(FileUpdate::write(
+                hostname_file_path,
+                format!("{hostname}\n"),
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Plan the network units updates, for both `.network` and `.netdev` fragments.
+    fn plan_network_units(&self, network_units_dir: &str) -> Result<Vec<FileUpdate>> {
+        let dir_path = Path::new(network_units_dir);
+        let mut updates = vec![];
+
+        // `.network` fragments for network interfaces/links.
+        for interface in &self.networks()? {
+            let unit_name = interface.sd_network_unit_name()?;
+            updates.push(FileUpdate::write(
+                dir_path.join(unit_name),
+                interface.config(),
+            ));
+        }
+
+        // `.netdev` fragments for virtual network devices.
+        for device in &self.virtual_network_devices()? {
+            updates.push(FileUpdate::write(
+                dir_path.join(device.netdev_unit_name()),
+                device.sd_netdev_config(),
+            ));
+        }
+        Ok(updates)
+    }
+
+    /// Plan the update of the named netplan config, if a config is available.
+    fn plan_netplan_config(
+        &self,
+        netplan_config_dir: &str,
+        file_name: &str,
+    ) -> Result<Option<FileUpdate>> {
+        // A single afterburn `.yaml` netplan config.
+        let update = self.netplan_config()?.map(|netplan_config| {
+            FileUpdate::write(
+                Path::new(netplan_config_dir).join(file_name),
+                netplan_config,
+            )
+        });
+        Ok(update)
+    }
+
+    /// Write the attributes file, returning the paths of written files.
+    fn write_attributes(&self, attributes_file_path: String) -> Result<Vec<PathBuf>> {
+        let update = self.plan_attributes(&attributes_file_path)?;
+        let mut attributes_file = create_file(&attributes_file_path)?;
+        write!(
+            &mut attributes_file,
+            "{}",
+            update.content.unwrap_or_default()
+        )
+        .with_context(|| format!("failed to write attributes to file {attributes_file:?}"))?;
+        journal::send(
+            Event::AttributesWritten,
+            &format!("wrote metadata attributes to {attributes_file_path}"),
+            &[("AFTERBURN_PATH", &attributes_file_path)],
+        );
+        Ok(vec![attributes_file_path.into()])
+    }
 
+    /// Write the named SSH authorized keys fragment for the given user,
+    /// returning the paths of written (or removed) files.
+    fn write_ssh_keys(&self, ssh_keys_user: String, fragment: &str) -> Result<Vec<PathBuf>> {
+        let ssh_keys = self.ssh_keys()?;
+        let user = crate::util::get_user_by_name(&ssh_keys_user)?;
+        let path = ssh_keys_fragment_path(&user, fragment);
+
+        write_ssh_keys(user, fragment, ssh_keys)?;
+
+        Ok(vec![path])
+    }
+
+    /// Write the hostname file, if a hostname is available, returning the
+    /// paths of written files.
+    fn write_hostname(&self, hostname_file_path: String) -> Result<Vec<PathBuf>> {
+        if let Some(update) = self.plan_hostname(&hostname_file_path)? {
+            let hostname = update.content.unwrap_or_default();
             let mut hostname_file = create_file(&hostname_file_path)?;
-            writeln!(&mut hostname_file, "{hostname}").with_context(|| {
+            write!(&mut hostname_file, "{hostname}").with_context(|| {
                 format!("failed to write hostname {hostname:?} to file {hostname_file:?}")
             })?;
-            slog_scope::info!("wrote hostname {} to {}", hostname, hostname_file_path);
+            slog_scope::info!(
+                "wrote hostname {} to {}",
+                hostname.trim_end(),
+                hostname_file_path
+            );
+            journal::send(
+                Event::HostnameWritten,
+                &format!(
+                    "wrote hostname {} to {hostname_file_path}",
+                    hostname.trim_end()
+                ),
+                &[
+                    ("AFTERBURN_HOSTNAME", hostname.trim_end()),
+                    ("AFTERBURN_PATH", &hostname_file_path),
+                ],
+            );
+            return Ok(vec![hostname_file_path.into()]);
         }
-        Ok(())
+        Ok(vec![])
     }
 
-    fn write_network_units(&self, network_units_dir: String) -> Result<()> {
+    /// Write network units, returning the paths of written files.
+    fn write_network_units(&self, network_units_dir: String) -> Result<Vec<PathBuf>> {
         let dir_path = Path::new(&network_units_dir);
         fs::create_dir_all(dir_path)
             .with_context(|| format!("failed to create directory {dir_path:?}"))?;
 
-        // Write `.network` fragments for network interfaces/links.
-        for interface in &self.networks()? {
-            let unit_name = interface.sd_network_unit_name()?;
-            let file_path = dir_path.join(unit_name);
-            let mut unit_file = File::create(&file_path)
-                .with_context(|| format!("failed to create file {file_path:?}"))?;
-            write!(&mut unit_file, "{}", interface.config()).with_context(|| {
-                format!("failed to write network interface unit file {unit_file:?}")
-            })?;
-        }
-
-        // Write `.netdev` fragments for virtual network devices.
-        for device in &self.virtual_network_devices()? {
-            let file_path = dir_path.join(device.netdev_unit_name());
+        let mut written = vec![];
+        for update in self.plan_network_units(&network_units_dir)? {
+            let file_path = update.path;
             let mut unit_file = File::create(&file_path)
-                .with_context(|| format!("failed to create netdev unit file {file_path:?}"))?;
-            write!(&mut unit_file, "{}", device.sd_netdev_config())
-                .with_context(|| format!("failed to write netdev unit file {unit_file:?}"))?;
+                .with_context(|| format!("failed to create unit file {file_path:?}"))?;
+            write!(&mut unit_file, "{}", update.content.unwrap_or_default())
+                .with_context(|| format!("failed to write unit file {unit_file:?}"))?;
+
+            let (event, kind) = match file_path.extension() {
+                Some(ext) if ext == "netdev" => (Event::NetdevUnitWritten, "netdev"),
+                _ => (Event::NetworkUnitWritten, "network"),
+            };
+            let path = file_path.to_string_lossy();
+            journal::send(
+                event,
+                &format!("wrote {kind} unit {path}"),
+                &[("AFTERBURN_PATH", &path)],
+            );
+            written.push(file_path);
         }
-        Ok(())
+        Ok(written)
     }
 
-    fn write_netplan_config(&self, netplan_config_dir: String) -> Result<()> {
+    /// Write the named netplan config, if available, returning the paths of
+    /// written files.
+    fn write_netplan_config(
+        &self,
+        netplan_config_dir: String,
+        file_name: &str,
+    ) -> Result<Vec<PathBuf>> {
         let dir_path = Path::new(&netplan_config_dir);
         fs::create_dir_all(dir_path)
             .with_context(|| format!("failed to create directory {dir_path:?}"))?;
 
-        // Write a single afterburn `.yaml` netplan config.
-        if let Some(netplan_config) = &self.netplan_config()? {
-            let file_path = dir_path.join("50-afterburn.yaml");
+        if let Some(update) = self.plan_netplan_config(&netplan_config_dir, file_name)? {
+            let file_path = update.path;
             let mut config_file = File::create(&file_path)
                 .with_context(|| format!("failed to create file {file_path:?}"))?;
-            write!(&mut config_file, "{netplan_config}")
+            write!(&mut config_file, "{}", update.content.unwrap_or_default())
                 .with_context(|| format!("failed to write netplan config file {config_file:?}"))?;
+
+            let path = file_path.to_string_lossy();
+            journal::send(
+                Event::NetplanConfigWritten,
+                &format!("wrote netplan config {path}"),
+                &[("AFTERBURN_PATH", &path)],
+            );
+            return Ok(vec![file_path]);
         }
-        Ok(())
+        Ok(vec![])
+    }
+
+    /// Write the user data, if available, returning the paths of written
+    /// files.
+    ///
+    /// The file is only readable by its owner, as user data frequently
+    /// contains secrets.
+    fn write_user_data(&self, user_data_path: String) -> Result<Vec<PathBuf>> {
+        let user_data = match self.user_data()? {
+            Some(user_data) => user_data,
+            None => {
+                slog_scope::info!("no user data available");
+                return Ok(vec![]);
+            }
+        };
+
+        let file_path = Path::new(&user_data_path);
+        let dir_path = file_path
+            .parent()
+            .ok_or_else(|| anyhow!("could not get parent directory of {:?}", file_path))?;
+        fs::create_dir_all(dir_path)
+            .with_context(|| format!("failed to create directory {dir_path:?}"))?;
+
+        // temporary files are created with mode 0600
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(".user-data-")
+            .tempfile_in(dir_path)
+            .context("failed to create temporary file")?;
+        temp_file
+            .write_all(&user_data)
+            .with_context(|| format!("failed to write to file {:?}", temp_file.path()))?;
+        temp_file
+            .as_file()
+            .sync_all()
+            .with_context(|| format!("failed to sync file {:?}", temp_file.path()))?;
+        temp_file
+            .persist(file_path)
+            .map_err(|e| {
+                e.file.close().ok();
+                e.error
+            })
+            .with_context(|| format!("failed to persist file {file_path:?}"))?;
+
+        let size = user_data.len().to_string();
+        journal::send(
+            Event::UserDataWritten,
+            &format!("wrote {size} bytes of user data to {user_data_path}"),
+            &[
+                ("AFTERBURN_PATH", &user_data_path),
+                ("AFTERBURN_SIZE", &size),
+            ],
+        );
+        Ok(vec![file_path.to_path_buf()])
     }
 }
 
@@ -342,6 +601,65 @@ mod tests {
         ret.trim_end().into()
     }
 
+    struct UserDataMock(Vec<u8>);
+
+    impl MetadataProvider for UserDataMock {
+        fn user_data(&self) -> Result<Option<Vec<u8>>> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    #[test]
+    fn test_write_user_data() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("afterburn/user-data");
+        let provider = UserDataMock(vec![0x00, 0xff, b'\n']);
+        let written = provider
+            .write_user_data(path.to_str().unwrap().into())
+            .unwrap();
+        assert_eq!(written, vec![path.clone()]);
+        assert_eq!(fs::read(&path).unwrap(), vec![0x00, 0xff, b'\n']);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_plan_hostname() {
+        let provider = HostnameMock("hostname7".into());
+        let update = provider.plan_hostname("/etc/hostname").unwrap().unwrap();
+        assert_eq!(update.path, Path::new("/etc/hostname"));
+        assert_eq!(update.content.as_deref(), Some("hostname7\n"));
+    }
+
+    #[test]
+    fn test_file_update_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hostname");
+
+        // Missing file.
+        let update = FileUpdate::write(&path, "foo\n".to_string());
+        let diff = update.diff().unwrap().unwrap();
+        assert!(diff.contains("+foo\n"), "{diff}");
+        FileUpdate::remove(&path)
+            .diff()
+            .unwrap()
+            .ok_or(())
+            .unwrap_err();
+
+        // Existing file.
+        fs::write(&path, "foo\n").unwrap();
+        update.diff().unwrap().ok_or(()).unwrap_err();
+        let diff = FileUpdate::write(&path, "bar\n".to_string())
+            .diff()
+            .unwrap()
+            .unwrap();
+        assert!(diff.contains("-foo\n+bar\n"), "{diff}");
+        let diff = FileUpdate::remove(&path).diff().unwrap().unwrap();
+        assert!(diff.contains("-foo\n"), "{diff}");
+    }
+
     #[test]
     fn test_hostname_truncation() {
         // assume some maximum exists
@@ -175,6 +175,13 @@ impl MetadataProvider for OpenstackConfigDrive {
         self.fetch_publickeys()
     }
 
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let filename = self.metadata_dir("openstack").join("user_data");
+        crate::util::read_optional(&filename)?
+            .map(crate::util::maybe_gunzip)
+            .transpose()
+    }
+
     fn networks(&self) -> Result<Vec<network::Interface>> {
         Ok(vec![])
     }
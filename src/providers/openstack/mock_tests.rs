@@ -5,7 +5,7 @@ use mockito;
 #[test]
 fn test_ssh_keys() {
     let mut server = mockito::Server::new();
-    let mut provider = OpenstackProviderNetwork::try_new().unwrap();
+    let mut provider = OpenstackProviderNetwork::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     let key1 = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQCsXe6CfHl45kCIzMF92VhDf2NpBWUyS1+IiTtxm5a83mT9730Hb8xim7GYeJu47kiESw2DAN8vNJ/Irg0apZ217ah2rXXjPQuWYSXuEuap8yLBSjqw8exgqVj/kzW+YqmnHASxI13eoFDxTQQGzyqbqowvxu/5gQmDwBmNAa9bT809ziB/qmpS1mD6qyyFDpR23kUwu3TkgAbwMXBDoqK+pdwfaF9uo9XaLHNEH8lD5BZuG2BeDafm2o76DhNSo83MvcCPNXKLxu3BbX/FCMFO6O8RRqony4i91fEV1b8TbXrbJz1bwEYEnJRvmjnqI/389tQFeYvplXR2WdT9PCKyEAG+j8y6XgecIcdTqV/7gFfak1mp2S7mYHZDnXixsn3MjCP/cIxxJVDitKusnj1TdFqtSXl4tqGccbg/5Sqnt/EVSK4bGwwBxv/YmE0P9cbXLxuEVI0JYzgrQvC8TtUgd8kUu2jqi1/Yj9IWm3aFsl/hhh8YwYrv/gm8PV0TxkM= root@example1";
@@ -39,7 +39,7 @@ fn test_ssh_keys() {
 #[test]
 fn test_ssh_keys_404_ok() {
     let mut server = mockito::Server::new();
-    let mut provider = OpenstackProviderNetwork::try_new().unwrap();
+    let mut provider = OpenstackProviderNetwork::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     server
@@ -55,7 +55,7 @@ fn test_ssh_keys_404_ok() {
 #[test]
 fn test_instance_uuid() {
     let mut server = mockito::Server::new();
-    let mut provider = OpenstackProviderNetwork::try_new().unwrap();
+    let mut provider = OpenstackProviderNetwork::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     server
@@ -87,7 +87,7 @@ fn test_instance_uuid() {
 #[test]
 fn test_instance_uuid_404_ok() {
     let mut server = mockito::Server::new();
-    let mut provider = OpenstackProviderNetwork::try_new().unwrap();
+    let mut provider = OpenstackProviderNetwork::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     server
@@ -15,6 +15,7 @@
 //! openstack metadata fetcher
 
 use crate::providers;
+use crate::retry;
 use anyhow::Result;
 use configdrive::OpenstackConfigDrive;
 use network::OpenstackProviderNetwork;
@@ -29,11 +30,13 @@ mod mock_tests;
 /// Read metadata from the config-drive first then fallback to fetch from metadata server.
 ///
 /// Reference: https://github.com/coreos/fedora-coreos-tracker/issues/422
-pub fn try_config_drive_else_network() -> Result<Box<dyn providers::MetadataProvider>> {
+pub fn try_config_drive_else_network(
+    config: &retry::ClientConfig,
+) -> Result<Box<dyn providers::MetadataProvider>> {
     if let Ok(config_drive) = OpenstackConfigDrive::try_new() {
         Ok(Box::new(config_drive))
     } else {
         warn!("failed to locate config-drive, using the metadata service API instead");
-        Ok(Box::new(OpenstackProviderNetwork::try_new()?))
+        Ok(Box::new(OpenstackProviderNetwork::try_new(config)?))
     }
 }
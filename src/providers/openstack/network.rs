@@ -8,9 +8,11 @@ use serde::Deserialize;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
+use crate::util;
 
 const EC2_URL: &str = "http://169.254.169.254/latest/meta-data";
 const NOVA_URL: &str = "http://169.254.169.254/openstack/2012-08-10/meta_data.json";
+const USER_DATA_URL: &str = "http://169.254.169.254/openstack/latest/user_data";
 
 /// Partial object for openstack `meta_data.json`
 #[derive(Debug, Deserialize, Default)]
@@ -25,8 +27,8 @@ pub struct OpenstackProviderNetwork {
 }
 
 impl OpenstackProviderNetwork {
-    pub fn try_new() -> Result<OpenstackProviderNetwork> {
-        let client = retry::Client::try_new()?.return_on_404(true);
+    pub fn try_new(config: &retry::ClientConfig) -> Result<OpenstackProviderNetwork> {
+        let client = retry::Client::try_from_config(config)?.return_on_404(true);
         Ok(OpenstackProviderNetwork { client })
     }
 
@@ -129,4 +131,12 @@ impl MetadataProvider for OpenstackProviderNetwork {
 
         Ok(out)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .get(retry::Raw, String::from(USER_DATA_URL))
+            .send()?;
+        user_data.map(|d| util::maybe_gunzip(d.0)).transpose()
+    }
 }
@@ -98,8 +98,8 @@ impl PacketProvider {
     /// Try to build a new provider client.
     ///
     /// This internally tries to fetch and cache the metadata content.
-    pub fn try_new() -> Result<Self> {
-        Self::fetch_content(None)
+    pub fn try_new(config: &retry::ClientConfig) -> Result<Self> {
+        Self::fetch_content(Some(retry::Client::try_from_config(config)?))
     }
 
     /// Fetch metadata content from Packet metadata endpoint.
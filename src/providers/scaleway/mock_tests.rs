@@ -39,7 +39,7 @@ fn test_attributes() {
         .with_body(metadata)
         .create();
 
-    let mut provider = ScalewayProvider::try_new().unwrap();
+    let mut provider = ScalewayProvider::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
     let got = provider.attributes().unwrap();
 
@@ -61,7 +61,7 @@ fn test_boot_checkin() {
         .with_status(200)
         .create();
 
-    let mut provider = ScalewayProvider::try_new().unwrap();
+    let mut provider = ScalewayProvider::try_new(&Default::default()).unwrap();
     provider.client = provider.client.max_retries(0).mock_base_url(server.url());
 
     provider.boot_checkin().unwrap();
@@ -78,8 +78,8 @@ pub struct ScalewayProvider {
 }
 
 impl ScalewayProvider {
-    pub fn try_new() -> Result<ScalewayProvider> {
-        let client = retry::Client::try_new()?;
+    pub fn try_new(config: &retry::ClientConfig) -> Result<ScalewayProvider> {
+        let client = retry::Client::try_from_config(config)?;
         Ok(ScalewayProvider { client })
     }
 
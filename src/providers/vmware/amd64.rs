@@ -3,16 +3,16 @@
 //! This uses the guest->host backdoor protocol for introspection.
 
 use super::VmwareProvider;
+use crate::util;
 use anyhow::{bail, Context, Result};
-use base64::{engine::general_purpose, Engine as _};
-use libflate::gzip::Decoder;
 use serde_json::json;
-use std::io::Read;
 
 /// Guestinfo key for network kargs.
 static INITRD_NET_KARGS: &str = "guestinfo.afterburn.initrd.network-kargs";
 static METADATA: &str = "guestinfo.metadata";
 static METADATA_ENCODING: &str = "guestinfo.metadata.encoding";
+static USERDATA: &str = "guestinfo.userdata";
+static USERDATA_ENCODING: &str = "guestinfo.userdata.encoding";
 
 impl VmwareProvider {
     /// Build the VMware provider, fetching and caching guestinfo entries.
@@ -49,9 +49,23 @@ impl VmwareProvider {
         let guestinfo_metadata =
             parse_metadata(guestinfo_metadata_encoding, guestinfo_metadata_raw)?;
 
+        let guestinfo_userdata = {
+            let mut erpc = vmw_backdoor::EnhancedChan::open(&mut backdoor)?;
+            Self::fetch_guestinfo(&mut erpc, USERDATA)?
+        };
+
+        let guestinfo_userdata_encoding = {
+            let mut erpc = vmw_backdoor::EnhancedChan::open(&mut backdoor)?;
+            Self::fetch_guestinfo(&mut erpc, USERDATA_ENCODING)?
+        };
+
+        // User data is only decoded when requested, so that unrelated
+        // actions don't fail on an invalid encoding.
         let provider = Self {
             guestinfo_net_kargs,
             guestinfo_metadata,
+            guestinfo_userdata,
+            guestinfo_userdata_encoding,
         };
 
         slog_scope::trace!("cached vmware provider: {:?}", provider);
@@ -86,11 +100,23 @@ impl VmwareProvider {
         }
     }
 
+    pub fn parse_user_data(&self) -> Result<Option<Vec<u8>>> {
+        decode_guestinfo(
+            self.guestinfo_userdata_encoding.as_deref(),
+            self.guestinfo_userdata.clone(),
+        )
+        .with_context(|| format!("failed to decode {USERDATA}"))?
+        .map(util::maybe_gunzip)
+        .transpose()
+    }
+
     #[cfg(test)]
     pub fn new_from_metadata(metadata: String) -> Result<Self> {
         Ok(Self {
             guestinfo_net_kargs: None,
             guestinfo_metadata: Some(metadata),
+            guestinfo_userdata: None,
+            guestinfo_userdata_encoding: None,
         })
     }
 }
@@ -99,27 +125,21 @@ fn parse_metadata(
     guestinfo_metadata_encoding: Option<String>,
     guestinfo_metadata_raw: Option<String>,
 ) -> Result<Option<String>> {
-    match (
+    let decoded = decode_guestinfo(
         guestinfo_metadata_encoding.as_deref(),
         guestinfo_metadata_raw,
-    ) {
-        (Some("base64" | "b64"), Some(guestinfo_metadata_raw_val)) => {
-            let decoded =
-                general_purpose::STANDARD.decode(guestinfo_metadata_raw_val.as_bytes())?;
-            Ok(Some(String::from_utf8(decoded)?))
-        }
-        (Some("gzip+base64" | "gz+b64"), Some(guestinfo_metadata_raw_val)) => {
-            let decoded =
-                general_purpose::STANDARD.decode(guestinfo_metadata_raw_val.as_bytes())?;
-            let mut decompressor = Decoder::new(decoded.as_slice())?;
-            let mut uncompressed = Vec::new();
-            decompressor.read_to_end(&mut uncompressed)?;
-            Ok(Some(String::from_utf8(uncompressed)?))
-        }
-        (Some(""), guestinfo_metadata_raw) => Ok(guestinfo_metadata_raw),
-        (Some(encoding), _) => bail!("unknown guestinfo.metadata.encoding '{}'", encoding),
-        (None, guestinfo_metadata_raw) => Ok(guestinfo_metadata_raw),
-    }
+    )
+    .with_context(|| format!("failed to decode {METADATA}"))?;
+    decoded
+        .map(String::from_utf8)
+        .transpose()
+        .with_context(|| format!("invalid UTF-8 in {METADATA}"))
+}
+
+/// Decode a guestinfo value according to its companion `.encoding` value.
+fn decode_guestinfo(encoding: Option<&str>, raw: Option<String>) -> Result<Option<Vec<u8>>> {
+    raw.map(|raw| util::decode(encoding.unwrap_or_default(), raw.as_bytes()))
+        .transpose()
 }
 
 #[test]
@@ -160,6 +180,32 @@ fn test_netplan_dhcp() {
     assert_eq!(netplan_config, metadata);
 }
 
+#[test]
+fn test_invalid_userdata() {
+    let metadata = "network:\n  version: 2\n";
+    let provider = VmwareProvider {
+        guestinfo_userdata: Some("not base64!".to_owned()),
+        guestinfo_userdata_encoding: Some("base64".to_owned()),
+        ..VmwareProvider::new_from_metadata(metadata.to_owned()).unwrap()
+    };
+    // Only user data is affected.
+    assert_eq!(provider.parse_netplan_config().unwrap().unwrap(), metadata);
+    provider.parse_user_data().unwrap_err();
+
+    let provider = VmwareProvider {
+        guestinfo_userdata: Some("aGVsbG8=".to_owned()),
+        guestinfo_userdata_encoding: Some("unknown".to_owned()),
+        ..provider
+    };
+    provider.parse_user_data().unwrap_err();
+
+    let provider = VmwareProvider {
+        guestinfo_userdata_encoding: Some("base64".to_owned()),
+        ..provider
+    };
+    assert_eq!(provider.parse_user_data().unwrap().unwrap(), b"hello");
+}
+
 #[test]
 fn test_metadata_plain_1() {
     let guestinfo_metadata_raw = Some("hello".to_owned());
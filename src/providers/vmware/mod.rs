@@ -13,6 +13,10 @@ pub struct VmwareProvider {
     guestinfo_net_kargs: Option<String>,
     /// Cloud-Init metadata for netplan YAML
     guestinfo_metadata: Option<String>,
+    /// Raw user data, decoded on demand.
+    guestinfo_userdata: Option<String>,
+    /// Encoding of the raw user data.
+    guestinfo_userdata_encoding: Option<String>,
 }
 
 // Architecture-specific implementation.
@@ -36,4 +40,8 @@ impl MetadataProvider for VmwareProvider {
     fn netplan_config(&self) -> Result<Option<String>> {
         self.parse_netplan_config()
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        self.parse_user_data()
+    }
 }
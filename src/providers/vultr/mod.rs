@@ -23,6 +23,7 @@ use std::collections::HashMap;
 
 use crate::providers::MetadataProvider;
 use crate::retry;
+use crate::util;
 
 #[cfg(test)]
 mod mock_tests;
@@ -33,8 +34,8 @@ pub struct VultrProvider {
 }
 
 impl VultrProvider {
-    pub fn try_new() -> Result<VultrProvider> {
-        let client = retry::Client::try_new()?.return_on_404(true);
+    pub fn try_new(config: &retry::ClientConfig) -> Result<VultrProvider> {
+        let client = retry::Client::try_from_config(config)?.return_on_404(true);
 
         Ok(VultrProvider { client })
     }
@@ -121,4 +122,18 @@ impl MetadataProvider for VultrProvider {
 
         Ok(out)
     }
+
+    fn user_data(&self) -> Result<Option<Vec<u8>>> {
+        let user_data: Option<retry::Bytes> = self
+            .client
+            .get(
+                retry::Raw,
+                "http://169.254.169.254/latest/user-data".to_string(),
+            )
+            .send()?;
+        user_data
+            .filter(|d| !d.0.is_empty())
+            .map(|d| util::maybe_gunzip(d.0))
+            .transpose()
+    }
 }
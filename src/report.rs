@@ -0,0 +1,408 @@
+//! Machine-readable run reports.
+//!
+//! While enabled, HTTP requests and config-drive mounts performed by
+//! providers are recorded process-wide. At the end of a run, they are
+//! combined with per-action outcomes into a report, which can be written as
+//! JSON or in the Prometheus textfile-collector format.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Default path for the JSON run report.
+pub(crate) const REPORT_PATH: &str = "/run/afterburn/report.json";
+
+/// Report of a single Afterburn run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct Report {
+    /// Name of the cloud provider.
+    pub provider: String,
+    /// Whether all actions succeeded.
+    pub success: bool,
+    /// Failure cause, if the run failed.
+    pub error: Option<String>,
+    /// Start of the run, in seconds since the Unix epoch.
+    pub timestamp_secs: u64,
+    /// Duration of the whole run.
+    pub duration_secs: f64,
+    /// Outcome of each action, in execution order.
+    pub actions: Vec<ActionReport>,
+    /// HTTP requests, in execution order.
+    pub requests: Vec<RequestReport>,
+    /// Config-drives mounted.
+    pub mounts: Vec<MountReport>,
+}
+
+/// Outcome of a single action.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct ActionReport {
+    pub name: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub duration_secs: f64,
+}
+
+/// An HTTP request, including all its retries.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct RequestReport {
+    pub method: String,
+    pub url: String,
+    pub attempts: u8,
+    pub success: bool,
+    pub duration_secs: f64,
+}
+
+/// A mounted config-drive.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct MountReport {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+}
+
+/// Events recorded so far, if recording is enabled.
+static RECORDER: Mutex<Option<Recorded>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Recorded {
+    start: SystemTime,
+    requests: Vec<RequestReport>,
+    mounts: Vec<MountReport>,
+}
+
+fn with_recorder(f: impl FnOnce(&mut Recorded)) {
+    let mut recorder = RECORDER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(recorded) = recorder.as_mut() {
+        f(recorded)
+    }
+}
+
+/// Start recording events for a run report.
+pub(crate) fn start() {
+    let mut recorder = RECORDER.lock().unwrap_or_else(|e| e.into_inner());
+    *recorder = Some(Recorded {
+        start: SystemTime::now(),
+        requests: vec![],
+        mounts: vec![],
+    });
+}
+
+/// Stop recording, and return a report with all events recorded so far.
+///
+/// The returned report has no actions, and is marked as successful.
+pub(crate) fn finish(provider: &str) -> Report {
+    let recorded = RECORDER.lock().unwrap_or_else(|e| e.into_inner()).take();
+    let mut report = Report {
+        provider: provider.to_string(),
+        success: true,
+        ..Default::default()
+    };
+    if let Some(recorded) = recorded {
+        report.timestamp_secs = recorded
+            .start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        report.duration_secs = recorded.start.elapsed().unwrap_or_default().as_secs_f64();
+        report.requests = recorded.requests;
+        report.mounts = recorded.mounts;
+    }
+    report
+}
+
+/// Record an HTTP request.
+pub(crate) fn record_request(
+    method: &str,
+    url: &str,
+    attempts: u8,
+    success: bool,
+    duration: Duration,
+) {
+    with_recorder(|r| {
+        r.requests.push(RequestReport {
+            method: method.to_string(),
+            url: url.to_string(),
+            attempts,
+            success,
+            duration_secs: duration.as_secs_f64(),
+        })
+    })
+}
+
+/// Record a config-drive mount.
+pub(crate) fn record_mount(source: &Path, target: &Path, fstype: &str) {
+    with_recorder(|r| {
+        r.mounts.push(MountReport {
+            source: source.display().to_string(),
+            target: target.display().to_string(),
+            fstype: fstype.to_string(),
+        })
+    })
+}
+
+impl Report {
+    /// Render the report in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let provider = escape_label(&self.provider);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            writeln!(out, "# HELP afterburn_{name} {help}").unwrap();
+            writeln!(out, "# TYPE afterburn_{name} {kind}").unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "afterburn_{name}{{{labels}}} {value}").unwrap();
+            }
+        };
+        let run_labels = format!("provider=\"{provider}\"");
+        metric(
+            "run_success",
+            "gauge",
+            "Whether the last run succeeded.",
+            &[(run_labels.clone(), bool_value(self.success))],
+        );
+        metric(
+            "run_timestamp_seconds",
+            "gauge",
+            "Start time of the last run.",
+            &[(run_labels.clone(), self.timestamp_secs as f64)],
+        );
+        metric(
+            "run_duration_seconds",
+            "gauge",
+            "Duration of the last run.",
+            &[(run_labels, self.duration_secs)],
+        );
+
+        let action_labels = |a: &ActionReport| {
+            format!(
+                "provider=\"{provider}\",action=\"{}\"",
+                escape_label(&a.name)
+            )
+        };
+        metric(
+            "action_success",
+            "gauge",
+            "Whether the action succeeded in the last run.",
+            &self
+                .actions
+                .iter()
+                .map(|a| (action_labels(a), bool_value(a.success)))
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "action_duration_seconds",
+            "gauge",
+            "Duration of the action in the last run.",
+            &self
+                .actions
+                .iter()
+                .map(|a| (action_labels(a), a.duration_secs))
+                .collect::<Vec<_>>(),
+        );
+
+        // Full URLs would make for unbounded label values, so aggregate
+        // requests by method and host; URLs are only kept in JSON reports.
+        let mut requests: Vec<(String, RequestReport)> = vec![];
+        for r in &self.requests {
+            let labels = format!(
+                "provider=\"{provider}\",method=\"{}\",host=\"{}\"",
+                escape_label(&r.method),
+                escape_label(&request_host(&r.url))
+            );
+            match requests.iter_mut().find(|(l, _)| *l == labels) {
+                Some((_, agg)) => {
+                    agg.attempts = agg.attempts.saturating_add(r.attempts);
+                    agg.duration_secs += r.duration_secs;
+                    agg.success = r.success;
+                }
+                None => requests.push((labels, r.clone())),
+            }
+        }
+        metric(
+            "http_request_attempts",
+            "gauge",
+            "Number of attempts for the HTTP host in the last run.",
+            &requests
+                .iter()
+                .map(|(l, r)| (l.clone(), f64::from(r.attempts)))
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "http_request_success",
+            "gauge",
+            "Whether the last request to the HTTP host succeeded in the last run.",
+            &requests
+                .iter()
+                .map(|(l, r)| (l.clone(), bool_value(r.success)))
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "http_request_duration_seconds",
+            "gauge",
+            "Time spent on the HTTP host in the last run, including retries.",
+            &requests
+                .iter()
+                .map(|(l, r)| (l.clone(), r.duration_secs))
+                .collect::<Vec<_>>(),
+        );
+        out
+    }
+
+    /// Write the report as JSON to the given path.
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let mut content =
+            serde_json::to_string_pretty(self).context("failed to serialize report")?;
+        content.push('\n');
+        write_atomic(path, &content)
+    }
+
+    /// Write the report in the Prometheus format to the given path.
+    pub fn write_prometheus(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &self.to_prometheus())
+    }
+}
+
+fn bool_value(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Return the host of a request URL, or an empty string if it has none.
+fn request_host(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(ToString::to_string))
+        .unwrap_or_default()
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Atomically replace the file at the given path, so that readers (e.g.
+/// the node exporter) never observe partial content.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("could not get parent directory of {:?}", path))?;
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create directory {dir:?}"))?;
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(".afterburn-report-")
+        .tempfile_in(dir)
+        .context("failed to create temporary file")?;
+    temp_file
+        .write_all(content.as_bytes())
+        .with_context(|| format!("failed to write to file {:?}", temp_file.path()))?;
+    temp_file
+        .persist(path)
+        .map_err(|e| e.error)
+        .with_context(|| format!("failed to persist file {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_report() -> Report {
+        Report {
+            provider: "aws".to_string(),
+            success: false,
+            error: Some("1 of 2 actions failed".to_string()),
+            timestamp_secs: 1700000000,
+            duration_secs: 1.5,
+            actions: vec![
+                ActionReport {
+                    name: "attributes".to_string(),
+                    success: true,
+                    error: None,
+                    duration_secs: 0.5,
+                },
+                ActionReport {
+                    name: "ssh-keys".to_string(),
+                    success: false,
+                    error: Some("writing ssh keys".to_string()),
+                    duration_secs: 1.0,
+                },
+            ],
+            requests: vec![
+                RequestReport {
+                    method: "GET".to_string(),
+                    url: "http://169.254.169.254/latest/meta-data/instance-id".to_string(),
+                    attempts: 2,
+                    success: false,
+                    duration_secs: 0.125,
+                },
+                RequestReport {
+                    method: "GET".to_string(),
+                    url: "http://169.254.169.254/latest/meta-data/hostname".to_string(),
+                    attempts: 1,
+                    success: true,
+                    duration_secs: 0.125,
+                },
+            ],
+            mounts: vec![],
+        }
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let expected = r#"# HELP afterburn_run_success Whether the last run succeeded.
+# TYPE afterburn_run_success gauge
+afterburn_run_success{provider="aws"} 0
+# HELP afterburn_run_timestamp_seconds Start time of the last run.
+# TYPE afterburn_run_timestamp_seconds gauge
+afterburn_run_timestamp_seconds{provider="aws"} 1700000000
+# HELP afterburn_run_duration_seconds Duration of the last run.
+# TYPE afterburn_run_duration_seconds gauge
+afterburn_run_duration_seconds{provider="aws"} 1.5
+# HELP afterburn_action_success Whether the action succeeded in the last run.
+# TYPE afterburn_action_success gauge
+afterburn_action_success{provider="aws",action="attributes"} 1
+afterburn_action_success{provider="aws",action="ssh-keys"} 0
+# HELP afterburn_action_duration_seconds Duration of the action in the last run.
+# TYPE afterburn_action_duration_seconds gauge
+afterburn_action_duration_seconds{provider="aws",action="attributes"} 0.5
+afterburn_action_duration_seconds{provider="aws",action="ssh-keys"} 1
+# HELP afterburn_http_request_attempts Number of attempts for the HTTP host in the last run.
+# TYPE afterburn_http_request_attempts gauge
+afterburn_http_request_attempts{provider="aws",method="GET",host="169.254.169.254"} 3
+# HELP afterburn_http_request_success Whether the last request to the HTTP host succeeded in the last run.
+# TYPE afterburn_http_request_success gauge
+afterburn_http_request_success{provider="aws",method="GET",host="169.254.169.254"} 1
+# HELP afterburn_http_request_duration_seconds Time spent on the HTTP host in the last run, including retries.
+# TYPE afterburn_http_request_duration_seconds gauge
+afterburn_http_request_duration_seconds{provider="aws",method="GET",host="169.254.169.254"} 0.25
+"#;
+        assert_eq!(sample_report().to_prometheus(), expected);
+        assert_eq!(escape_label("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+
+    #[test]
+    fn test_write_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("afterburn/report.json");
+        let report = sample_report();
+        report.write_json(&path).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(value["provider"], "aws");
+        assert_eq!(value["actions"][1]["error"], "writing ssh keys");
+        assert_eq!(value["requests"][0]["attempts"], 2);
+        assert_eq!(
+            value["requests"][1]["url"],
+            "http://169.254.169.254/latest/meta-data/hostname"
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Capture and replay of HTTP exchanges.
+//!
+//! While capturing, every request sent through [`Client`](super::Client) is
+//! recorded along with its response, or with the transport error it failed
+//! with. Captured exchanges are saved as a tar bundle, which can be attached
+//! to bug reports. Sensitive headers are redacted, as well as response bodies
+//! of endpoints returning credentials, user data or vendor data; other
+//! response bodies are kept.
+//!
+//! While replaying, requests are served from a bundle without any network
+//! access. Exchanges are matched on method and URL, in capture order; once
+//! all exchanges for a request are consumed, the last one is served again.
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{blocking, header, StatusCode};
+use serde::{Deserialize, Serialize};
+use slog_scope::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::errors::{Error, ErrorKind};
+
+/// Index of all exchanges, within a bundle.
+const INDEX_PATH: &str = "exchanges.json";
+
+/// Directory of response bodies, within a bundle.
+const BODIES_DIR: &str = "bodies";
+
+/// Placeholder for redacted header values.
+const REDACTED: &str = "<redacted>";
+
+/// Headers which may carry credentials.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-auth-token",
+    "x-aws-ec2-metadata-token",
+];
+
+/// Endpoints whose response bodies are credentials, by method and URL path.
+///
+/// Their bodies are replaced by a placeholder, which is then replayed as is.
+const SENSITIVE_ENDPOINTS: &[(&str, &str)] = &[
+    // AWS IMDSv2 session token.
+    ("PUT", "/latest/api/token"),
+];
+
+/// Last URL path components of user and vendor data endpoints.
+///
+/// User and vendor data may embed secrets, so their bodies are redacted
+/// for any method.
+const SENSITIVE_DATA_NAMES: &[&str] = &[
+    "user-data",
+    "user_data",
+    "userdata",
+    "vendor-data",
+    "vendor_data.json",
+    "vendor_data2.json",
+];
+
+/// A recorded HTTP exchange.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    pub url: String,
+    pub request_headers: BTreeMap<String, String>,
+    pub request_body: Option<String>,
+    /// Response status, unless the request failed.
+    pub status: Option<u16>,
+    pub response_headers: BTreeMap<String, String>,
+    /// Transport error, if the request failed.
+    pub error: Option<String>,
+    /// Response body, stored as a separate bundle entry.
+    #[serde(skip)]
+    pub body: Vec<u8>,
+}
+
+/// A set of recorded HTTP exchanges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bundle {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Bundle {
+    /// Read a bundle from a tar archive.
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open bundle {}", path.display()))?;
+        Self::from_tar(file).with_context(|| format!("invalid bundle {}", path.display()))
+    }
+
+    fn from_tar(reader: impl Read) -> Result<Self> {
+        let mut index = None;
+        let mut bodies = HashMap::new();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().context("failed to read archive")? {
+            let mut entry = entry.context("failed to read archive entry")?;
+            let path = entry
+                .path()
+                .context("invalid archive entry path")?
+                .to_string_lossy()
+                .into_owned();
+            let mut content = vec![];
+            entry
+                .read_to_end(&mut content)
+                .with_context(|| format!("failed to read archive entry {path}"))?;
+            if path == INDEX_PATH {
+                let exchanges: Vec<Exchange> =
+                    serde_json::from_slice(&content).context("failed to parse exchanges")?;
+                index = Some(exchanges);
+            } else if let Some(name) = path.strip_prefix(&format!("{BODIES_DIR}/")) {
+                bodies.insert(name.to_string(), content);
+            }
+        }
+
+        let mut exchanges = index.ok_or_else(|| anyhow!("missing {}", INDEX_PATH))?;
+        for (i, exchange) in exchanges.iter_mut().enumerate() {
+            exchange.body = bodies.remove(&body_name(i)).unwrap_or_default();
+        }
+        Ok(Self { exchanges })
+    }
+
+    /// Atomically write the bundle as a tar archive.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // temporary files are created with mode 0600
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(".afterburn-capture-")
+            .tempfile_in(dir)
+            .context("failed to create temporary file")?;
+        self.to_tar(&mut temp_file)
+            .with_context(|| format!("failed to write to file {:?}", temp_file.path()))?;
+        temp_file
+            .persist(path)
+            .map_err(|e| {
+                e.file.close().ok();
+                e.error
+            })
+            .with_context(|| format!("failed to persist file {path:?}"))?;
+        Ok(())
+    }
+
+    fn to_tar(&self, writer: impl Write) -> Result<()> {
+        let mut builder = tar::Builder::new(writer);
+        let index = serde_json::to_vec_pretty(&self.exchanges)?;
+        append(&mut builder, INDEX_PATH, &index)?;
+        for (i, exchange) in self.exchanges.iter().enumerate() {
+            let name = format!("{}/{}", BODIES_DIR, body_name(i));
+            append(&mut builder, &name, &exchange.body)?;
+        }
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+fn body_name(index: usize) -> String {
+    format!("{index:04}")
+}
+
+fn append(builder: &mut tar::Builder<impl Write>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("failed to append {path}"))
+}
+
+/// Current capture or replay session, if any.
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+#[derive(Debug)]
+enum Session {
+    Capture(Bundle),
+    Replay(Replayer),
+}
+
+/// Start capturing HTTP exchanges.
+pub fn start_capture() {
+    *SESSION.lock().unwrap_or_else(|e| e.into_inner()) = Some(Session::Capture(Bundle::default()));
+}
+
+/// Stop capturing, and return all exchanges captured so far.
+pub fn finish_capture() -> Bundle {
+    let mut session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+    match session.take() {
+        Some(Session::Capture(bundle)) => bundle,
+        other => {
+            *session = other;
+            Bundle::default()
+        }
+    }
+}
+
+/// Serve all HTTP requests from the given bundle from now on.
+pub fn start_replay(bundle: Bundle) {
+    info!(
+        "replaying {} captured HTTP exchanges",
+        bundle.exchanges.len()
+    );
+    *SESSION.lock().unwrap_or_else(|e| e.into_inner()) = Some(Session::Replay(Replayer {
+        bundle,
+        served: HashMap::new(),
+    }));
+}
+
+#[derive(Debug)]
+struct Replayer {
+    bundle: Bundle,
+    /// Number of exchanges served so far, by method and URL.
+    served: HashMap<(String, String), usize>,
+}
+
+impl Replayer {
+    fn serve(&mut self, method: &str, url: &str) -> Result<Response> {
+        let candidates: Vec<&Exchange> = self
+            .bundle
+            .exchanges
+            .iter()
+            .filter(|e| e.method == method && e.url == url)
+            .collect();
+        let served = self
+            .served
+            .entry((method.to_string(), url.to_string()))
+            .or_default();
+        let exchange = match candidates.get(*served).or(candidates.last()) {
+            Some(exchange) => *exchange,
+            None => bail!(Error::new(
+                ErrorKind::NetworkUnreachable,
+                format!("no captured response for {method} {url}")
+            )),
+        };
+        *served += 1;
+
+        debug!("replaying {} {}", method, url);
+        match (exchange.status, &exchange.error) {
+            (Some(status), _) => Ok(Response {
+                status: StatusCode::from_u16(status)
+                    .with_context(|| format!("invalid captured status {status}"))?,
+                body: exchange.body.clone(),
+            }),
+            (None, error) => bail!(Error::new(
+                ErrorKind::NetworkUnreachable,
+                format!(
+                    "captured request failed: {}",
+                    error.as_deref().unwrap_or("unknown error")
+                )
+            )),
+        }
+    }
+}
+
+/// A fully-read HTTP response.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub status: StatusCode,
+    pub body: Vec<u8>,
+}
+
+/// Send a request, capturing or replaying it as configured.
+pub(crate) fn execute(client: &blocking::Client, req: blocking::Request) -> Result<Response> {
+    let mut session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+    match session.as_mut() {
+        None => {
+            // don't hold the lock during network access
+            drop(session);
+            send(client, req)
+        }
+        Some(Session::Replay(replayer)) => {
+            replayer.serve(req.method().as_str(), req.url().as_str())
+        }
+        Some(Session::Capture(_)) => {
+            drop(session);
+            let mut exchange = Exchange {
+                method: req.method().to_string(),
+                url: req.url().to_string(),
+                request_headers: redacted(req.headers()),
+                request_body: req
+                    .body()
+                    .and_then(|b| b.as_bytes())
+                    .map(|b| String::from_utf8_lossy(b).into_owned()),
+                ..Default::default()
+            };
+            let res = send_capturing(client, req, &mut exchange);
+            if let Some(Session::Capture(bundle)) =
+                SESSION.lock().unwrap_or_else(|e| e.into_inner()).as_mut()
+            {
+                bundle.exchanges.push(exchange);
+            }
+            res
+        }
+    }
+}
+
+fn send(client: &blocking::Client, req: blocking::Request) -> Result<Response> {
+    let mut resp = client.execute(req)?;
+    let mut body = vec![];
+    resp.read_to_end(&mut body)
+        .context("failed to read response body")?;
+    Ok(Response {
+        status: resp.status(),
+        body,
+    })
+}
+
+fn send_capturing(
+    client: &blocking::Client,
+    req: blocking::Request,
+    exchange: &mut Exchange,
+) -> Result<Response> {
+    let mut resp = match client.execute(req) {
+        Ok(resp) => resp,
+        Err(e) => {
+            exchange.error = Some(e.to_string());
+            return Err(e.into());
+        }
+    };
+    exchange.status = Some(resp.status().as_u16());
+    exchange.response_headers = redacted(resp.headers());
+    let mut body = vec![];
+    resp.read_to_end(&mut body)
+        .context("failed to read response body")?;
+    exchange.body = captured_body(&exchange.method, &exchange.url, &body);
+    Ok(Response {
+        status: resp.status(),
+        body,
+    })
+}
+
+/// Return the response body to record for the given request, redacting
+/// credentials and user or vendor data.
+fn captured_body(method: &str, url: &str, body: &[u8]) -> Vec<u8> {
+    let path = reqwest::Url::parse(url).map(|url| url.path().to_string());
+    let path = match path {
+        Ok(path) => path,
+        Err(_) => return body.to_vec(),
+    };
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let sensitive = SENSITIVE_ENDPOINTS
+        .iter()
+        .any(|(m, p)| *m == method && path == *p)
+        || SENSITIVE_DATA_NAMES.contains(&name);
+    if sensitive {
+        REDACTED.as_bytes().to_vec()
+    } else {
+        body.to_vec()
+    }
+}
+
+/// Convert headers to a map, redacting sensitive values.
+fn redacted(headers: &header::HeaderMap) -> BTreeMap<String, String> {
+    let mut map: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        map.entry(name.to_string())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_bundle() -> Bundle {
+        Bundle {
+            exchanges: vec![
+                Exchange {
+                    method: "GET".to_string(),
+                    url: "http://192.0.2.1/hostname".to_string(),
+                    status: Some(503),
+                    ..Default::default()
+                },
+                Exchange {
+                    method: "GET".to_string(),
+                    url: "http://192.0.2.1/hostname".to_string(),
+                    status: Some(200),
+                    body: b"mock-host".to_vec(),
+                    ..Default::default()
+                },
+                Exchange {
+                    method: "GET".to_string(),
+                    url: "http://192.0.2.1/user-data".to_string(),
+                    error: Some("connection refused".to_string()),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.tar");
+        sample_bundle().write(&path).unwrap();
+        assert_eq!(Bundle::read(&path).unwrap(), sample_bundle());
+
+        std::fs::write(&path, "not a tar").unwrap();
+        Bundle::read(&path).unwrap_err();
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut replayer = Replayer {
+            bundle: sample_bundle(),
+            served: HashMap::new(),
+        };
+        let url = "http://192.0.2.1/hostname";
+        assert_eq!(
+            replayer.serve("GET", url).unwrap().status,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Served in capture order, then the last one is repeated.
+        for _ in 0..2 {
+            let resp = replayer.serve("GET", url).unwrap();
+            assert_eq!(resp.status, StatusCode::OK);
+            assert_eq!(resp.body, b"mock-host");
+        }
+
+        let err = replayer
+            .serve("GET", "http://192.0.2.1/user-data")
+            .unwrap_err();
+        assert!(err.to_string().contains("connection refused"));
+        let err = replayer.serve("PUT", url).unwrap_err();
+        assert_eq!(crate::errors::kind_of(&err), ErrorKind::NetworkUnreachable);
+    }
+
+    #[test]
+    fn test_captured_body() {
+        let url = "http://169.254.169.254/latest/api/token";
+        assert_eq!(captured_body("PUT", url, b"secret"), REDACTED.as_bytes());
+        assert_eq!(captured_body("GET", url, b"secret"), b"secret");
+        assert_eq!(
+            captured_body(
+                "GET",
+                "http://169.254.169.254/latest/meta-data/hostname",
+                b"host"
+            ),
+            b"host"
+        );
+
+        for url in [
+            "http://169.254.169.254/2021-01-03/user-data",
+            "http://169.254.169.254/openstack/latest/user_data",
+            "http://169.254.169.254/openstack/latest/vendor_data2.json",
+            "http://169.254.169.254/hetzner/v1/userdata",
+            "http://169.254.169.254/computeMetadata/v1/instance/attributes/user-data",
+        ] {
+            assert_eq!(captured_body("GET", url, b"secret"), REDACTED.as_bytes());
+        }
+        assert_eq!(
+            captured_body("GET", "http://169.254.169.254/user-data/x", b"x"),
+            b"x"
+        );
+    }
+
+    #[test]
+    fn test_redacted() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert("X-aws-ec2-metadata-token", "secret".parse().unwrap());
+        headers.insert("Metadata-Flavor", "Google".parse().unwrap());
+        headers.append("Accept", "text/plain".parse().unwrap());
+        headers.append("Accept", "application/json".parse().unwrap());
+        assert_eq!(
+            redacted(&headers),
+            maplit::btreemap! {
+                "accept".to_string() => "text/plain, application/json".to_string(),
+                "metadata-flavor".to_string() => "Google".to_string(),
+                "x-aws-ec2-metadata-token".to_string() => REDACTED.to_string(),
+            }
+        );
+    }
+}
@@ -20,14 +20,18 @@
 //! deserializing responses and handles headers in a sane way.
 
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::io::Read;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use reqwest::{self, blocking, header, Method};
 use slog_scope::info;
 
-use crate::retry::Retry;
+use crate::errors::{self, ErrorKind};
+use crate::report;
+use crate::retry::{capture, ClientConfig, Retry};
 
 use crate::retry::raw_deserializer;
 
@@ -110,20 +114,31 @@ pub struct Client {
     headers: header::HeaderMap,
     retry: Retry,
     return_on_404: bool,
+    endpoints: BTreeMap<String, String>,
     #[cfg(test)]
     mock_base_url: Option<String>,
 }
 
 impl Client {
     pub fn try_new() -> Result<Self> {
-        let client = blocking::Client::builder()
-            .build()
-            .context("failed to initialize client")?;
+        Self::try_from_config(&ClientConfig::default())
+    }
+
+    /// Build a client with the given settings.
+    pub fn try_from_config(config: &ClientConfig) -> Result<Self> {
+        let mut builder = blocking::Client::builder();
+        // Don't let a single request outlive the deadline.
+        if let Some(deadline) = config.deadline {
+            builder = builder.timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        let client = builder.build().context("failed to initialize client")?;
+        let retry = config.retry.clone().unwrap_or_default();
         Ok(Client {
             client,
             headers: header::HeaderMap::new(),
-            retry: Retry::new(),
+            retry: retry.deadline(config.deadline),
             return_on_404: false,
+            endpoints: config.endpoints.clone(),
             #[cfg(test)]
             mock_base_url: None,
         })
@@ -179,6 +194,7 @@ impl Client {
             headers: self.headers.clone(),
             retry: self.retry.clone(),
             return_on_404: self.return_on_404,
+            endpoints: self.endpoints.clone(),
             #[cfg(test)]
             mock_base_url: self.mock_base_url.clone(),
         }
@@ -196,6 +212,7 @@ impl Client {
             headers: self.headers.clone(),
             retry: self.retry.clone(),
             return_on_404: self.return_on_404,
+            endpoints: self.endpoints.clone(),
             #[cfg(test)]
             mock_base_url: self.mock_base_url.clone(),
         }
@@ -213,6 +230,7 @@ impl Client {
             headers: self.headers.clone(),
             retry: self.retry.clone(),
             return_on_404: self.return_on_404,
+            endpoints: self.endpoints.clone(),
             #[cfg(test)]
             mock_base_url: self.mock_base_url.clone(),
         }
@@ -230,6 +248,7 @@ impl Client {
             headers: self.headers.clone(),
             retry: self.retry.clone(),
             return_on_404: self.return_on_404,
+            endpoints: self.endpoints.clone(),
             #[cfg(test)]
             mock_base_url: self.mock_base_url.clone(),
         }
@@ -247,6 +266,7 @@ where
     headers: header::HeaderMap,
     retry: Retry,
     return_on_404: bool,
+    endpoints: BTreeMap<String, String>,
     #[cfg(test)]
     mock_base_url: Option<String>,
 }
@@ -265,11 +285,11 @@ where
         T: for<'de> serde::Deserialize<'de>,
     {
         let url = self.parse_url()?;
-        let mut req = blocking::Request::new(Method::GET, url);
+        let mut req = blocking::Request::new(Method::GET, url.clone());
         req.headers_mut().extend(self.headers.clone());
 
-        self.retry.clone().retry(|attempt| {
-            info!("Fetching {}: Attempt #{}", req.url(), attempt + 1);
+        self.retry_request(Method::GET, &url, |attempt| {
+            info!("Fetching {}: Attempt #{}", req.url(), attempt + 1; "url" => %req.url(), "attempt" => attempt + 1);
             self.dispatch_request(&req)
         })
     }
@@ -277,7 +297,7 @@ where
     pub fn dispatch_patch(self) -> Result<reqwest::StatusCode> {
         let url = self.parse_url()?;
 
-        self.retry.clone().retry(|attempt| {
+        self.retry_request(Method::PATCH, &url, |attempt| {
             let mut builder = blocking::Client::new()
                 .patch(url.clone())
                 .headers(self.headers.clone())
@@ -287,16 +307,14 @@ where
             };
             let req = builder.build().context("failed to build PATCH request")?;
 
-            info!("Patching {}: Attempt #{}", req.url(), attempt + 1);
-            let status = self
-                .client
-                .execute(req)
+            info!("Patching {}: Attempt #{}", req.url(), attempt + 1; "url" => %req.url(), "attempt" => attempt + 1);
+            let status = capture::execute(&self.client, req)
                 .context("failed to PATCH request")?
-                .status();
+                .status;
             if status.is_success() {
                 Ok(status)
             } else {
-                Err(anyhow!("PATCH failed: {}", status))
+                Err(status_error(format!("PATCH failed: {status}"), status))
             }
         })
     }
@@ -307,7 +325,7 @@ where
     {
         let url = self.parse_url()?;
 
-        self.retry.clone().retry(|attempt| {
+        self.retry_request(Method::PUT, &url, |attempt| {
             let mut builder = blocking::Client::new()
                 .put(url.clone())
                 .headers(self.headers.clone())
@@ -317,16 +335,17 @@ where
             };
             let req = builder.build().context("failed to build PUT request")?;
 
-            info!("Putting {}: Attempt #{}", req.url(), attempt + 1);
-            let response = self.client.execute(req).context("failed to PUT request")?;
-            let status = response.status();
+            info!("Putting {}: Attempt #{}", req.url(), attempt + 1; "url" => %req.url(), "attempt" => attempt + 1);
+            let response =
+                capture::execute(&self.client, req).context("failed to PUT request")?;
+            let status = response.status;
             if status.is_success() {
                 self.d
-                    .deserialize(response)
+                    .deserialize(response.body.as_slice())
                     .map(Some)
                     .context("failed to deserialize data")
             } else {
-                Err(anyhow!("PUT failed: {}", status))
+                Err(status_error(format!("PUT failed: {status}"), status))
             }
         })
     }
@@ -334,7 +353,7 @@ where
     pub fn dispatch_post(self) -> Result<reqwest::StatusCode> {
         let url = self.parse_url()?;
 
-        self.retry.clone().retry(|attempt| {
+        self.retry_request(Method::POST, &url, |attempt| {
             let mut builder = blocking::Client::new()
                 .post(url.clone())
                 .headers(self.headers.clone())
@@ -344,30 +363,50 @@ where
             };
             let req = builder.build().context("failed to build POST request")?;
 
-            info!("Posting {}: Attempt #{}", req.url(), attempt + 1);
-            let status = self
-                .client
-                .execute(req)
+            info!("Posting {}: Attempt #{}", req.url(), attempt + 1; "url" => %req.url(), "attempt" => attempt + 1);
+            let status = capture::execute(&self.client, req)
                 .context("failed to POST request")?
-                .status();
+                .status;
             if status.is_success() {
                 Ok(status)
             } else {
-                Err(anyhow!("POST failed: {}", status))
+                Err(status_error(format!("POST failed: {status}"), status))
             }
         })
     }
 
+    /// Drive a request through the retrying driver, recording it in the
+    /// run report.
+    fn retry_request<F, R>(&self, method: Method, url: &reqwest::Url, try_fn: F) -> Result<R>
+    where
+        F: Fn(u8) -> Result<R>,
+    {
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+        let res = self.retry.clone().retry(|attempt| {
+            attempts.set(attempt.saturating_add(1));
+            try_fn(attempt)
+        });
+        report::record_request(
+            method.as_str(),
+            url.as_str(),
+            attempts.get(),
+            res.is_ok(),
+            start.elapsed(),
+        );
+        res
+    }
+
     fn dispatch_request<T>(&self, req: &blocking::Request) -> Result<Option<T>>
     where
         T: for<'de> serde::Deserialize<'de>,
     {
-        match self.client.execute(clone_request(req)) {
-            Ok(resp) => match (resp.status(), self.return_on_404) {
+        match capture::execute(&self.client, clone_request(req)) {
+            Ok(resp) => match (resp.status, self.return_on_404) {
                 (reqwest::StatusCode::OK, _) => {
                     info!("Fetch successful");
                     self.d
-                        .deserialize(resp)
+                        .deserialize(resp.body.as_slice())
                         .map(Some)
                         .context("failed to deserialize data")
                 }
@@ -377,38 +416,61 @@ where
                 }
                 (s, _) => {
                     info!("Failed to fetch: {}", s);
-                    Err(anyhow!("failed to fetch: {}", s))
+                    Err(status_error(format!("failed to fetch: {s}"), s))
                 }
             },
             Err(e) => {
-                info!("Failed to fetch: {}", e);
-                Err(anyhow!(e).context("failed to fetch"))
+                info!("Failed to fetch: {:#}", e);
+                Err(e.context("failed to fetch"))
             }
         }
     }
 
     fn parse_url(&self) -> Result<reqwest::Url> {
-        #[allow(unused_mut)]
         let mut url = reqwest::Url::parse(self.url.as_str()).context("failed to parse uri")?;
         #[cfg(test)]
         if let Some(mock_base_url) = &self.mock_base_url {
-            let base_url =
-                reqwest::Url::parse(mock_base_url).context("failed to parse mock base URL")?;
-            url.set_scheme(base_url.scheme())
-                .map_err(|_| anyhow!("failed to update URL scheme"))?;
-            let host = base_url
-                .host()
-                .context("mock base URL doesn't have a host")?
-                .to_string();
-            url.set_host(Some(&host))
-                .context("failed to update URL host")?;
-            url.set_port(base_url.port())
-                .map_err(|_| anyhow!("failed to update URL port"))?;
+            rebase_url(&mut url, mock_base_url).context("invalid mock base URL")?;
+            return Ok(url);
+        }
+        // Redirect requests for an overridden endpoint, keeping the path.
+        if let Some(base_url) = url.host_str().and_then(|host| self.endpoints.get(host)) {
+            rebase_url(&mut url, base_url)
+                .with_context(|| format!("invalid base URL '{base_url}'"))?;
         }
         Ok(url)
     }
 }
 
+/// Replace the scheme, host and port of a URL with the ones of `base_url`.
+fn rebase_url(url: &mut reqwest::Url, base_url: &str) -> Result<()> {
+    let base_url = reqwest::Url::parse(base_url).context("failed to parse base URL")?;
+    url.set_scheme(base_url.scheme())
+        .map_err(|_| anyhow!("failed to update URL scheme"))?;
+    let host = base_url
+        .host()
+        .context("base URL doesn't have a host")?
+        .to_string();
+    url.set_host(Some(&host))
+        .context("failed to update URL host")?;
+    url.set_port(base_url.port())
+        .map_err(|_| anyhow!("failed to update URL port"))?;
+    Ok(())
+}
+
+/// Build an error for an unsuccessful HTTP status.
+///
+/// Client errors mean that the requested metadata is missing, anything
+/// else that the metadata service is unavailable.
+fn status_error(message: String, status: reqwest::StatusCode) -> anyhow::Error {
+    let kind = if status.is_client_error() {
+        ErrorKind::BadMetadata
+    } else {
+        ErrorKind::NetworkUnreachable
+    };
+    anyhow!(errors::Error::new(kind, message))
+}
+
 /// Reqwests Request struct doesn't implement `Clone`,
 /// so we have to do it here.
 fn clone_request(req: &blocking::Request) -> blocking::Request {
@@ -416,3 +478,38 @@ fn clone_request(req: &blocking::Request) -> blocking::Request {
     newreq.headers_mut().extend(req.headers().clone());
     newreq
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints() {
+        let config = ClientConfig {
+            endpoints: maplit::btreemap! {
+                "169.254.169.254".to_string() => "http://127.0.0.1:8080".to_string(),
+            },
+            ..Default::default()
+        };
+        let client = Client::try_from_config(&config).unwrap();
+
+        let url = client
+            .get(
+                Raw,
+                "http://169.254.169.254/metadata/instance?x=1".to_string(),
+            )
+            .parse_url()
+            .unwrap();
+        assert_eq!(url.as_str(), "http://127.0.0.1:8080/metadata/instance?x=1");
+
+        // Other endpoints are left alone.
+        let url = client
+            .get(
+                Raw,
+                "http://168.63.129.16/machine/?comp=goalstate".to_string(),
+            )
+            .parse_url()
+            .unwrap();
+        assert_eq!(url.as_str(), "http://168.63.129.16/machine/?comp=goalstate");
+    }
+}
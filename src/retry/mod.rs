@@ -14,20 +14,35 @@
 
 //! Drive a functions through a finite number of retries until it succeeds.
 
+use std::collections::BTreeMap;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 
+pub mod capture;
 mod client;
 pub mod raw_deserializer;
 pub use self::client::*;
+pub use self::raw_deserializer::Bytes;
+
+/// Settings for HTTP clients, usually built from configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Retrying driver, replacing the built-in one.
+    pub retry: Option<Retry>,
+    /// Alternative base URLs (scheme, host and port), by original host.
+    pub endpoints: BTreeMap<String, String>,
+    /// Point in time after which requests are no longer retried.
+    pub deadline: Option<Instant>,
+}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Retry {
     initial_backoff: Duration,
     max_backoff: Duration,
     max_retries: u8,
+    deadline: Option<Instant>,
 }
 
 impl Default for Retry {
@@ -36,6 +51,7 @@ impl Default for Retry {
             initial_backoff: Duration::new(1, 0),
             max_backoff: Duration::new(5, 0),
             max_retries: 10,
+            deadline: None,
         }
     }
 }
@@ -49,14 +65,12 @@ impl Retry {
     }
 
     /// Set the initial backoff.
-    #[allow(dead_code)]
     pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
         self.initial_backoff = initial_backoff;
         self
     }
 
     /// Set the maximum backoff.
-    #[allow(dead_code)]
     pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
         self.max_backoff = max_backoff;
         self
@@ -71,6 +85,12 @@ impl Retry {
         self
     }
 
+    /// Stop retrying after the given point in time.
+    pub fn deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
     /// Retry a function until it either succeeds once or fails all the time.
     pub fn retry<F, R>(self, try_fn: F) -> Result<R>
     where
@@ -93,6 +113,11 @@ impl Retry {
                     format!("maximum number of retries ({}) reached", self.max_retries)
                 });
             }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() + delay > deadline {
+                    break res.context("timed out");
+                }
+            }
             attempts = attempts.saturating_add(1);
 
             thread::sleep(delay);
@@ -154,4 +179,25 @@ mod tests {
         let total = final_res.unwrap();
         assert_eq!(total, retries);
     }
+
+    #[test]
+    fn test_deadline() {
+        let timings = Duration::from_millis(100);
+        let driver = Retry {
+            deadline: Some(Instant::now() + Duration::from_millis(250)),
+            ..Retry::new()
+                .initial_backoff(timings)
+                .max_backoff(timings)
+                .max_retries(10)
+        };
+
+        let final_res: AttemptResult = driver.retry(|attempt| {
+            if attempt > 2 {
+                panic!("unreachable attempt {attempt}");
+            }
+            bail!("expected error #{}", attempt)
+        });
+        let err = final_res.unwrap_err();
+        assert_eq!(err.to_string(), "timed out");
+    }
 }
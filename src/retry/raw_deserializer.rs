@@ -17,10 +17,11 @@ use std::io::Read;
 use std::result;
 
 use anyhow::{Context, Result};
-use serde::de::{self, DeserializeOwned, Visitor};
+use serde::de::{self, DeserializeOwned, Error as _, Visitor};
+use serde::{Deserialize, Deserializer};
 
 pub struct RawDeserializer {
-    s: String,
+    data: Vec<u8>,
 }
 
 impl RawDeserializer {
@@ -28,9 +29,40 @@ impl RawDeserializer {
     where
         R: Read,
     {
-        let mut s = String::new();
-        r.read_to_string(&mut s).context("error reading")?;
-        Ok(RawDeserializer { s })
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).context("error reading")?;
+        Ok(RawDeserializer { data })
+    }
+}
+
+/// Raw binary content, for payloads which may not be valid UTF-8.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Bytes;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a byte buffer")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> result::Result<Bytes, E> {
+                Ok(Bytes(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> result::Result<Bytes, E> {
+                Ok(Bytes(v))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
     }
 }
 
@@ -135,19 +167,20 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut RawDeserializer {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_string(self.s.clone())
+        let s = String::from_utf8(self.data.clone()).map_err(Self::Error::custom)?;
+        visitor.visit_string(s)
     }
-    fn deserialize_bytes<V>(self, _: V) -> result::Result<V::Value, Self::Error>
+    fn deserialize_bytes<V>(self, visitor: V) -> result::Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_bytes(&self.data)
     }
-    fn deserialize_byte_buf<V>(self, _: V) -> result::Result<V::Value, Self::Error>
+    fn deserialize_byte_buf<V>(self, visitor: V) -> result::Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_byte_buf(self.data.clone())
     }
     fn deserialize_option<V>(self, _: V) -> result::Result<V::Value, Self::Error>
     where
@@ -0,0 +1,437 @@
+//! Local HTTP metadata service over a unix socket.
+//!
+//! The `serve` sub-command answers a small read-only HTTP/JSON API from the
+//! metadata fetched by its last refresh, so that containers and unprivileged
+//! services don't need to reach the metadata service (or run Afterburn)
+//! themselves. Access is controlled through the socket file permissions, and
+//! only allowlisted attributes are exposed.
+
+use crate::metadata::MetadataSnapshot;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use slog_scope::{debug, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Default path of the listening socket.
+pub(crate) const SOCKET_PATH: &str = "/run/afterburn/metadata.sock";
+
+/// Default permissions of the listening socket.
+pub(crate) const SOCKET_MODE: u32 = 0o660;
+
+/// Maximum size of a request, headers included.
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// Timeout for reading a request and writing its response.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of connections served concurrently.
+const WORKERS: usize = 4;
+
+/// Paths of the API.
+const ROUTES: &[&str] = &[
+    "/v1/attributes",
+    "/v1/hostname",
+    "/v1/ssh-keys",
+    "/v1/network",
+];
+
+/// Allowlist of exposed attributes.
+///
+/// Each pattern is either an attribute name, or a prefix followed by `*`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct AttributeFilter {
+    patterns: Vec<String>,
+}
+
+impl AttributeFilter {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self { patterns }
+    }
+
+    /// Whether the given attribute is exposed.
+    pub fn allows(&self, name: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// Return the exposed subset of the given attributes.
+    fn filter(&self, attributes: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+        attributes
+            .iter()
+            .filter(|(name, _)| self.allows(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// JSON response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+
+    /// Write the response, as HTTP/1.1 closing the connection.
+    fn write_to(&self, mut w: impl Write) -> Result<()> {
+        let body = format!("{}\n", self.body);
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.reason(),
+            body.len()
+        );
+        if self.status == 405 {
+            head.push_str("Allow: GET\r\n");
+        }
+        head.push_str("\r\n");
+        w.write_all(head.as_bytes())?;
+        w.write_all(body.as_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Metadata server.
+#[derive(Debug)]
+pub(crate) struct Server {
+    metadata: RwLock<Option<MetadataSnapshot>>,
+    attributes: AttributeFilter,
+}
+
+impl Server {
+    /// Create a server exposing the given attributes, without metadata yet.
+    pub fn new(attributes: AttributeFilter) -> Self {
+        Self {
+            metadata: RwLock::new(None),
+            attributes,
+        }
+    }
+
+    /// Replace the served metadata, returning whether it changed.
+    pub fn update(&self, snapshot: MetadataSnapshot) -> bool {
+        let mut metadata = self.metadata.write().unwrap_or_else(|e| e.into_inner());
+        let changed = metadata.as_ref() != Some(&snapshot);
+        *metadata = Some(snapshot);
+        changed
+    }
+
+    /// Return the served metadata, if any.
+    pub fn snapshot(&self) -> Option<MetadataSnapshot> {
+        self.metadata
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Accept and serve connections forever, with a fixed pool of workers.
+    ///
+    /// Connections are served one at a time by each worker, so at most
+    /// `WORKERS` of them are handled concurrently; others wait in the
+    /// listen backlog.
+    pub fn run(self: Arc<Self>, listener: UnixListener) {
+        for _ in 1..WORKERS {
+            match listener.try_clone() {
+                Ok(listener) => {
+                    let server = Arc::clone(&self);
+                    thread::spawn(move || server.accept_loop(&listener));
+                }
+                Err(e) => warn!("failed to start connection worker: {}", e),
+            }
+        }
+        self.accept_loop(&listener);
+    }
+
+    /// Accept and serve connections forever, one at a time.
+    fn accept_loop(&self, listener: &UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.handle(&stream) {
+                        debug!("failed to serve request: {:#}", e);
+                    }
+                }
+                Err(e) => warn!("failed to accept connection: {}", e),
+            }
+        }
+    }
+
+    /// Serve a single request on the given connection.
+    fn handle(&self, stream: &UnixStream) -> Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let response = match read_request(stream) {
+            Ok((method, target)) => {
+                let response = self.respond(&method, &target);
+                debug!("{} {} -> {}", method, target, response.status);
+                response
+            }
+            Err(e) => {
+                debug!("invalid request: {:#}", e);
+                Response::error(400, "invalid request")
+            }
+        };
+        response.write_to(stream).context("writing response")
+    }
+
+    /// Answer a request for the given method and target.
+    fn respond(&self, method: &str, target: &str) -> Response {
+        let path = target.split_once('?').map_or(target, |(path, _)| path);
+        if !ROUTES.contains(&path) {
+            return Response::error(404, "not found");
+        }
+        if method != "GET" {
+            return Response::error(405, "method not allowed");
+        }
+
+        let metadata = self.metadata.read().unwrap_or_else(|e| e.into_inner());
+        let snapshot = match metadata.as_ref() {
+            Some(snapshot) => snapshot,
+            None => return Response::error(503, "metadata not available yet"),
+        };
+        Response::ok(match path {
+            "/v1/attributes" => json!(self.attributes.filter(&snapshot.attributes)),
+            "/v1/hostname" => json!({ "hostname": snapshot.hostname }),
+            "/v1/ssh-keys" => json!(snapshot.ssh_keys),
+            "/v1/network" => json!({
+                "networks": snapshot.networks,
+                "virtual_network_devices": snapshot.virtual_network_devices,
+            }),
+            _ => unreachable!("unhandled route {}", path),
+        })
+    }
+}
+
+/// Read an HTTP request, returning its method and target.
+///
+/// Headers are read but ignored, and any body is left unread.
+fn read_request(stream: impl Read) -> Result<(String, String)> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .context("reading request line")?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).context("reading headers")? == 0 {
+            bail!("truncated request");
+        }
+        if header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            Ok((method.to_string(), target.to_string()))
+        }
+        _ => bail!("malformed request line {:?}", request_line.trim_end()),
+    }
+}
+
+/// Create the listening socket, replacing any stale one.
+///
+/// The socket gets the given permissions, and is owned by the given group
+/// if any.
+pub(crate) fn bind(path: &Path, mode: u32, group: Option<&str>) -> Result<UnixListener> {
+    let gid = group
+        .map(|name| {
+            uzers::get_group_by_name(name)
+                .map(|group| group.gid())
+                .ok_or_else(|| anyhow!("unknown group '{}'", name))
+        })
+        .transpose()?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)
+            .with_context(|| format!("removing stale socket {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("binding {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("setting permissions on {}", path.display()))?;
+    if let Some(gid) = gid {
+        nix::unistd::chown(path, None, Some(nix::unistd::Gid::from_raw(gid)))
+            .with_context(|| format!("changing group of {}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network;
+
+    fn snapshot() -> MetadataSnapshot {
+        MetadataSnapshot {
+            attributes: maplit::btreemap! {
+                "MOCK_REGION".to_string() => "moon-1".to_string(),
+                "MOCK_ZONE".to_string() => "moon-1a".to_string(),
+                "MOCK_TOKEN".to_string() => "secret".to_string(),
+            },
+            hostname: Some("mock.example.com".to_string()),
+            ssh_keys: vec!["ssh-ed25519 AAAA core@mock".to_string()],
+            networks: vec![network::Interface {
+                name: Some("eth0".to_string()),
+                mac_address: None,
+                path: None,
+                priority: 10,
+                nameservers: vec![],
+                ip_addresses: vec![],
+                routes: vec![],
+                bond: None,
+                unmanaged: false,
+                required_for_online: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let filter = AttributeFilter::new(vec!["MOCK_REGION".to_string(), "MOCK_Z*".to_string()]);
+        assert!(filter.allows("MOCK_REGION"));
+        assert!(!filter.allows("MOCK_REGION_2"));
+        assert!(filter.allows("MOCK_ZONE"));
+        assert!(!filter.allows("MOCK_TOKEN"));
+        assert_eq!(
+            filter
+                .filter(&snapshot().attributes)
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["MOCK_REGION", "MOCK_ZONE"]
+        );
+
+        // Nothing is exposed by default.
+        assert!(AttributeFilter::default()
+            .filter(&snapshot().attributes)
+            .is_empty());
+        assert!(AttributeFilter::new(vec!["*".to_string()]).allows("MOCK_TOKEN"));
+    }
+
+    #[test]
+    fn test_respond() {
+        let server = Server::new(AttributeFilter::new(vec!["MOCK_REGION".to_string()]));
+        assert_eq!(server.respond("GET", "/v1/hostname").status, 503);
+        assert!(server.update(snapshot()));
+        assert!(!server.update(snapshot()));
+
+        assert_eq!(
+            server.respond("GET", "/v1/attributes"),
+            Response::ok(json!({ "MOCK_REGION": "moon-1" }))
+        );
+        assert_eq!(
+            server.respond("GET", "/v1/hostname?foo=bar"),
+            Response::ok(json!({ "hostname": "mock.example.com" }))
+        );
+        assert_eq!(
+            server.respond("GET", "/v1/ssh-keys"),
+            Response::ok(json!(["ssh-ed25519 AAAA core@mock"]))
+        );
+        let network = server.respond("GET", "/v1/network");
+        assert_eq!(network.status, 200);
+        assert_eq!(network.body["networks"][0]["name"], "eth0");
+        assert_eq!(network.body["virtual_network_devices"], json!([]));
+
+        assert_eq!(server.respond("POST", "/v1/hostname").status, 405);
+        assert_eq!(server.respond("GET", "/v1/hostname/").status, 404);
+        assert_eq!(server.respond("GET", "/").status, 404);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = "GET /v1/hostname HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            read_request(request.as_bytes()).unwrap(),
+            ("GET".to_string(), "/v1/hostname".to_string())
+        );
+        read_request("GET /v1/hostname\r\n\r\n".as_bytes()).unwrap_err();
+        read_request("GET /v1/hostname HTTP/1.1\r\nHost: localhost\r\n".as_bytes()).unwrap_err();
+        read_request("GET / SPDY/3\r\n\r\n".as_bytes()).unwrap_err();
+        let oversized = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(10000));
+        read_request(oversized.as_bytes()).unwrap_err();
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/metadata.sock");
+        let listener = bind(&path, 0o600, None).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Stale sockets are replaced, other files are left alone.
+        drop(listener);
+        let listener = bind(&path, SOCKET_MODE, None).unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        bind(&file, SOCKET_MODE, None).unwrap_err();
+        bind(&path, SOCKET_MODE, Some("no-such-group-afterburn")).unwrap_err();
+
+        let server = Arc::new(Server::new(AttributeFilter::default()));
+        server.update(snapshot());
+        thread::spawn(move || server.run(listener));
+
+        // Idle connections occupy workers, but don't block the others.
+        let _idle: Vec<_> = (1..WORKERS)
+            .map(|_| UnixStream::connect(&path).unwrap())
+            .collect();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"GET /v1/hostname HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"hostname\":\"mock.example.com\"}\n"));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"nonsense\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -14,85 +14,212 @@
 
 //! Kernel cmdline parsing - utility functions
 //!
-//! NOTE(lucab): this is not a complete/correct cmdline parser, as it implements
-//!  just enough logic to extract a few interesting values. In particular, it doesn't
-//!  handle separator quoting/escaping, list of values, and merging of repeated
-//!  flags.
+//! Parsing follows the rules of the kernel itself (see `next_arg()` in
+//! `kernel/params.c`): parameters are separated by any whitespace, double
+//! quotes protect whitespace, quotes around values (or whole parameters)
+//! are stripped, and a bare `--` terminates kernel parameters, the rest
+//! being passed to init.
 
+use crate::errors::{Error, ErrorKind};
 use anyhow::{bail, Context, Result};
 use slog_scope::trace;
 
 /// Platform key.
 const CMDLINE_PLATFORM_FLAG: &str = "ignition.platform.id";
 
-/// Get platform value from cmdline file.
-pub fn get_platform(fpath: &str) -> Result<String> {
-    let content = std::fs::read_to_string(fpath)
-        .with_context(|| format!("Failed to read cmdline file ({fpath})"))?;
+/// Keys of initrd network configuration parameters (see `dracut.cmdline(7)`).
+const NETWORK_KEYS: [&str; 5] = ["ip", "nameserver", "bond", "vlan", "ifname"];
 
-    match find_flag_value(CMDLINE_PLATFORM_FLAG, &content) {
-        Some(platform) => {
-            trace!("found '{}' flag: {}", CMDLINE_PLATFORM_FLAG, platform);
-            Ok(platform)
-        }
-        None => bail!(
-            "Couldn't find flag '{}' in cmdline file ({})",
-            CMDLINE_PLATFORM_FLAG,
-            fpath
-        ),
-    }
+/// Flag requesting network in the initrd.
+const NEEDNET_FLAG: &str = "rd.neednet";
+
+/// A kernel cmdline parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Param {
+    /// Parameter key, including any `rd.` prefix.
+    pub key: String,
+    /// Parameter value, unless this is a bare flag.
+    pub value: Option<String>,
 }
 
-/// Check whether kernel cmdline file contains flags for network configuration.
-#[allow(unused)]
-pub fn has_network_kargs(fpath: &str) -> Result<bool> {
-    const IP_PREFIX: &str = "ip=";
+impl Param {
+    /// Whether this parameter only applies to the initrd.
+    pub fn is_initrd(&self) -> bool {
+        self.key.starts_with("rd.")
+    }
 
-    let content = std::fs::read_to_string(fpath)
-        .with_context(|| format!("Failed to read cmdline file ({fpath})"))?;
-    let has_ip = contains_flag_prefix(&content, IP_PREFIX);
-    Ok(has_ip)
+    /// Return the parameter key, without any `rd.` prefix.
+    pub fn name(&self) -> &str {
+        self.key.strip_prefix("rd.").unwrap_or(&self.key)
+    }
 }
 
-/// Check whether cmdline contains any flag starting with the given prefix.
-///
-/// This splits `cmdline` content into flag elements and match each with `prefix`,
-/// short-circuiting to `true` on the first match.
-fn contains_flag_prefix(cmdline: &str, prefix: &str) -> bool {
-    cmdline.split(' ').any(|s| s.starts_with(prefix))
+/// Parsed kernel cmdline.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cmdline {
+    params: Vec<Param>,
 }
 
-// Find value of flag in cmdline string.
-fn find_flag_value(flagname: &str, cmdline: &str) -> Option<String> {
-    // split the contents into elements and keep key-value tuples only.
-    let params: Vec<(&str, &str)> = cmdline
-        .split(' ')
-        .filter_map(|s| {
-            let kv: Vec<&str> = s.splitn(2, '=').collect();
-            match kv.len() {
-                2 => Some((kv[0], kv[1])),
-                _ => None,
+impl Cmdline {
+    /// Read and parse cmdline file.
+    pub fn read(fpath: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(fpath)
+            .with_context(|| format!("Failed to read cmdline file ({fpath})"))?;
+        Ok(Self::parse(&content))
+    }
+
+    /// Parse cmdline string.
+    pub fn parse(cmdline: &str) -> Self {
+        let mut params = vec![];
+        let mut rest = cmdline.trim_start();
+        while !rest.is_empty() {
+            let (param, tail) = next_param(rest);
+            rest = tail.trim_start();
+            if param.key == "--" && param.value.is_none() {
+                break;
             }
-        })
-        .collect();
+            params.push(param);
+        }
+        Self { params }
+    }
+
+    /// Return the value of the given key; the last occurrence wins.
+    ///
+    /// Bare flags are ignored.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.values(key).pop()
+    }
 
-    // find the oem flag
-    for (key, val) in params {
-        if key != flagname {
-            continue;
+    /// Return all values of the given key, in order.
+    ///
+    /// Bare flags are ignored.
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|p| p.key == key)
+            .filter_map(|p| p.value.as_deref())
+            .collect()
+    }
+
+    /// Return all parameters whose key starts with the given prefix, with
+    /// the prefix stripped from keys.
+    pub fn with_prefix(&self, prefix: &str) -> Vec<(&str, Option<&str>)> {
+        self.params
+            .iter()
+            .filter_map(|p| {
+                let key = p.key.strip_prefix(prefix)?;
+                Some((key, p.value.as_deref()))
+            })
+            .collect()
+    }
+
+    /// Check whether the given boolean flag is set.
+    ///
+    /// The flag is set if present without any value, or with a value other
+    /// than `0`; the last occurrence wins.
+    pub fn flag(&self, key: &str) -> bool {
+        self.params
+            .iter()
+            .filter(|p| p.key == key)
+            .map(|p| p.value.as_deref() != Some("0"))
+            .next_back()
+            .unwrap_or(false)
+    }
+
+    /// Check whether the cmdline contains parameters for initrd network
+    /// configuration.
+    ///
+    /// Network parameters are recognized without `rd.` prefix only, as
+    /// dracut does.
+    pub fn has_network_kargs(&self) -> bool {
+        self.flag(NEEDNET_FLAG)
+            || self
+                .params
+                .iter()
+                .any(|p| !p.is_initrd() && NETWORK_KEYS.contains(&p.name()) && p.value.is_some())
+    }
+}
+
+/// Split the next parameter from cmdline string, which must not start
+/// with whitespace.
+fn next_param(args: &str) -> (Param, &str) {
+    let (quoted, args) = match args.strip_prefix('"') {
+        Some(args) => (true, args),
+        None => (false, args),
+    };
+
+    let mut in_quote = quoted;
+    let mut equals = None;
+    let mut end = args.len();
+    for (i, c) in args.char_indices() {
+        if c.is_whitespace() && !in_quote {
+            end = i;
+            break;
         }
-        let bare_val = val.trim();
-        if !bare_val.is_empty() {
-            return Some(bare_val.to_string());
+        if equals.is_none() && c == '=' && i > 0 {
+            equals = Some(i);
+        }
+        if c == '"' {
+            in_quote = !in_quote;
         }
     }
-    None
+    let (token, rest) = args.split_at(end);
+
+    // Strip the closing quote of quoted values or parameters.
+    let (key, value) = match equals {
+        Some(i) => {
+            let value = &token[i + 1..];
+            let value = match value.strip_prefix('"') {
+                Some(value) => value.strip_suffix('"').unwrap_or(value),
+                None if quoted => value.strip_suffix('"').unwrap_or(value),
+                None => value,
+            };
+            (&token[..i], Some(value))
+        }
+        None if quoted => (token.strip_suffix('"').unwrap_or(token), None),
+        None => (token, None),
+    };
+    let param = Param {
+        key: key.to_string(),
+        value: value.map(String::from),
+    };
+    (param, rest)
+}
+
+/// Get platform value from cmdline file.
+pub fn get_platform(fpath: &str) -> Result<String> {
+    let cmdline = Cmdline::read(fpath)?;
+    match cmdline
+        .value(CMDLINE_PLATFORM_FLAG)
+        .filter(|v| !v.is_empty())
+    {
+        Some(platform) => {
+            trace!("found '{}' flag: {}", CMDLINE_PLATFORM_FLAG, platform);
+            Ok(platform.to_string())
+        }
+        None => bail!(Error::new(
+            ErrorKind::UnsupportedPlatform,
+            format!("Couldn't find flag '{CMDLINE_PLATFORM_FLAG}' in cmdline file ({fpath})")
+        )),
+    }
+}
+
+/// Check whether kernel cmdline file contains flags for network configuration.
+pub fn has_network_kargs(fpath: &str) -> Result<bool> {
+    Ok(Cmdline::read(fpath)?.has_network_kargs())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn param(key: &str, value: Option<&str>) -> Param {
+        Param {
+            key: key.to_string(),
+            value: value.map(String::from),
+        }
+    }
+
     #[test]
     fn test_find_flag() {
         let flagname = "coreos.oem.id";
@@ -101,22 +228,103 @@ mod tests {
             ("foo=bar", None),
             ("coreos.oem.id", None),
             ("coreos.oem.id=", None),
+            // tabs separate parameters, like spaces
             ("coreos.oem.id=\t", None),
+            ("coreos.oem.id=\tec2", None),
             ("coreos.oem.id=ec2", Some("ec2".to_string())),
-            ("coreos.oem.id=\tec2", Some("ec2".to_string())),
             ("coreos.oem.id=ec2\n", Some("ec2".to_string())),
             ("foo=bar coreos.oem.id=ec2", Some("ec2".to_string())),
             ("coreos.oem.id=ec2 foo=bar", Some("ec2".to_string())),
+            ("foo=bar\tcoreos.oem.id=ec2", Some("ec2".to_string())),
+            (
+                "coreos.oem.id=gcp coreos.oem.id=ec2",
+                Some("ec2".to_string()),
+            ),
+            ("coreos.oem.id=\"ec2\"", Some("ec2".to_string())),
+            ("-- coreos.oem.id=ec2", None),
+        ];
+        for (tcase, tres) in tests {
+            let res = Cmdline::parse(tcase)
+                .value(flagname)
+                .filter(|v| !v.is_empty())
+                .map(String::from);
+            assert_eq!(res, tres, "failed testcase: '{tcase}'");
+        }
+    }
+
+    #[test]
+    fn test_parse_quoting() {
+        let cmdline = Cmdline::parse(
+            "  root=/dev/sda1\tafterburn.ssh_key=\"ssh-ed25519 AAAA core@host\" \"quoted=a b\" \"bare flag\" a=\"b\"c=d -- init=/bin/sh\n",
+        );
+        assert_eq!(
+            cmdline.params,
+            &[
+                param("root", Some("/dev/sda1")),
+                param("afterburn.ssh_key", Some("ssh-ed25519 AAAA core@host")),
+                param("quoted", Some("a b")),
+                param("bare flag", None),
+                // like the kernel, only strip quotes around the whole value
+                param("a", Some("b\"c=d")),
+            ]
+        );
+        assert!(cmdline.values("init").is_empty());
+
+        // unterminated quotes run until the end
+        assert_eq!(Cmdline::parse("x=\"a b").params, &[param("x", Some("a b"))]);
+        // a leading equal sign is part of the key
+        assert_eq!(Cmdline::parse("=x").params, &[param("=x", None)]);
+    }
+
+    #[test]
+    fn test_values() {
+        let cmdline =
+            Cmdline::parse("afterburn.ssh_key=key1 afterburn.ssh_key afterburn.ssh_key=key2 foo");
+        assert_eq!(cmdline.values("afterburn.ssh_key"), vec!["key1", "key2"]);
+        assert_eq!(cmdline.value("afterburn.ssh_key"), Some("key2"));
+        assert!(cmdline.values("foo").is_empty());
+
+        let cmdline = Cmdline::parse("afterburn.attr.FOO=bar afterburn.attr.BAZ=1=2 quiet");
+        assert_eq!(
+            cmdline.with_prefix("afterburn.attr."),
+            vec![("FOO", Some("bar")), ("BAZ", Some("1=2"))]
+        );
+    }
+
+    #[test]
+    fn test_flag() {
+        let flagname = "afterburn.network.disable";
+        let tests = vec![
+            ("", false),
+            ("quiet", false),
+            ("afterburn.network.disable", true),
+            ("quiet afterburn.network.disable\n", true),
+            ("afterburn.network.disable=1", true),
+            ("afterburn.network.disable=0", false),
+            (
+                "afterburn.network.disable afterburn.network.disable=0",
+                false,
+            ),
+            ("afterburn.network.disabled", false),
         ];
         for (tcase, tres) in tests {
-            let res = find_flag_value(flagname, tcase);
+            let res = Cmdline::parse(tcase).flag(flagname);
             assert_eq!(res, tres, "failed testcase: '{tcase}'");
         }
     }
 
     #[test]
-    fn test_contains_flag_prefix() {
-        let prefix = "ip=";
+    fn test_initrd_params() {
+        let cmdline = Cmdline::parse("rd.break=pre-mount break=cmdline");
+        let params = &cmdline.params;
+        assert!(params[0].is_initrd());
+        assert_eq!(params[0].name(), "break");
+        assert!(!params[1].is_initrd());
+        assert_eq!(params[1].name(), "break");
+    }
+
+    #[test]
+    fn test_has_network_kargs() {
         let tests = vec![
             ("", false),
             ("ip=foo", true),
@@ -124,9 +332,20 @@ mod tests {
             ("coreos.oem.id=", false),
             ("coreos.oem.id=ec2", false),
             ("coreos.oem.id=ip ip=bar\n", true),
+            ("coreos.oem.id=ec2\tip=dhcp", true),
+            ("rd.neednet", true),
+            ("rd.neednet=1", true),
+            ("rd.neednet=0", false),
+            ("nameserver=192.0.2.53", true),
+            ("bond=bond0:eth0,eth1", true),
+            ("vlan=eth0.100:eth0", true),
+            ("ifname=eth0:52:54:00:12:34:56", true),
+            ("rd.ip=dhcp", false),
+            ("ip", false),
+            ("-- ip=dhcp", false),
         ];
         for (tcase, tres) in tests {
-            let res = contains_flag_prefix(tcase, prefix);
+            let res = Cmdline::parse(tcase).has_network_kargs();
             assert_eq!(res, tres, "failed testcase: '{tcase}'");
         }
     }
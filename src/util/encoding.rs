@@ -0,0 +1,85 @@
+//! Decoding of encoded and compressed payloads.
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use libflate::gzip::Decoder;
+use std::io::Read;
+
+/// Magic bytes at the start of gzip streams.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Decode a payload according to a cloud-init style encoding name.
+///
+/// Supported encodings are `base64` (or `b64`) and `gzip+base64` (or
+/// `gz+b64`); an empty encoding leaves the payload untouched.
+pub(crate) fn decode(encoding: &str, raw: &[u8]) -> Result<Vec<u8>> {
+    match encoding {
+        "" => Ok(raw.to_vec()),
+        "base64" | "b64" => general_purpose::STANDARD
+            .decode(raw)
+            .context("failed to decode base64 payload"),
+        "gzip+base64" | "gz+b64" => {
+            let decoded = general_purpose::STANDARD
+                .decode(raw)
+                .context("failed to decode base64 payload")?;
+            gunzip(&decoded)
+        }
+        _ => bail!("unknown encoding '{}'", encoding),
+    }
+}
+
+/// Decompress a payload if it is gzip-compressed, otherwise return it as is.
+pub(crate) fn maybe_gunzip(data: Vec<u8>) -> Result<Vec<u8>> {
+    if data.starts_with(GZIP_MAGIC) {
+        gunzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressor = Decoder::new(data).context("invalid gzip header")?;
+    let mut uncompressed = Vec::new();
+    decompressor
+        .read_to_end(&mut uncompressed)
+        .context("failed to decompress gzip payload")?;
+    Ok(uncompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libflate::gzip::Encoder;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().into_result().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let compressed = general_purpose::STANDARD.encode(gzip(b"#cloud-config\n"));
+        assert_eq!(decode("", b"plain").unwrap(), b"plain");
+        assert_eq!(decode("base64", b"cGxhaW4=").unwrap(), b"plain");
+        assert_eq!(decode("b64", b"cGxhaW4=").unwrap(), b"plain");
+        assert_eq!(
+            decode("gzip+base64", compressed.as_bytes()).unwrap(),
+            b"#cloud-config\n"
+        );
+        assert_eq!(
+            decode("gz+b64", compressed.as_bytes()).unwrap(),
+            b"#cloud-config\n"
+        );
+        decode("base64", b"not base64!").unwrap_err();
+        decode("rot13", b"plain").unwrap_err();
+    }
+
+    #[test]
+    fn test_maybe_gunzip() {
+        assert_eq!(maybe_gunzip(b"plain".to_vec()).unwrap(), b"plain");
+        assert_eq!(maybe_gunzip(gzip(b"packed")).unwrap(), b"packed");
+        maybe_gunzip(vec![0x1f, 0x8b, 0x00]).unwrap_err();
+    }
+}
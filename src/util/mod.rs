@@ -14,18 +14,25 @@
 
 //! utility functions
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
 
 mod cmdline;
-pub use self::cmdline::{get_platform, has_network_kargs};
+pub use self::cmdline::{get_platform, has_network_kargs, Cmdline};
 
 mod dhcp;
 pub use self::dhcp::DhcpOption;
 
+mod encoding;
+pub(crate) use encoding::{decode, maybe_gunzip};
+
 mod mount;
 pub(crate) use mount::{mount_ro, unmount};
 
+mod root;
+pub(crate) use root::{get_user_by_name, root, rooted, set_root};
+
 fn key_lookup_line(delim: char, key: &str, line: &str) -> Option<String> {
     match line.find(delim) {
         Some(index) => {
@@ -40,6 +47,15 @@ fn key_lookup_line(delim: char, key: &str, line: &str) -> Option<String> {
     }
 }
 
+/// Read the content of a file, returning `None` if it does not exist.
+pub(crate) fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read file {path:?}")),
+    }
+}
+
 pub fn key_lookup<R: Read>(delim: char, key: &str, reader: R) -> Result<Option<String>> {
     let contents = BufReader::new(reader);
 
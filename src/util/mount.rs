@@ -1,5 +1,6 @@
 //! Helpers for mounting and unmounting.
 
+use crate::report;
 use crate::retry;
 use anyhow::{Context, Result};
 use nix::mount;
@@ -33,7 +34,7 @@ pub(crate) fn unmount(target: &Path, retries: u8) -> Result<()> {
 /// This can internally wait for udev events settling and retry in case of transient errors.
 pub(crate) fn mount_ro(source: &Path, target: &Path, fstype: &str, retries: u8) -> Result<()> {
     let driver = retry::Retry::new().max_retries(retries);
-    driver.retry(|attempt| {
+    let res = driver.retry(|attempt| {
         debug!("mounting '{}': attempt #{}", source.display(), attempt + 1);
         let res = mount::mount(
             Some(source),
@@ -58,7 +59,11 @@ pub(crate) fn mount_ro(source: &Path, target: &Path, fstype: &str, retries: u8)
             settle_udev(None)
         };
         res
-    })
+    });
+    if res.is_ok() {
+        report::record_mount(source, target, fstype);
+    }
+    res
 }
 
 /// Wait for udev queue to settle, ignoring any errors.
@@ -0,0 +1,152 @@
+//! Alternative root directory for outputs.
+//!
+//! When set, all output paths are interpreted relative to the root
+//! directory, and users are resolved from its passwd and group databases
+//! instead of the running system's ones. This allows populating a sysroot
+//! from the initrd, or an offline image tree.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use uzers::os::unix::UserExt;
+use uzers::User;
+
+static ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Set the process-wide root directory.
+pub(crate) fn set_root(root: Option<PathBuf>) {
+    *ROOT.write().unwrap_or_else(|e| e.into_inner()) = root;
+}
+
+/// Return the process-wide root directory, if any.
+pub(crate) fn root() -> Option<PathBuf> {
+    ROOT.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Return the given path under the root directory, if any.
+pub(crate) fn rooted(path: impl AsRef<Path>) -> Result<PathBuf> {
+    match root() {
+        Some(root) => join_under(&root, path.as_ref()),
+        None => Ok(path.as_ref().to_path_buf()),
+    }
+}
+
+/// Join `path` under `root`, even if it is absolute.
+///
+/// Paths with `..` components are rejected, as they could escape the root
+/// directory.
+fn join_under(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => bail!(
+                "path {} escapes root directory {}",
+                path.display(),
+                root.display()
+            ),
+            c => relative.push(c),
+        }
+    }
+    Ok(root.join(relative))
+}
+
+/// Look up a user by name.
+///
+/// With a root directory, the user is resolved from `<root>/etc/passwd`,
+/// its primary group is checked against `<root>/etc/group`, and its home
+/// directory is returned under the root directory.
+pub(crate) fn get_user_by_name(name: &str) -> Result<User> {
+    match root() {
+        None => uzers::get_user_by_name(name)
+            .ok_or_else(|| anyhow!("could not find user with username {:?}", name)),
+        Some(root) => get_user_from_root(&root, name),
+    }
+}
+
+fn get_user_from_root(root: &Path, name: &str) -> Result<User> {
+    let passwd_path = join_under(root, Path::new("/etc/passwd"))?;
+    let passwd = std::fs::read_to_string(&passwd_path)
+        .with_context(|| format!("failed to read {}", passwd_path.display()))?;
+    let fields: Vec<&str> = passwd
+        .lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|f| f.len() >= 7 && f[0] == name)
+        .ok_or_else(|| {
+            anyhow!(
+                "could not find user with username {:?} in {}",
+                name,
+                passwd_path.display()
+            )
+        })?;
+    let uid = fields[2]
+        .parse()
+        .with_context(|| format!("invalid uid for user {name:?}"))?;
+    let gid = fields[3]
+        .parse()
+        .with_context(|| format!("invalid gid for user {name:?}"))?;
+    let home = join_under(root, Path::new(fields[5]))?;
+
+    let group_path = join_under(root, Path::new("/etc/group"))?;
+    let group = std::fs::read_to_string(&group_path)
+        .with_context(|| format!("failed to read {}", group_path.display()))?;
+    let has_group = group
+        .lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .any(|f| f.len() >= 3 && f[2].parse() == Ok(gid));
+    if !has_group {
+        bail!(
+            "could not find primary group {} of user {:?} in {}",
+            gid,
+            name,
+            group_path.display()
+        );
+    }
+
+    Ok(User::new(uid, name, gid).with_home_dir(&home))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_join_under() {
+        let root = Path::new("/sysroot");
+        assert_eq!(
+            join_under(root, Path::new("/etc/hostname")).unwrap(),
+            PathBuf::from("/sysroot/etc/hostname")
+        );
+        assert_eq!(
+            join_under(root, Path::new("run/metadata")).unwrap(),
+            PathBuf::from("/sysroot/run/metadata")
+        );
+        assert_eq!(
+            join_under(root, Path::new("/etc/./hostname")).unwrap(),
+            PathBuf::from("/sysroot/etc/hostname")
+        );
+        join_under(root, Path::new("/../etc/shadow")).unwrap_err();
+        join_under(root, Path::new("/run/../../etc/shadow")).unwrap_err();
+    }
+
+    #[test]
+    fn test_user_from_root() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\ncore:x:1000:1000:CoreOS Admin:/var/home/core:/bin/bash\nnogroup:x:1001:1001::/home/nogroup:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(root.path().join("etc/group"), "root:x:0:\ncore:x:1000:\n").unwrap();
+
+        let user = get_user_from_root(root.path(), "core").unwrap();
+        assert_eq!(user.uid(), 1000);
+        assert_eq!(user.primary_group_id(), 1000);
+        assert_eq!(user.home_dir(), root.path().join("var/home/core"));
+
+        get_user_from_root(root.path(), "missing").unwrap_err();
+        get_user_from_root(root.path(), "nogroup").unwrap_err();
+    }
+}